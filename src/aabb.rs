@@ -1,6 +1,10 @@
 use crate::ray::Ray;
 use crate::utility::{ffmax, ffmin};
 use crate::vec3::Vec3;
+
+// Thickness given to flat boxes, so rays in their plane still see the box
+pub const FLAT: f64 = 0.0001;
+
 #[derive(Copy, Clone)]
 pub struct AABB {
     minimum: Vec3,
@@ -53,6 +57,26 @@ impl AABB {
         true
     }
 
+    // Range of t over which the ray is inside the box, if any of it is in
+    // [t_min, t_max]
+    pub fn clip(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let (mut t0, mut t1) = (t_min, t_max);
+        for a in 0..3 {
+            let inv_d = 1.0 / r.direction()[a];
+            let mut near = (self.min()[a] - r.origin()[a]) * inv_d;
+            let mut far = (self.max()[a] - r.origin()[a]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t1 < t0 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
     pub fn surrounding_box(box0: AABB, box1: AABB) -> AABB {
         let small = Vec3::new(
             ffmin(box0.min().x(), box1.min().x()),
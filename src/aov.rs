@@ -0,0 +1,301 @@
+use std::io::{self, Read, Write};
+
+use crate::framebuffer::FrameBuffer;
+use crate::vec3::Vec3;
+
+// Arbitrary output variables: what the camera ray saw at its first hit, written
+// next to the beauty image for compositing and denoising
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Aov {
+    Normal,
+    Depth,
+    Albedo,
+    ObjectId,
+    MaterialId,
+    Uv,
+    Position,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 7] = [
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Uv,
+        Aov::Position,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Uv => "uv",
+            Aov::Position => "position",
+        }
+    }
+}
+
+// First hit of one camera sample. A miss only has an albedo, the background.
+#[derive(Copy, Clone)]
+pub struct AovSample {
+    pub hit: bool,
+    pub normal: Vec3,
+    pub depth: f64,
+    pub albedo: Vec3,
+    pub uv: (f64, f64),
+    pub position: Vec3,
+    pub object_id: Option<usize>,
+    pub material_id: Option<usize>,
+}
+
+impl AovSample {
+    pub fn miss(background: Vec3) -> Self {
+        Self {
+            hit: false,
+            normal: Vec3::empty(),
+            depth: 0.0,
+            albedo: background,
+            uv: (0.0, 0.0),
+            position: Vec3::empty(),
+            object_id: None,
+            material_id: None,
+        }
+    }
+}
+
+// Per pixel sums. Geometric values are averaged over the samples that hit
+// something, albedo over all samples. Ids can not be averaged, so the pixel
+// keeps the ids of its first sample.
+#[derive(Copy, Clone)]
+pub struct AovPixel {
+    normal_sum: Vec3,
+    depth_sum: f64,
+    albedo_sum: Vec3,
+    uv_sum: Vec3,
+    position_sum: Vec3,
+    hits: u32,
+    samples: u32,
+    object_id: Option<usize>,
+    material_id: Option<usize>,
+}
+
+fn average(sum: Vec3, count: u32) -> Vec3 {
+    if count == 0 {
+        return Vec3::empty();
+    }
+    sum / count as f64
+}
+
+// Ids are written as floats, -1 marks pixels where the first sample missed
+fn id_value(id: Option<usize>) -> f64 {
+    match id {
+        Some(id) => id as f64,
+        None => -1.0,
+    }
+}
+
+impl AovPixel {
+    pub fn empty() -> Self {
+        Self {
+            normal_sum: Vec3::empty(),
+            depth_sum: 0.0,
+            albedo_sum: Vec3::empty(),
+            uv_sum: Vec3::empty(),
+            position_sum: Vec3::empty(),
+            hits: 0,
+            samples: 0,
+            object_id: None,
+            material_id: None,
+        }
+    }
+
+    pub fn add(&mut self, sample: &AovSample) {
+        if self.samples == 0 {
+            self.object_id = sample.object_id;
+            self.material_id = sample.material_id;
+        }
+
+        self.samples += 1;
+        self.albedo_sum += sample.albedo;
+
+        if sample.hit {
+            self.hits += 1;
+            self.normal_sum += sample.normal;
+            self.depth_sum += sample.depth;
+            self.uv_sum += Vec3::new(sample.uv.0, sample.uv.1, 0.0);
+            self.position_sum += sample.position;
+        }
+    }
+
+    pub fn merge(&mut self, other: &AovPixel) {
+        if self.samples == 0 {
+            self.object_id = other.object_id;
+            self.material_id = other.material_id;
+        }
+
+        self.normal_sum += other.normal_sum;
+        self.depth_sum += other.depth_sum;
+        self.albedo_sum += other.albedo_sum;
+        self.uv_sum += other.uv_sum;
+        self.position_sum += other.position_sum;
+        self.hits += other.hits;
+        self.samples += other.samples;
+    }
+
+    pub fn value(&self, aov: Aov) -> Vec3 {
+        match aov {
+            Aov::Normal => {
+                let n = average(self.normal_sum, self.hits);
+                if n.length_squared() > 0.0 {
+                    Vec3::unit_vector(n)
+                } else {
+                    n
+                }
+            }
+            Aov::Depth => {
+                let d = if self.hits == 0 {
+                    0.0
+                } else {
+                    self.depth_sum / self.hits as f64
+                };
+                Vec3::new(d, d, d)
+            }
+            Aov::Albedo => average(self.albedo_sum, self.samples),
+            Aov::ObjectId => {
+                let id = id_value(self.object_id);
+                Vec3::new(id, id, id)
+            }
+            Aov::MaterialId => {
+                let id = id_value(self.material_id);
+                Vec3::new(id, id, id)
+            }
+            Aov::Uv => average(self.uv_sum, self.hits),
+            Aov::Position => average(self.position_sum, self.hits),
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for v in [
+            self.normal_sum,
+            self.albedo_sum,
+            self.uv_sum,
+            self.position_sum,
+        ]
+        .iter()
+        {
+            for c in 0..3 {
+                out.write_all(&v[c].to_le_bytes())?;
+            }
+        }
+        out.write_all(&self.depth_sum.to_le_bytes())?;
+        out.write_all(&self.hits.to_le_bytes())?;
+        out.write_all(&self.samples.to_le_bytes())?;
+        out.write_all(&id_value(self.object_id).to_le_bytes())?;
+        out.write_all(&id_value(self.material_id).to_le_bytes())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut pixel = AovPixel::empty();
+        let mut vectors = [Vec3::empty(); 4];
+        for v in vectors.iter_mut() {
+            for c in 0..3 {
+                v[c] = read_f64(input)?;
+            }
+        }
+        pixel.normal_sum = vectors[0];
+        pixel.albedo_sum = vectors[1];
+        pixel.uv_sum = vectors[2];
+        pixel.position_sum = vectors[3];
+        pixel.depth_sum = read_f64(input)?;
+        pixel.hits = read_u32(input)?;
+        pixel.samples = read_u32(input)?;
+        pixel.object_id = read_id(input)?;
+        pixel.material_id = read_id(input)?;
+        Ok(pixel)
+    }
+}
+
+fn read_f64<R: Read>(input: &mut R) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_id<R: Read>(input: &mut R) -> io::Result<Option<usize>> {
+    let v = read_f64(input)?;
+    Ok(if v < 0.0 { None } else { Some(v as usize) })
+}
+
+// Writes every AOV of `images` as `<prefix>_<name>.pfm`
+pub fn write_aovs(prefix: &str, images: &[(Aov, FrameBuffer)]) -> io::Result<()> {
+    for (aov, image) in images.iter() {
+        image.write_pfm(&format!("{}_{}.pfm", prefix, aov.name()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit_sample(depth: f64, id: usize) -> AovSample {
+        AovSample {
+            hit: true,
+            normal: Vec3::new(0.0, 2.0, 0.0),
+            depth,
+            albedo: Vec3::new(0.5, 0.5, 0.5),
+            uv: (0.25, 0.75),
+            position: Vec3::new(1.0, 2.0, 3.0),
+            object_id: Some(id),
+            material_id: Some(id + 10),
+        }
+    }
+
+    #[test]
+    fn test_geometry_is_averaged_over_hits() {
+        let mut pixel = AovPixel::empty();
+        pixel.add(&hit_sample(2.0, 3));
+        pixel.add(&hit_sample(4.0, 5));
+        pixel.add(&AovSample::miss(Vec3::new(1.0, 1.0, 1.0)));
+
+        assert_eq!(pixel.value(Aov::Depth).x(), 3.0);
+        assert_eq!(pixel.value(Aov::Normal).y(), 1.0);
+        assert_eq!(pixel.value(Aov::Uv).y(), 0.75);
+        assert!((pixel.value(Aov::Albedo).x() - 2.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_ids_come_from_first_sample() {
+        let mut pixel = AovPixel::empty();
+        pixel.add(&hit_sample(1.0, 3));
+        pixel.add(&hit_sample(1.0, 5));
+
+        assert_eq!(pixel.value(Aov::ObjectId).x(), 3.0);
+        assert_eq!(pixel.value(Aov::MaterialId).x(), 13.0);
+        assert_eq!(AovPixel::empty().value(Aov::ObjectId).x(), -1.0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut pixel = AovPixel::empty();
+        pixel.add(&hit_sample(2.5, 7));
+        let mut bytes: Vec<u8> = vec![];
+        pixel.write_to(&mut bytes).unwrap();
+        let loaded = AovPixel::read_from(&mut bytes.as_slice()).unwrap();
+
+        for aov in Aov::ALL.iter() {
+            assert_eq!(loaded.value(*aov).x(), pixel.value(*aov).x());
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// Greyscale transmission image over the square [-1, 1]^2 of the lens, sampled
+// in proportion to its brightness. Rows are stored top to bottom.
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    // Running sums of the rows and of every row's pixels, normalised to end at 1
+    row_cdf: Vec<f64>,
+    pixel_cdf: Vec<f64>,
+}
+
+// Index of the first entry of the non decreasing `cdf` above u
+fn find_interval(cdf: &[f64], u: f64) -> usize {
+    cdf.iter().position(|c| u < *c).unwrap_or(cdf.len() - 1)
+}
+
+impl ApertureMask {
+    pub fn from_values(width: usize, height: usize, values: &[f64]) -> Self {
+        let mut pixel_cdf = vec![0.0; width * height];
+        let mut row_cdf = vec![0.0; height];
+        let mut total = 0.0;
+
+        for y in 0..height {
+            let mut row_total = 0.0;
+            for x in 0..width {
+                row_total += values[y * width + x].max(0.0);
+                pixel_cdf[y * width + x] = row_total;
+            }
+            for x in 0..width {
+                if row_total > 0.0 {
+                    pixel_cdf[y * width + x] /= row_total;
+                }
+            }
+            total += row_total;
+            row_cdf[y] = total;
+        }
+        for c in row_cdf.iter_mut() {
+            if total > 0.0 {
+                *c /= total;
+            }
+        }
+
+        Self {
+            width,
+            height,
+            row_cdf,
+            pixel_cdf,
+        }
+    }
+
+    // Uses the first channel of a PNG, e.g. a white hexagon on black
+    pub fn load(path: &str) -> io::Result<Self> {
+        let decoder = png::Decoder::new(File::open(path)?);
+        let (info, mut reader) = decoder
+            .read_info()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut buf = vec![0; info.buffer_size()];
+        reader
+            .next_frame(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let width = info.width as usize;
+        let height = info.height as usize;
+        let bytes_per_pixel = info.line_size / width;
+        let values: Vec<f64> = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                buf[y * info.line_size + x * bytes_per_pixel] as f64 / 255.0
+            })
+            .collect();
+
+        Ok(ApertureMask::from_values(width, height, &values))
+    }
+
+    // Picks a row, then a pixel in it, then a point in the pixel
+    fn sample(&self, u1: f64, u2: f64) -> (f64, f64) {
+        let y = find_interval(&self.row_cdf, u1);
+        let row = &self.pixel_cdf[y * self.width..(y + 1) * self.width];
+        let x = find_interval(row, u2);
+
+        let y_start = if y == 0 { 0.0 } else { self.row_cdf[y - 1] };
+        let x_start = if x == 0 { 0.0 } else { row[x - 1] };
+        let fy = (u1 - y_start) / (self.row_cdf[y] - y_start).max(f64::MIN_POSITIVE);
+        let fx = (u2 - x_start) / (row[x] - x_start).max(f64::MIN_POSITIVE);
+
+        let px = (x as f64 + fx.clamp(0.0, 1.0)) / self.width as f64;
+        let py = (y as f64 + fy.clamp(0.0, 1.0)) / self.height as f64;
+        (2.0 * px - 1.0, 1.0 - 2.0 * py)
+    }
+}
+
+#[derive(Clone)]
+pub enum ApertureShape {
+    Circle,
+    // Regular polygon inscribed in the unit circle, rotated by `rotation` degrees
+    Polygon { blades: u32, rotation: f64 },
+    Mask(Arc<ApertureMask>),
+}
+
+// Shape of the lens opening, which is the shape of out of focus highlights.
+// `cats_eye` clips the opening towards the edges of the frame the way the lens
+// barrel does, 0 turns it off and 1 leaves nothing at the corners. `anamorphic`
+// squeezes the opening horizontally, so a value of 2 gives bokeh twice as tall
+// as wide.
+#[derive(Clone)]
+pub struct Aperture {
+    pub shape: ApertureShape,
+    pub cats_eye: f64,
+    pub anamorphic: f64,
+}
+
+impl Aperture {
+    pub fn circle() -> Self {
+        Self {
+            shape: ApertureShape::Circle,
+            cats_eye: 0.0,
+            anamorphic: 1.0,
+        }
+    }
+
+    pub fn polygon(blades: u32, rotation: f64) -> Self {
+        Self {
+            shape: ApertureShape::Polygon { blades, rotation },
+            ..Aperture::circle()
+        }
+    }
+
+    pub fn mask(mask: ApertureMask) -> Self {
+        Self {
+            shape: ApertureShape::Mask(Arc::new(mask)),
+            ..Aperture::circle()
+        }
+    }
+
+    // Point on the lens for a ray through film position (s, t), in units of the
+    // lens radius, or None when the barrel blocks it. Always takes one 2D sample.
+    pub fn sample(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<(f64, f64)> {
+        let (x, y) = match &self.shape {
+            ApertureShape::Circle => {
+                let p = Vec3::random_in_unit_disk(sampler);
+                (p.x(), p.y())
+            }
+            ApertureShape::Polygon { blades, rotation } => {
+                let (u1, u2) = sampler.get_2d();
+                sample_polygon(*blades, *rotation, u1, u2)
+            }
+            ApertureShape::Mask(mask) => {
+                let (u1, u2) = sampler.get_2d();
+                mask.sample(u1, u2)
+            }
+        };
+
+        // The barrel is a second unit circle, moved towards the centre of the
+        // frame as the film position moves away from it
+        if self.cats_eye > 0.0 {
+            let cx = -(2.0 * s - 1.0) * self.cats_eye;
+            let cy = -(2.0 * t - 1.0) * self.cats_eye;
+            if (x - cx) * (x - cx) + (y - cy) * (y - cy) > 1.0 {
+                return None;
+            }
+        }
+
+        Some((x / self.anamorphic, y))
+    }
+}
+
+// One triangle per blade. u1 picks the triangle and is then reused inside it.
+fn sample_polygon(blades: u32, rotation: f64, u1: f64, u2: f64) -> (f64, f64) {
+    let n = blades.max(3) as f64;
+    let scaled = u1 * n;
+    let blade = scaled.floor().min(n - 1.0);
+    let a = (scaled - blade).sqrt();
+
+    let angle0 = rotation.to_radians() + 2.0 * PI * blade / n;
+    let angle1 = angle0 + 2.0 * PI / n;
+
+    let x = a * ((1.0 - u2) * angle0.cos() + u2 * angle1.cos());
+    let y = a * ((1.0 - u2) * angle0.sin() + u2 * angle1.sin());
+    (x, y)
+}
+
+// Camera settings in photographic units. Light is expected in cd/m^2, and the
+// exposure scale maps it to 1 at a middle grey exposure the same way a light
+// meter would (saturation based sensitivity).
+#[derive(Copy, Clone)]
+pub struct PhysicalCamera {
+    // Millimetres
+    pub focal_length: f64,
+    pub f_stop: f64,
+    // Seconds
+    pub shutter: f64,
+    pub iso: f64,
+    // Millimetres, 24 for full frame
+    pub sensor_height: f64,
+    // Scene units per metre
+    pub scene_scale: f64,
+}
+
+impl PhysicalCamera {
+    pub fn vfov(&self) -> f64 {
+        2.0 * (self.sensor_height / (2.0 * self.focal_length))
+            .atan()
+            .to_degrees()
+    }
+
+    // Diameter of the entrance pupil in scene units
+    pub fn aperture(&self) -> f64 {
+        self.focal_length / self.f_stop / 1000.0 * self.scene_scale
+    }
+
+    pub fn ev100(&self) -> f64 {
+        (self.f_stop * self.f_stop / self.shutter * 100.0 / self.iso).log2()
+    }
+
+    pub fn exposure_scale(&self) -> f64 {
+        1.0 / (1.2 * 2.0_f64.powf(self.ev100()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{create_sampler, SamplerType};
+
+    fn samples(aperture: &Aperture, s: f64, t: f64) -> Vec<Option<(f64, f64)>> {
+        let mut sampler = create_sampler(SamplerType::Sobol, 256, 1);
+        (0..256)
+            .map(|i| {
+                sampler.start_pixel_sample(0, 0, i);
+                aperture.sample(s, t, sampler.as_mut())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_polygon_stays_inside_blades() {
+        let aperture = Aperture::polygon(6, 15.0);
+        let apothem = (PI / 6.0).cos();
+
+        for (x, y) in samples(&aperture, 0.5, 0.5).iter().flatten() {
+            // Distance to every edge of the hexagon
+            for k in 0..6 {
+                let angle = (15.0_f64).to_radians() + PI / 6.0 + 2.0 * PI * k as f64 / 6.0;
+                assert!(x * angle.cos() + y * angle.sin() <= apothem + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_polygon_covers_all_blades() {
+        let aperture = Aperture::polygon(5, 0.0);
+        let mut seen = [false; 5];
+        for (x, y) in samples(&aperture, 0.5, 0.5).iter().flatten() {
+            let angle = y.atan2(*x).rem_euclid(2.0 * PI);
+            seen[((angle / (2.0 * PI / 5.0)) as usize).min(4)] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+    }
+
+    #[test]
+    fn test_mask_samples_bright_pixels_only() {
+        // Only the top right quarter is open
+        let mask = ApertureMask::from_values(2, 2, &[0.0, 1.0, 0.0, 0.0]);
+        let aperture = Aperture::mask(mask);
+
+        for (x, y) in samples(&aperture, 0.5, 0.5).iter().flatten() {
+            assert!(*x >= 0.0 && *y >= 0.0);
+        }
+    }
+
+    #[test]
+    fn test_cats_eye_only_clips_off_centre() {
+        let mut aperture = Aperture::circle();
+        aperture.cats_eye = 0.5;
+
+        assert!(samples(&aperture, 0.5, 0.5).iter().all(|s| s.is_some()));
+        let corner = samples(&aperture, 1.0, 1.0);
+        assert!(corner.iter().any(|s| s.is_none()));
+        // What is left is the part of the lens towards the frame centre
+        for (x, y) in corner.iter().flatten() {
+            assert!(x + y < 1.0);
+        }
+    }
+
+    #[test]
+    fn test_anamorphic_squeezes_horizontally() {
+        let mut aperture = Aperture::circle();
+        aperture.anamorphic = 2.0;
+
+        for (x, _) in samples(&aperture, 0.5, 0.5).iter().flatten() {
+            assert!(x.abs() <= 0.5 + 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_physical_exposure() {
+        let camera = PhysicalCamera {
+            focal_length: 50.0,
+            f_stop: 1.0,
+            shutter: 1.0,
+            iso: 100.0,
+            sensor_height: 24.0,
+            scene_scale: 1.0,
+        };
+        assert_eq!(camera.ev100(), 0.0);
+        assert!((camera.aperture() - 0.05).abs() < 1e-12);
+
+        // One stop down halves the light
+        let stopped = PhysicalCamera {
+            f_stop: 2.0_f64.sqrt(),
+            ..camera
+        };
+        assert!((stopped.exposure_scale() / camera.exposure_scale() - 0.5).abs() < 1e-12);
+        let faster = PhysicalCamera {
+            shutter: 0.5,
+            ..camera
+        };
+        assert!((faster.exposure_scale() / camera.exposure_scale() - 0.5).abs() < 1e-12);
+    }
+}
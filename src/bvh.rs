@@ -1,6 +1,7 @@
 use crate::aabb::AABB;
 use crate::HitAble;
-use crate::{hit::HitRecord, utility::random_int_from_values, world::World};
+use crate::rng::Rng;
+use crate::{hit::HitRecord, world::World};
 use std::cmp::Ordering;
 
 #[derive(Clone)]
@@ -8,7 +9,8 @@ pub struct BvhNode {
     pub left: Option<Box<BvhNode>>,
     pub right: Option<Box<BvhNode>>,
     pub node_box: AABB,
-    id: Option<usize>,
+    // Leaves hold the position of their object in the world's list
+    index: Option<usize>,
 }
 
 fn box_compare(a: &AABB, b: &AABB, axis: usize) -> Ordering {
@@ -27,54 +29,62 @@ impl BvhNode {
         left: Option<Box<BvhNode>>,
         right: Option<Box<BvhNode>>,
         node_box: AABB,
-        id: Option<usize>,
+        index: Option<usize>,
     ) -> BvhNode {
         BvhNode {
             left,
             right,
             node_box,
-            id,
+            index,
         }
     }
 
-    pub fn new_from_list(objects: &mut [Box<dyn HitAble>], time0: f64, time1: f64) -> BvhNode {
+    // Sorts `indices`, the positions in `objects` to build over, instead of
+    // the objects themselves, so the leaves still find theirs
+    pub fn new_from_list(
+        objects: &[Box<dyn HitAble>],
+        indices: &mut [usize],
+        time0: f64,
+        time1: f64,
+        rng: &mut Rng,
+    ) -> BvhNode {
         // eprintln!("{}", objects.len());
-        let axis = random_int_from_values(0, 2) as usize;
+        let axis = rng.random_int_from_values(0, 2) as usize;
 
-        let object_span = objects.len();
+        let object_span = indices.len();
 
         if object_span == 1 {
             // eprintln!("Leaf");
             // At 1 node we just return a leaf
             // This unwrap is dangerous. Need to fix this later
-            let aabb_box = objects[0].bounding_box(time0, time1).unwrap();
+            let aabb_box = objects[indices[0]].bounding_box(time0, time1).unwrap();
 
-            return BvhNode::new(None, None, aabb_box, objects[0].id());
+            return BvhNode::new(None, None, aabb_box, Some(indices[0]));
         } else if object_span == 2 {
             // eprintln!("Split Leaf");
             match box_compare(
-                &objects[0].bounding_box(time0, time1).unwrap(),
-                &objects[1].bounding_box(time0, time1).unwrap(),
+                &objects[indices[0]].bounding_box(time0, time1).unwrap(),
+                &objects[indices[1]].bounding_box(time0, time1).unwrap(),
                 axis,
             ) {
                 Ordering::Less => {
                     let left_node = BvhNode::new(
                         None,
                         None,
-                        objects[0].bounding_box(time0, time1).unwrap(),
-                        objects[0].id(),
+                        objects[indices[0]].bounding_box(time0, time1).unwrap(),
+                        Some(indices[0]),
                     );
                     let right_node = BvhNode::new(
                         None,
                         None,
-                        objects[1].bounding_box(time0, time1).unwrap(),
-                        objects[1].id(),
+                        objects[indices[1]].bounding_box(time0, time1).unwrap(),
+                        Some(indices[1]),
                     );
 
                     // Because im using ids. I can just build the last 2 levels of the node.
                     let aabb_box = AABB::surrounding_box(
-                        objects[0].bounding_box(time0, time1).unwrap(),
-                        objects[1].bounding_box(time0, time1).unwrap(),
+                        objects[indices[0]].bounding_box(time0, time1).unwrap(),
+                        objects[indices[1]].bounding_box(time0, time1).unwrap(),
                     );
 
                     return BvhNode::new(
@@ -88,20 +98,20 @@ impl BvhNode {
                     let left_node = BvhNode::new(
                         None,
                         None,
-                        objects[1].bounding_box(time0, time1).unwrap(),
-                        objects[1].id(),
+                        objects[indices[1]].bounding_box(time0, time1).unwrap(),
+                        Some(indices[1]),
                     );
                     let right_node = BvhNode::new(
                         None,
                         None,
-                        objects[0].bounding_box(time0, time1).unwrap(),
-                        objects[0].id(),
+                        objects[indices[0]].bounding_box(time0, time1).unwrap(),
+                        Some(indices[0]),
                     );
 
                     // Because im using ids. I can just build the last 2 levels of the node.
                     let aabb_box = AABB::surrounding_box(
-                        objects[0].bounding_box(time0, time1).unwrap(),
-                        objects[1].bounding_box(time0, time1).unwrap(),
+                        objects[indices[0]].bounding_box(time0, time1).unwrap(),
+                        objects[indices[1]].bounding_box(time0, time1).unwrap(),
                     );
 
                     return BvhNode::new(
@@ -114,19 +124,21 @@ impl BvhNode {
             }
         } else {
             // eprintln!("Digging further");
-            objects.sort_by(|a, b| {
+            indices.sort_by(|a, b| {
                 return box_compare(
-                    &a.bounding_box(time0, time1).unwrap(),
-                    &b.bounding_box(time0, time1).unwrap(),
+                    &objects[*a].bounding_box(time0, time1).unwrap(),
+                    &objects[*b].bounding_box(time0, time1).unwrap(),
                     axis,
                 );
             });
 
             let mid = 0 + object_span / 2;
-            let end = objects.len();
+            let end = indices.len();
 
-            let left_node = BvhNode::new_from_list(&mut objects[0..mid], time0, time1);
-            let right_node = BvhNode::new_from_list(&mut objects[mid..end], time0, time1);
+            let left_node =
+                BvhNode::new_from_list(objects, &mut indices[0..mid], time0, time1, rng);
+            let right_node =
+                BvhNode::new_from_list(objects, &mut indices[mid..end], time0, time1, rng);
 
             let aabb_box = AABB::surrounding_box(
                 left_node.bounding_box(time0, time1).unwrap(),
@@ -157,13 +169,11 @@ impl BvhNode {
 
         if self.left.is_none() && self.right.is_none() {
 
-            let (object, material) = world.get(self.id.unwrap());
-
-            if object.hit(r, t_min, t_max, rec) {
-                rec.set_id(self.id);
-                return true;
-            }
-            return false;            
+            // Through the world, which skips the parts materials cut away
+            return match self.index {
+                Some(index) => world.hit_object(world.object(index), r, t_min, t_max, rec),
+                None => false,
+            };
         }
 
         let hit_left = match &self.left {
@@ -1,5 +1,7 @@
-use crate::random_double_from_values;
+use crate::aperture::{Aperture, PhysicalCamera};
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::shutter::Shutter;
 use crate::vec3::Vec3;
 
 // origin: Vec3::new(0.0, 0.0, 0.0),
@@ -7,6 +9,36 @@ use crate::vec3::Vec3;
 // vertical: Vec3::new(0.0, 2.0, 0.0),
 // lower_left_corner: Vec3::new(-2.0, -1.0, -1.0),
 
+// Anything that turns a film position into a camera ray. (s, t) run from 0 to
+// 1 across the image, with t = 0 at the bottom.
+// None means the ray is blocked before it leaves the camera, by the lens
+// barrel or outside the image circle of a fisheye. Those samples are black.
+pub trait CameraModel: Send + Sync {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray>;
+
+    // Scale for all radiance seen through the camera
+    fn exposure(&self) -> f64 {
+        1.0
+    }
+
+    // Ray together with the scale for the radiance it brings back. Cameras
+    // that sample their lens unevenly weight each ray to make up for it.
+    fn get_weighted_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<(Ray, f64)> {
+        self.get_ray(s, t, sampler).map(|r| (r, self.exposure()))
+    }
+}
+
+// Orthonormal camera frame. w points backwards, away from lookat.
+pub fn basis(lookfrom: Vec3, lookat: Vec3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = Vec3::unit_vector(lookfrom - lookat);
+    let u = Vec3::unit_vector(Vec3::cross(&vup, &w));
+    let v = Vec3::cross(&w, &u);
+    (u, v, w)
+}
+
+// Perspective projection with a thin lens. The lens opening is round unless
+// another aperture is set with `with_aperture`.
+#[derive(Clone)]
 pub struct Camera {
     origin: Vec3,
     lower_left_corner: Vec3,
@@ -16,8 +48,9 @@ pub struct Camera {
     v: Vec3,
     w: Vec3,
     lens_radius: f64,
-    time0: f64,
-    time1: f64,
+    aperture: Aperture,
+    exposure: f64,
+    shutter: Shutter,
 }
 
 impl Camera {
@@ -37,9 +70,7 @@ impl Camera {
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = Vec3::unit_vector(lookfrom - lookat);
-        let u = Vec3::unit_vector(Vec3::cross(&vup, &w));
-        let v = Vec3::cross(&w, &u);
+        let (u, v, w) = basis(lookfrom, lookat, vup);
 
         let origin = lookfrom;
         let horizontal = u * viewport_width * focus_dist;
@@ -57,24 +88,267 @@ impl Camera {
             v: v,
             w: w,
             lens_radius: lens_radius,
+            aperture: Aperture::circle(),
+            exposure: 1.0,
+            shutter: Shutter::new(time0, time1),
+        }
+    }
+
+    // Field of view, lens opening and exposure all follow from the
+    // photographic settings. The lens is focused on lookat.
+    pub fn physical(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        aspect_ratio: f64,
+        settings: &PhysicalCamera,
+        time0: f64,
+        time1: f64,
+    ) -> Camera {
+        let mut camera = Camera::new(
+            lookfrom,
+            lookat,
+            vup,
+            settings.vfov(),
+            aspect_ratio,
+            settings.aperture(),
+            (lookat - lookfrom).length(),
             time0,
             time1,
+        );
+        camera.exposure = settings.exposure_scale();
+        camera
+    }
+
+    pub fn with_aperture(mut self, aperture: Aperture) -> Camera {
+        self.aperture = aperture;
+        self
+    }
+
+    // Replaces the uniform shutter from time0 to time1
+    pub fn with_shutter(mut self, shutter: Shutter) -> Camera {
+        self.shutter = shutter;
+        self
+    }
+
+    // One eye of a stereo pair: the camera moved `offset` along u, with the
+    // viewing window shifted back so both eyes share it at the `convergence`
+    // distance. That is the off-axis setup, the view directions stay parallel
+    // and objects at the convergence distance end up with zero parallax.
+    pub fn eye(&self, offset: f64, convergence: f64) -> Camera {
+        let centre = self.lower_left_corner + self.horizontal / 2.0 + self.vertical / 2.0;
+        let focus_dist = Vec3::dot(&(self.origin - centre), &self.w);
+        let shift = self.u * (offset - offset * focus_dist / convergence);
+
+        Camera {
+            origin: self.origin + self.u * offset,
+            lower_left_corner: self.lower_left_corner + shift,
+            ..self.clone()
         }
     }
+}
 
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd = Vec3::random_in_unit_disk() * self.lens_radius;
-        let offset = self.u * rd.x() + self.v * rd.y();
+impl CameraModel for Camera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (x, y) = self.aperture.sample(s, t, sampler)?;
+        let offset = (self.u * x + self.v * y) * self.lens_radius;
 
         let origin = self.origin + offset;
         let direction = self.lower_left_corner + (self.horizontal * s) + (self.vertical * t)
             - self.origin
             - offset;
 
-        Ray::new(
+        Some(Ray::new(&origin, &direction, self.shutter.sample(sampler)))
+    }
+
+    fn exposure(&self) -> f64 {
+        self.exposure
+    }
+}
+
+// Parallel projection. Rays start on a view_width x view_height rectangle
+// centred on lookfrom and all travel along the view direction. A non zero
+// aperture, set with `with_focus`, still focuses them on the plane focus_dist
+// in front of the camera.
+pub struct OrthographicCamera {
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    lens_radius: f64,
+    focus_dist: f64,
+    shutter: Shutter,
+}
+
+impl OrthographicCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        view_width: f64,
+        view_height: f64,
+        time0: f64,
+        time1: f64,
+    ) -> OrthographicCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+
+        let horizontal = u * view_width;
+        let vertical = v * view_height;
+
+        OrthographicCamera {
+            lower_left_corner: lookfrom - (horizontal / 2.0) - (vertical / 2.0),
+            horizontal,
+            vertical,
+            u,
+            v,
+            w,
+            lens_radius: 0.0,
+            focus_dist: 1.0,
+            shutter: Shutter::new(time0, time1),
+        }
+    }
+
+    pub fn with_focus(mut self, aperture: f64, focus_dist: f64) -> OrthographicCamera {
+        self.lens_radius = aperture / 2.0;
+        self.focus_dist = focus_dist;
+        self
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> OrthographicCamera {
+        self.shutter = shutter;
+        self
+    }
+}
+
+impl CameraModel for OrthographicCamera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let rd = Vec3::random_in_unit_disk(sampler) * self.lens_radius;
+        let offset = self.u * rd.x() + self.v * rd.y();
+
+        let point = self.lower_left_corner + (self.horizontal * s) + (self.vertical * t);
+        let focus = point - self.w * self.focus_dist;
+        let origin = point + offset;
+
+        Some(Ray::new(
             &origin,
-            &direction,
-            random_double_from_values(self.time0, self.time1),
+            &(focus - origin),
+            self.shutter.sample(sampler),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{create_sampler, SamplerType};
+    use crate::shutter::ShutterCurve;
+
+    #[test]
+    fn test_orthographic_rays_are_parallel() {
+        let cam = OrthographicCamera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            4.0,
+            2.0,
+            0.0,
+            1.0,
+        );
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+
+        let a = cam.get_ray(0.0, 0.0, sampler.as_mut()).unwrap();
+        let b = cam.get_ray(1.0, 1.0, sampler.as_mut()).unwrap();
+
+        assert!((Vec3::unit_vector(a.direction()) - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-12);
+        assert!((Vec3::unit_vector(b.direction()) - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-12);
+        assert!((a.origin() - Vec3::new(-2.0, -1.0, 5.0)).length() < 1e-12);
+        assert!((b.origin() - Vec3::new(2.0, 1.0, 5.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_orthographic_depth_of_field_focuses_on_plane() {
+        let cam = OrthographicCamera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            4.0,
+            2.0,
+            0.0,
+            1.0,
+        )
+        .with_focus(1.0, 5.0);
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+
+        for _ in 0..8 {
+            let r = cam.get_ray(0.75, 0.5, sampler.as_mut()).unwrap();
+            let focus = r.at(1.0);
+            assert!((focus - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_perspective_centre_ray_looks_at_target() {
+        let cam = Camera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            1.0,
+        );
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+        let r = cam.get_ray(0.5, 0.5, sampler.as_mut()).unwrap();
+
+        assert!((Vec3::unit_vector(r.direction()) - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_ray_times_follow_the_shutter() {
+        let shutter = Shutter::with_curve(2.0, 3.0, ShutterCurve::Table(vec![0.0, 0.0, 1.0]));
+        let cam = OrthographicCamera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            4.0,
+            2.0,
+            0.0,
+            1.0,
+        )
+        .with_shutter(shutter);
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+
+        for _ in 0..64 {
+            let time = cam.get_ray(0.5, 0.5, sampler.as_mut()).unwrap().time();
+            assert!((2.5..=3.0).contains(&time));
+        }
+    }
+
+    #[test]
+    fn test_cats_eye_blocks_rays_at_the_corners() {
+        let mut aperture = Aperture::polygon(6, 0.0);
+        aperture.cats_eye = 0.6;
+        let cam = Camera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.0,
+            1.0,
+            5.0,
+            0.0,
+            1.0,
         )
+        .with_aperture(aperture);
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+
+        let centre = (0..64).filter(|_| cam.get_ray(0.5, 0.5, sampler.as_mut()).is_some());
+        assert_eq!(centre.count(), 64);
+        let corner = (0..64).filter(|_| cam.get_ray(0.0, 1.0, sampler.as_mut()).is_some());
+        assert!(corner.count() < 64);
     }
 }
@@ -0,0 +1,174 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::film::Film;
+use crate::render::RenderSettings;
+use crate::sampler::SamplerType;
+
+const MAGIC: &[u8; 4] = b"RTCK";
+const VERSION: u32 = 3;
+
+// Everything needed to carry on with a render later: the accumulated film with
+// its per pixel sample counts, plus the seed and sampler and the sample count
+// the sampler was laid out for. Samplers restart from (pixel, sample index), so
+// those together are the full random number state.
+pub struct Checkpoint {
+    pub seed: u64,
+    pub sampler: SamplerType,
+    pub sampler_samples: u32,
+    pub film: Film,
+}
+
+fn sampler_id(sampler: SamplerType) -> u8 {
+    match sampler {
+        SamplerType::Independent => 0,
+        SamplerType::Stratified => 1,
+        SamplerType::Halton => 2,
+        SamplerType::Sobol => 3,
+    }
+}
+
+fn sampler_from_id(id: u8) -> io::Result<SamplerType> {
+    match id {
+        0 => Ok(SamplerType::Independent),
+        1 => Ok(SamplerType::Stratified),
+        2 => Ok(SamplerType::Halton),
+        3 => Ok(SamplerType::Sobol),
+        _ => Err(invalid("unknown sampler")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+impl Checkpoint {
+    // Written to a temporary file first and then renamed, so an interrupted
+    // write never replaces a good checkpoint with a broken one
+    pub fn save(
+        path: &str,
+        film: &Film,
+        seed: u64,
+        sampler: SamplerType,
+        sampler_samples: u32,
+    ) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", path);
+        {
+            let mut out = BufWriter::new(File::create(&temp_path)?);
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+            out.write_all(&(film.width() as u64).to_le_bytes())?;
+            out.write_all(&(film.height() as u64).to_le_bytes())?;
+            out.write_all(&seed.to_le_bytes())?;
+            out.write_all(&(sampler_samples as u64).to_le_bytes())?;
+            out.write_all(&[
+                sampler_id(sampler),
+                film.has_aovs() as u8,
+                film.has_id_mattes() as u8,
+            ])?;
+            film.write_pixels(&mut out)?;
+            out.flush()?;
+        }
+        fs::rename(&temp_path, path)
+    }
+
+    pub fn load(path: &str) -> io::Result<Checkpoint> {
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a checkpoint file"));
+        }
+
+        let mut version = [0; 4];
+        input.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != VERSION {
+            return Err(invalid("unsupported checkpoint version"));
+        }
+
+        let width = read_u64(&mut input)? as usize;
+        let height = read_u64(&mut input)? as usize;
+        let seed = read_u64(&mut input)?;
+        let sampler_samples = read_u64(&mut input)? as u32;
+
+        let mut flags = [0; 3];
+        input.read_exact(&mut flags)?;
+        let sampler = sampler_from_id(flags[0])?;
+
+        let film = Film::read_pixels(&mut input, width, height, flags[1] != 0, flags[2] != 0)?;
+
+        Ok(Checkpoint {
+            seed,
+            sampler,
+            sampler_samples,
+            film,
+        })
+    }
+
+    // Resuming with another seed or sampler would repeat or correlate samples
+    pub fn check_settings(&self, settings: &RenderSettings) -> io::Result<()> {
+        if self.film.width() != settings.image_width || self.film.height() != settings.image_height
+        {
+            return Err(invalid("checkpoint resolution does not match"));
+        }
+        if self.seed != settings.seed || self.sampler != settings.sampler {
+            return Err(invalid("checkpoint seed or sampler does not match"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BoxFilter;
+    use crate::vec3::Vec3;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("ray-trace-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let filter = BoxFilter::new(0.5);
+        let mut film = Film::new(3, 2);
+        film.add_sample(2, 1, 2.3, 1.6, Vec3::new(0.25, 1.5, 3.0), &filter);
+        film.add_sample(2, 1, 2.7, 1.1, Vec3::new(0.75, 0.5, 1.0), &filter);
+
+        let path = temp_path("round-trip.ckpt");
+        Checkpoint::save(&path, &film, 17, SamplerType::Halton, 64).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.seed, 17);
+        assert_eq!(loaded.sampler, SamplerType::Halton);
+        assert_eq!(loaded.sampler_samples, 64);
+        assert_eq!(loaded.film.width(), 3);
+        assert_eq!(loaded.film.pixel(2, 1).samples(), 2);
+        assert_eq!(loaded.film.pixel(2, 1).color().y(), 1.0);
+        assert_eq!(
+            loaded.film.pixel(2, 1).relative_error(),
+            film.pixel(2, 1).relative_error()
+        );
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let path = temp_path("garbage.ckpt");
+        fs::write(&path, b"P3\n1 1\n255\n0 0 0\n").unwrap();
+        let result = Checkpoint::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
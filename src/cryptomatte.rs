@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::aov::AovSample;
+use crate::framebuffer::FrameBuffer;
+use crate::vec3::Vec3;
+use crate::world::World;
+
+// Cryptomatte style ID mattes. Every pixel counts how many of its samples saw
+// each object and each material first, so an id's coverage is the anti-aliased
+// fraction of the pixel it covers, including partial coverage at edges and
+// through motion blur or depth of field.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IdKind {
+    Object,
+    Material,
+}
+
+impl IdKind {
+    pub const ALL: [IdKind; 2] = [IdKind::Object, IdKind::Material];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdKind::Object => "CryptoObject",
+            IdKind::Material => "CryptoMaterial",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MattePixel {
+    objects: Vec<(usize, u32)>,
+    materials: Vec<(usize, u32)>,
+    samples: u32,
+}
+
+fn add_count(counts: &mut Vec<(usize, u32)>, id: usize, count: u32) {
+    match counts.iter_mut().find(|(i, _)| *i == id) {
+        Some(entry) => entry.1 += count,
+        None => counts.push((id, count)),
+    }
+}
+
+fn write_counts<W: Write>(out: &mut W, counts: &[(usize, u32)]) -> io::Result<()> {
+    out.write_all(&(counts.len() as u32).to_le_bytes())?;
+    for (id, count) in counts.iter() {
+        out.write_all(&(*id as u64).to_le_bytes())?;
+        out.write_all(&count.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_counts<R: Read>(input: &mut R) -> io::Result<Vec<(usize, u32)>> {
+    let len = read_u32(input)?;
+    let mut counts = vec![];
+    for _ in 0..len {
+        let mut id = [0; 8];
+        input.read_exact(&mut id)?;
+        counts.push((u64::from_le_bytes(id) as usize, read_u32(input)?));
+    }
+    Ok(counts)
+}
+
+impl MattePixel {
+    pub fn empty() -> Self {
+        Self {
+            objects: vec![],
+            materials: vec![],
+            samples: 0,
+        }
+    }
+
+    // Misses count towards the samples but belong to no id
+    pub fn add(&mut self, sample: &AovSample) {
+        self.samples += 1;
+        if let Some(id) = sample.object_id {
+            add_count(&mut self.objects, id, 1);
+        }
+        if let Some(id) = sample.material_id {
+            add_count(&mut self.materials, id, 1);
+        }
+    }
+
+    pub fn merge(&mut self, other: &MattePixel) {
+        self.samples += other.samples;
+        for (id, count) in other.objects.iter() {
+            add_count(&mut self.objects, *id, *count);
+        }
+        for (id, count) in other.materials.iter() {
+            add_count(&mut self.materials, *id, *count);
+        }
+    }
+
+    // Ids with their coverage, most coverage first. Ties go to the lower id so
+    // the order does not depend on the order samples were merged in.
+    pub fn ranked(&self, kind: IdKind) -> Vec<(usize, f64)> {
+        let counts = match kind {
+            IdKind::Object => &self.objects,
+            IdKind::Material => &self.materials,
+        };
+        let mut ranked = counts.clone();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+            .iter()
+            .map(|(id, count)| (*id, *count as f64 / self.samples as f64))
+            .collect()
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.samples.to_le_bytes())?;
+        write_counts(out, &self.objects)?;
+        write_counts(out, &self.materials)
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let samples = read_u32(input)?;
+        let objects = read_counts(input)?;
+        let materials = read_counts(input)?;
+        Ok(Self {
+            objects,
+            materials,
+            samples,
+        })
+    }
+}
+
+// PFM only has three channels, so each rank is its own layer holding the id in
+// red and the coverage in green. Pixels with fewer ids get -1 and 0 coverage.
+pub fn rank_image(pixels: &[MattePixel], width: usize, kind: IdKind, rank: usize) -> FrameBuffer {
+    let height = pixels.len() / width.max(1);
+    let mut frame = FrameBuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let ranked = pixels[y * width + x].ranked(kind);
+            let value = match ranked.get(rank) {
+                Some((id, coverage)) => Vec3::new(*id as f64, *coverage, 0.0),
+                None => Vec3::new(-1.0, 0.0, 0.0),
+            };
+            frame.set(x, y, value);
+        }
+    }
+    frame
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Maps every id of the scene to its name, e.g. {"3": "short_box"}
+pub fn manifest(world: &World, kind: IdKind) -> String {
+    let names: Vec<String> = match kind {
+        IdKind::Object => (0..world.object_count())
+            .map(|id| world.object_name(id))
+            .collect(),
+        IdKind::Material => (0..world.material_count())
+            .map(|id| world.material_name(id))
+            .collect(),
+    };
+
+    let entries: Vec<String> = names
+        .iter()
+        .enumerate()
+        .map(|(id, name)| format!("\"{}\": \"{}\"", id, escape_json(name)))
+        .collect();
+    format!("{{{}}}\n", entries.join(", "))
+}
+
+// Writes `<prefix>_<kind><rank>.pfm` for the top `ranks` ids of every pixel and
+// `<prefix>_<kind>_manifest.json`, for objects and materials
+pub fn write_mattes(
+    prefix: &str,
+    pixels: &[MattePixel],
+    width: usize,
+    ranks: usize,
+    world: &World,
+) -> io::Result<()> {
+    for kind in IdKind::ALL.iter() {
+        for rank in 0..ranks {
+            let path = format!("{}_{}{:02}.pfm", prefix, kind.name(), rank);
+            rank_image(pixels, width, *kind, rank).write_pfm(&path)?;
+        }
+
+        let path = format!("{}_{}_manifest.json", prefix, kind.name());
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(manifest(world, *kind).as_bytes())?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::HitAble;
+    use crate::material::{Lambertian, Material};
+    use crate::models::Sphere;
+    use crate::rng::Rng;
+    use std::sync::Arc;
+
+    fn sample(object_id: Option<usize>, material_id: Option<usize>) -> AovSample {
+        let mut sample = AovSample::miss(Vec3::empty());
+        sample.hit = object_id.is_some();
+        sample.object_id = object_id;
+        sample.material_id = material_id;
+        sample
+    }
+
+    #[test]
+    fn test_coverage_is_fraction_of_samples() {
+        let mut pixel = MattePixel::empty();
+        pixel.add(&sample(Some(4), Some(1)));
+        pixel.add(&sample(Some(2), Some(1)));
+        pixel.add(&sample(Some(4), Some(1)));
+        pixel.add(&sample(None, None));
+
+        assert_eq!(pixel.ranked(IdKind::Object), vec![(4, 0.5), (2, 0.25)]);
+        assert_eq!(pixel.ranked(IdKind::Material), vec![(1, 0.75)]);
+    }
+
+    #[test]
+    fn test_ties_are_ranked_by_id() {
+        let mut a = MattePixel::empty();
+        a.add(&sample(Some(7), None));
+        let mut b = MattePixel::empty();
+        b.add(&sample(Some(3), None));
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+
+        assert_eq!(ab.ranked(IdKind::Object), ba.ranked(IdKind::Object));
+        assert_eq!(ab.ranked(IdKind::Object)[0].0, 3);
+    }
+
+    #[test]
+    fn test_rank_image_marks_missing_ranks() {
+        let mut pixel = MattePixel::empty();
+        pixel.add(&sample(Some(5), Some(0)));
+        let image = rank_image(&[pixel], 1, IdKind::Object, 1);
+
+        assert_eq!(image.get(0, 0).x(), -1.0);
+        assert_eq!(image.get(0, 0).y(), 0.0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut pixel = MattePixel::empty();
+        pixel.add(&sample(Some(5), Some(2)));
+        pixel.add(&sample(Some(6), Some(2)));
+        let mut bytes: Vec<u8> = vec![];
+        pixel.write_to(&mut bytes).unwrap();
+        let loaded = MattePixel::read_from(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(loaded.ranked(IdKind::Object), pixel.ranked(IdKind::Object));
+        assert_eq!(
+            loaded.ranked(IdKind::Material),
+            pixel.ranked(IdKind::Material)
+        );
+    }
+
+    #[test]
+    fn test_manifest_uses_scene_names() {
+        let shared: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let objects: Vec<Box<dyn HitAble>> = vec![
+            Box::new(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 0)),
+            Box::new(Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5, 1)),
+        ];
+        let mut world = World::new(objects, vec![shared.clone(), shared], &mut Rng::new(1));
+        world.set_object_name(1, "ball");
+
+        assert_eq!(
+            manifest(&world, IdKind::Object),
+            "{\"0\": \"object_0\", \"1\": \"ball\"}\n"
+        );
+        assert_eq!(
+            manifest(&world, IdKind::Material),
+            "{\"0\": \"material_0\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_escape_json() {
+        assert_eq!(escape_json("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
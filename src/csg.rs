@@ -0,0 +1,296 @@
+use crate::aabb::AABB;
+use crate::hit::{HitAble, HitRecord};
+use crate::ray::Ray;
+use crate::utility::{ffmax, ffmin};
+use crate::vec3::Vec3;
+
+// More crossings than this along one ray and the rest are ignored
+const MAX_CROSSINGS: usize = 64;
+// Crossings closer than this, relative to their distance, are the same one,
+// like the two faces that meet at a box edge
+const CROSSING_EPSILON: f64 = 1e-7;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // What is in a but not in b
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(&self, a: bool, b: bool) -> bool {
+        match self {
+            CsgOperation::Union => a || b,
+            CsgOperation::Intersection => a && b,
+            CsgOperation::Difference => a && !b,
+        }
+    }
+}
+
+// Combines two closed objects. A hit on the result is a hit on one of them,
+// with that operand's id, so each keeps its own material. Give the operands
+// ids of their own, the node itself has none.
+pub struct Csg {
+    operation: CsgOperation,
+    a: Box<dyn HitAble>,
+    b: Box<dyn HitAble>,
+}
+
+// Every time the ray crosses the surface of a closed object after t_min, in
+// order, up to the first one past t_max. front_face tells whether it goes in
+// or out.
+fn crossings(object: &dyn HitAble, r: &Ray, t_min: f64, t_max: f64) -> Vec<HitRecord> {
+    let mut hits: Vec<HitRecord> = vec![];
+    let mut t = t_min;
+
+    while hits.len() < MAX_CROSSINGS && t <= t_max {
+        let mut rec = HitRecord::empty();
+        if !object.hit(r, t, f64::INFINITY, &mut rec) {
+            break;
+        }
+        let epsilon = CROSSING_EPSILON * rec.t().abs().max(1.0);
+        t = rec.t() + epsilon;
+
+        match hits.last() {
+            // Going in or out twice at once is one crossing, in and out at
+            // once only grazes the surface
+            Some(last) if rec.t() - last.t() < epsilon => {
+                if last.front_face() != rec.front_face() {
+                    hits.pop();
+                }
+            }
+            _ => hits.push(rec),
+        }
+    }
+    hits
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, a: Box<dyn HitAble>, b: Box<dyn HitAble>) -> Self {
+        Self { operation, a, b }
+    }
+
+    pub fn union(a: Box<dyn HitAble>, b: Box<dyn HitAble>) -> Self {
+        Csg::new(CsgOperation::Union, a, b)
+    }
+
+    pub fn intersection(a: Box<dyn HitAble>, b: Box<dyn HitAble>) -> Self {
+        Csg::new(CsgOperation::Intersection, a, b)
+    }
+
+    pub fn difference(a: Box<dyn HitAble>, b: Box<dyn HitAble>) -> Self {
+        Csg::new(CsgOperation::Difference, a, b)
+    }
+}
+
+impl HitAble for Csg {
+    // Walks the crossings of both operands in order, keeping track of whether
+    // the ray is inside each. The first crossing that takes it in or out of
+    // the combined shape is the hit.
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let a_hits = crossings(self.a.as_ref(), r, t_min, t_max);
+        let b_hits = crossings(self.b.as_ref(), r, t_min, t_max);
+
+        // Leaving an object first means the ray started inside it
+        let mut in_a = a_hits.first().is_some_and(|h| !h.front_face());
+        let mut in_b = b_hits.first().is_some_and(|h| !h.front_face());
+        let inside = self.operation.inside(in_a, in_b);
+
+        let (mut i, mut j) = (0, 0);
+        while i < a_hits.len() || j < b_hits.len() {
+            let from_a = j == b_hits.len() || (i < a_hits.len() && a_hits[i].t() <= b_hits[j].t());
+            let hit = if from_a {
+                in_a = !in_a;
+                i += 1;
+                &a_hits[i - 1]
+            } else {
+                in_b = !in_b;
+                j += 1;
+                &b_hits[j - 1]
+            };
+            if hit.t() >= t_max {
+                return false;
+            }
+
+            let now_inside = self.operation.inside(in_a, in_b);
+            if now_inside == inside {
+                continue;
+            }
+
+            rec.set_t(hit.t());
+            rec.set_p(hit.p());
+            rec.set_uv((hit.u(), hit.v()));
+            rec.set_dpdu(hit.dpdu());
+            rec.set_dpdv(hit.dpdv());
+            rec.set_id(hit.id());
+            // What is cut away by b is bounded by b's surface turned inside out
+            let outward = if hit.front_face() {
+                hit.normal()
+            } else {
+                -hit.normal()
+            };
+            let flip = !from_a && self.operation == CsgOperation::Difference;
+            rec.set_face_normal(r, &if flip { -outward } else { outward });
+            return true;
+        }
+
+        false
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<AABB> {
+        let a = self.a.bounding_box(time0, time1);
+        let b = self.b.bounding_box(time0, time1);
+
+        match self.operation {
+            CsgOperation::Union => Some(AABB::surrounding_box(a?, b?)),
+            CsgOperation::Intersection => match (a, b) {
+                (Some(a), Some(b)) => {
+                    let min = Vec3::new(
+                        ffmax(a.min().x(), b.min().x()),
+                        ffmax(a.min().y(), b.min().y()),
+                        ffmax(a.min().z(), b.min().z()),
+                    );
+                    let max = Vec3::new(
+                        ffmin(a.max().x(), b.max().x()),
+                        ffmin(a.max().y(), b.max().y()),
+                        ffmin(a.max().z(), b.max().z()),
+                    );
+                    Some(AABB::new(min, max))
+                }
+                (a, None) => a,
+                (None, b) => b,
+            },
+            CsgOperation::Difference => a,
+        }
+    }
+
+    // Hits carry the id of the operand they are on
+    fn id(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit};
+    use crate::material::{Lambertian, Material};
+    use crate::models::{Box3D, Sphere};
+    use crate::rng::Rng;
+    use crate::world::World;
+    use std::sync::Arc;
+
+    fn sphere(x: f64, id: usize) -> Box<dyn HitAble> {
+        Box::new(Sphere::new(Vec3::new(x, 0.0, 0.0), 1.0, id))
+    }
+
+    fn left() -> Vec3 {
+        Vec3::new(-1.0, 0.0, 0.0)
+    }
+
+    #[test]
+    fn test_union_skips_inner_surfaces() {
+        let union = Csg::union(sphere(0.0, 1), sphere(1.0, 2));
+
+        // In through the right sphere and out through the left one
+        let rec = hit(&union, Vec3::new(5.0, 0.0, 0.0), left()).unwrap();
+        assert!(close(rec.p(), Vec3::new(2.0, 0.0, 0.0), 1e-6));
+        assert_eq!(rec.id(), Some(2));
+        let r = Ray::new(&Vec3::new(5.0, 0.0, 0.0), &left(), 0.0);
+        let mut rec = HitRecord::empty();
+        assert!(union.hit(&r, 3.5, f64::INFINITY, &mut rec));
+        assert!(close(rec.p(), Vec3::new(-1.0, 0.0, 0.0), 1e-6));
+        assert_eq!(rec.id(), Some(1));
+        assert!(!rec.front_face());
+    }
+
+    #[test]
+    fn test_intersection_makes_a_lens() {
+        let lens = Csg::intersection(sphere(0.0, 1), sphere(1.0, 2));
+
+        let rec = hit(&lens, Vec3::new(5.0, 0.0, 0.0), left()).unwrap();
+        assert!(close(rec.p(), Vec3::new(1.0, 0.0, 0.0), 1e-6));
+        assert_eq!(rec.id(), Some(1));
+        assert!(close(rec.normal(), Vec3::new(1.0, 0.0, 0.0), 1e-6));
+
+        // Outside the overlap nothing is there
+        assert!(hit(&lens, Vec3::new(-0.5, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)).is_none());
+
+        let bbox = lens.bounding_box(0.0, 1.0).unwrap();
+        assert!(close(*bbox.min(), Vec3::new(0.0, -1.0, -1.0), 1e-6));
+        assert!(close(*bbox.max(), Vec3::new(1.0, 1.0, 1.0), 1e-6));
+    }
+
+    #[test]
+    fn test_difference_flips_subtracted_surfaces() {
+        // A sphere with a box cut out of its right half
+        let cut = Box::new(Box3D::new(
+            Vec3::new(0.5, -2.0, -2.0),
+            Vec3::new(2.0, 2.0, 2.0),
+            2,
+        ));
+        let difference = Csg::difference(sphere(0.0, 1), cut);
+
+        let rec = hit(&difference, Vec3::new(5.0, 0.0, 0.0), left()).unwrap();
+        assert!(close(rec.p(), Vec3::new(0.5, 0.0, 0.0), 1e-6));
+        assert_eq!(rec.id(), Some(2));
+        // The box face is the outside of the result here, facing the ray
+        assert!(rec.front_face());
+        assert!(close(rec.normal(), Vec3::new(1.0, 0.0, 0.0), 1e-6));
+
+        // The untouched half is still there
+        let rec = hit(&difference, Vec3::new(-5.0, 0.0, 0.0), -left()).unwrap();
+        assert!(close(rec.p(), Vec3::new(-1.0, 0.0, 0.0), 1e-6));
+        assert_eq!(rec.id(), Some(1));
+
+        let bbox = difference.bounding_box(0.0, 1.0).unwrap();
+        assert!(close(*bbox.max(), Vec3::new(1.0, 1.0, 1.0), 1e-6));
+    }
+
+    #[test]
+    fn test_ray_starting_inside() {
+        let difference = Csg::difference(sphere(0.0, 1), sphere(0.0, 2));
+        // A hollow shell would need a smaller b, this one is empty
+        assert!(hit(&difference, Vec3::empty(), left()).is_none());
+
+        let shell = Csg::difference(sphere(0.0, 1), Box::new(Sphere::new(Vec3::empty(), 0.5, 2)));
+        let rec = hit(&shell, Vec3::empty(), left()).unwrap();
+        assert!(close(rec.p(), Vec3::new(-0.5, 0.0, 0.0), 1e-6));
+        assert!(rec.front_face());
+    }
+
+    #[test]
+    fn test_ray_through_box_edge() {
+        let cube = Box3D::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0), 1);
+        // In through the edge at x = y = 1 and out through the one at -1
+        let r = Ray::new(&Vec3::new(3.0, 3.0, 0.2), &Vec3::new(-1.0, -1.0, 0.0), 0.0);
+        let hits = crossings(&cube, &r, 0.0, f64::INFINITY);
+        assert_eq!(hits.len(), 2);
+        assert!(hits[0].front_face());
+        assert!(!hits[1].front_face());
+        assert_eq!(crossings(&cube, &r, 0.0, 1.0).len(), 1);
+
+        // Counting the edge twice would take the ray back out of the cube and
+        // hit the far edge instead
+        let ball = Box::new(Sphere::new(Vec3::empty(), 3.0, 2));
+        let rounded = Csg::intersection(Box::new(cube), ball);
+        let rec = hit(&rounded, r.origin(), r.direction()).unwrap();
+        assert!(close(rec.p(), Vec3::new(1.0, 1.0, 0.2), 1e-6));
+        assert_eq!(rec.id(), Some(1));
+    }
+
+    #[test]
+    fn test_world_keeps_operand_ids() {
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(1.0, 0.0, 0.0)));
+        let blue: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.0, 0.0, 1.0)));
+        let objects: Vec<Box<dyn HitAble>> =
+            vec![Box::new(Csg::union(sphere(0.0, 0), sphere(1.0, 1)))];
+        let world = World::new(objects, vec![red, blue], &mut Rng::new(1));
+
+        let r = Ray::new(&Vec3::new(5.0, 0.0, 0.0), &left(), 0.0);
+        assert_eq!(world.hit(&r, 0.001, f64::INFINITY).unwrap().id(), Some(1));
+        let r = Ray::new(&Vec3::new(-5.0, 0.0, 0.0), &-left(), 0.0);
+        assert_eq!(world.hit(&r, 0.001, f64::INFINITY).unwrap().id(), Some(0));
+    }
+}
@@ -0,0 +1,432 @@
+use crate::aabb::AABB;
+use crate::hit::{HitAble, HitRecord};
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+#[derive(Copy, Clone)]
+pub enum CurveType {
+    // Always faces the ray and is shaded like a round fibre
+    Cylinder,
+    // A flat strip whose normal turns from n0 at the start to n1 at the end.
+    // Seen edge on it gets thinner and disappears.
+    Ribbon { n0: Vec3, n1: Vec3 },
+}
+
+// Cubic Bezier curve with a width that changes linearly from start to end, for
+// hair, fur and grass. u runs along the curve and v across it, 0.5 on the
+// centre line, and dpdu is set on hits so hair shading can find the tangent.
+//
+// Rays are tested by splitting the curve in halves until the pieces are close
+// to straight, dropping every piece whose control points are too far from the
+// ray, and intersecting the ray with the straight pieces left.
+pub struct Curve {
+    cp: [Vec3; 4],
+    width0: f64,
+    width1: f64,
+    curve_type: CurveType,
+    id: usize,
+}
+
+fn lerp(t: f64, a: Vec3, b: Vec3) -> Vec3 {
+    a * (1.0 - t) + b * t
+}
+
+// Point and derivative at u
+fn eval_bezier(cp: &[Vec3], u: f64) -> (Vec3, Vec3) {
+    let cp1 = [
+        lerp(u, cp[0], cp[1]),
+        lerp(u, cp[1], cp[2]),
+        lerp(u, cp[2], cp[3]),
+    ];
+    let cp2 = [lerp(u, cp1[0], cp1[1]), lerp(u, cp1[1], cp1[2])];
+    // Coincident control points at an end leave no derivative there
+    let derivative = if (cp2[1] - cp2[0]).length_squared() > 0.0 {
+        (cp2[1] - cp2[0]) * 3.0
+    } else {
+        cp[3] - cp[0]
+    };
+    (lerp(u, cp2[0], cp2[1]), derivative)
+}
+
+// Control points of the two halves, sharing the middle one
+fn subdivide_bezier(cp: &[Vec3]) -> [Vec3; 7] {
+    [
+        cp[0],
+        (cp[0] + cp[1]) / 2.0,
+        (cp[0] + cp[1] * 2.0 + cp[2]) / 4.0,
+        (cp[0] + cp[1] * 3.0 + cp[2] * 3.0 + cp[3]) / 8.0,
+        (cp[1] + cp[2] * 2.0 + cp[3]) / 4.0,
+        (cp[2] + cp[3]) / 2.0,
+        cp[3],
+    ]
+}
+
+// Coordinates with the ray origin at zero and the ray going up z, measured in
+// the same units as the scene
+struct RayFrame {
+    origin: Vec3,
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl RayFrame {
+    fn to_local(&self, p: Vec3) -> Vec3 {
+        let d = p - self.origin;
+        Vec3::new(
+            Vec3::dot(&d, &self.x),
+            Vec3::dot(&d, &self.y),
+            Vec3::dot(&d, &self.z),
+        )
+    }
+}
+
+// The ray, the range of z along it still searched and the closest hit so far
+struct Search<'a> {
+    r: &'a Ray,
+    z_min: f64,
+    closest: f64,
+    rec: &'a mut HitRecord,
+}
+
+// Any unit vector perpendicular to the unit vector v
+fn perpendicular(v: Vec3) -> Vec3 {
+    let other = if v.x().abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    Vec3::unit_vector(Vec3::cross(&v, &other))
+}
+
+impl Curve {
+    pub fn new(cp: [Vec3; 4], width0: f64, width1: f64, id: usize) -> Self {
+        Self {
+            cp,
+            width0,
+            width1,
+            curve_type: CurveType::Cylinder,
+            id,
+        }
+    }
+
+    pub fn ribbon(cp: [Vec3; 4], width0: f64, width1: f64, n0: Vec3, n1: Vec3, id: usize) -> Self {
+        Self {
+            curve_type: CurveType::Ribbon {
+                n0: Vec3::unit_vector(n0),
+                n1: Vec3::unit_vector(n1),
+            },
+            ..Curve::new(cp, width0, width1, id)
+        }
+    }
+
+    fn width(&self, u: f64) -> f64 {
+        self.width0 * (1.0 - u) + self.width1 * u
+    }
+
+    // Ribbon normal at u, turning at a constant rate between the ends
+    fn ribbon_normal(n0: Vec3, n1: Vec3, u: f64) -> Vec3 {
+        let angle = Vec3::dot(&n0, &n1).clamp(-1.0, 1.0).acos();
+        if angle.sin() < 1e-6 {
+            return n0;
+        }
+        let s0 = ((1.0 - u) * angle).sin() / angle.sin();
+        let s1 = (u * angle).sin() / angle.sin();
+        Vec3::unit_vector(n0 * s0 + n1 * s1)
+    }
+
+    fn recursive_hit(
+        &self,
+        frame: &RayFrame,
+        cp: &[Vec3],
+        u0: f64,
+        u1: f64,
+        depth: usize,
+        search: &mut Search,
+    ) -> bool {
+        if depth > 0 {
+            let split = subdivide_bezier(cp);
+            let u = [u0, (u0 + u1) / 2.0, u1];
+            let mut hit_anything = false;
+
+            for seg in 0..2 {
+                let cps = &split[3 * seg..3 * seg + 4];
+                let half_width = 0.5 * self.width(u[seg]).max(self.width(u[seg + 1]));
+
+                // The ray is the z axis, so a piece that can not reach it in
+                // x or y, or lies outside the searched part of it, is skipped
+                let mut outside = false;
+                for a in 0..3 {
+                    let low = cps.iter().map(|p| p[a]).fold(f64::INFINITY, f64::min);
+                    let high = cps.iter().map(|p| p[a]).fold(f64::NEG_INFINITY, f64::max);
+                    let (start, end) = if a == 2 {
+                        (search.z_min, search.closest)
+                    } else {
+                        (0.0, 0.0)
+                    };
+                    if high + half_width < start || low - half_width > end {
+                        outside = true;
+                    }
+                }
+                if outside {
+                    continue;
+                }
+
+                if self.recursive_hit(frame, cps, u[seg], u[seg + 1], depth - 1, search) {
+                    hit_anything = true;
+                }
+            }
+            return hit_anything;
+        }
+
+        // The ray has to pass between the planes perpendicular to the curve
+        // at both ends of the piece
+        let edge = (cp[1].y() - cp[0].y()) * -cp[0].y() + cp[0].x() * (cp[0].x() - cp[1].x());
+        if edge < 0.0 {
+            return false;
+        }
+        let edge = (cp[2].y() - cp[3].y()) * -cp[3].y() + cp[3].x() * (cp[3].x() - cp[2].x());
+        if edge < 0.0 {
+            return false;
+        }
+
+        // Where along the piece, taken as straight, the ray passes closest
+        let segment = Vec3::new(cp[3].x() - cp[0].x(), cp[3].y() - cp[0].y(), 0.0);
+        let denom = segment.length_squared();
+        if denom == 0.0 {
+            return false;
+        }
+        let w = (-cp[0].x() * segment.x() - cp[0].y() * segment.y()) / denom;
+        let u = (u0 * (1.0 - w) + u1 * w).clamp(u0, u1);
+
+        let mut hit_width = self.width(u);
+        let ribbon_normal = match self.curve_type {
+            CurveType::Ribbon { n0, n1 } => {
+                let n = Curve::ribbon_normal(n0, n1, u);
+                hit_width *= Vec3::dot(&n, &frame.z).abs();
+                Some(n)
+            }
+            CurveType::Cylinder => None,
+        };
+
+        let (pc, _) = eval_bezier(cp, w.clamp(0.0, 1.0));
+        let distance_squared = pc.x() * pc.x() + pc.y() * pc.y();
+        if distance_squared > hit_width * hit_width * 0.25 {
+            return false;
+        }
+        if pc.z() <= search.z_min || pc.z() >= search.closest {
+            return false;
+        }
+
+        // Across the curve, seen from the ray
+        let (_, dpdu) = eval_bezier(&self.cp, u);
+        let tangent = Vec3::unit_vector(dpdu);
+        let towards_ray = -frame.z - tangent * Vec3::dot(&-frame.z, &tangent);
+        let facing = if towards_ray.length_squared() > 0.0 {
+            Vec3::unit_vector(towards_ray)
+        } else {
+            perpendicular(tangent)
+        };
+        let side = Vec3::cross(&facing, &tangent);
+
+        // From the centre line to the ray, -1 to 1 across the width
+        let offset = frame.x * -pc.x() + frame.y * -pc.y();
+        let h = if hit_width > 0.0 {
+            (Vec3::dot(&offset, &side) / (0.5 * hit_width)).clamp(-1.0, 1.0)
+        } else {
+            0.0
+        };
+
+        let outward_normal = match ribbon_normal {
+            Some(n) => n,
+            None => side * h + facing * (1.0 - h * h).max(0.0).sqrt(),
+        };
+
+        let r = search.r;
+        let t = pc.z() / r.direction().length();
+        search.closest = pc.z();
+        let rec = &mut *search.rec;
+        rec.set_t(t);
+        rec.set_p(r.at(t));
+        rec.set_face_normal(r, &outward_normal);
+        rec.set_uv((u, 0.5 + 0.5 * h));
+        rec.set_dpdu(dpdu);
+        rec.set_dpdv(side * self.width(u));
+        rec.set_id(Some(self.id));
+        true
+    }
+}
+
+impl HitAble for Curve {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let length = r.direction().length();
+        let z = r.direction() / length;
+
+        // Lined up with the chord where it can, so the curve's projection is
+        // as wide as possible
+        let chord = Vec3::cross(&z, &(self.cp[3] - self.cp[0]));
+        let up = if chord.length_squared() > 0.0 {
+            Vec3::unit_vector(chord)
+        } else {
+            perpendicular(z)
+        };
+        let x = Vec3::unit_vector(Vec3::cross(&up, &z));
+        let frame = RayFrame {
+            origin: r.origin(),
+            x,
+            y: Vec3::cross(&z, &x),
+            z,
+        };
+        let cp = [
+            frame.to_local(self.cp[0]),
+            frame.to_local(self.cp[1]),
+            frame.to_local(self.cp[2]),
+            frame.to_local(self.cp[3]),
+        ];
+
+        // Splits needed for the pieces to be within 5% of the width of straight
+        let mut l0: f64 = 0.0;
+        for i in 0..2 {
+            let d = cp[i] - cp[i + 1] * 2.0 + cp[i + 2];
+            l0 = l0.max(d.x().abs()).max(d.y().abs()).max(d.z().abs());
+        }
+        let eps = self.width0.max(self.width1) * 0.05;
+        let depth = if l0 > 0.0 && eps > 0.0 {
+            ((std::f64::consts::SQRT_2 * 6.0 * l0 / (8.0 * eps)).log2() / 2.0).clamp(0.0, 10.0)
+                as usize
+        } else {
+            0
+        };
+
+        let mut search = Search {
+            r,
+            z_min: t_min * length,
+            closest: t_max * length,
+            rec,
+        };
+        self.recursive_hit(&frame, &cp, 0.0, 1.0, depth, &mut search)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        // The curve stays inside the hull of its control points
+        let half_width = 0.5 * self.width0.max(self.width1);
+        let mut min = self.cp[0];
+        let mut max = self.cp[0];
+        for p in self.cp.iter() {
+            for a in 0..3 {
+                min[a] = min[a].min(p[a]);
+                max[a] = max[a].max(p[a]);
+            }
+        }
+        let pad = Vec3::new(half_width, half_width, half_width);
+        Some(AABB::new(min - pad, max + pad))
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit};
+
+    // Straight along x from 0 to 3
+    fn straight() -> [Vec3; 4] {
+        [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(3.0, 0.0, 0.0),
+        ]
+    }
+
+    fn back() -> Vec3 {
+        Vec3::new(0.0, 0.0, -1.0)
+    }
+
+    #[test]
+    fn test_cylinder_is_round() {
+        let curve = Curve::new(straight(), 0.5, 0.5, 2);
+
+        let rec = hit(&curve, Vec3::new(1.5, 0.0, 5.0), back() * 2.0).unwrap();
+        assert!((rec.t() - 2.5).abs() < 1e-9);
+        assert!((rec.u() - 0.5).abs() < 1e-9);
+        assert!((rec.v() - 0.5).abs() < 1e-9);
+        assert!(close(rec.normal(), Vec3::new(0.0, 0.0, 1.0), 1e-9));
+        assert!(close(
+            Vec3::unit_vector(rec.dpdu()),
+            Vec3::new(1.0, 0.0, 0.0),
+            1e-9
+        ));
+        assert_eq!(rec.id(), Some(2));
+
+        // Halfway to the edge the normal leans out by 30 degrees
+        let rec = hit(&curve, Vec3::new(1.5, 0.125, 5.0), back()).unwrap();
+        assert!((rec.normal().y().abs() - 0.5).abs() < 1e-9);
+        assert!((rec.v() - 0.5).abs() > 0.2);
+
+        assert!(hit(&curve, Vec3::new(1.5, 0.3, 5.0), back()).is_none());
+        assert!(hit(&curve, Vec3::new(3.5, 0.0, 5.0), back()).is_none());
+    }
+
+    #[test]
+    fn test_width_tapers() {
+        let curve = Curve::new(straight(), 1.0, 0.0, 0);
+
+        // 0.8 wide at u = 0.2 and 0.2 wide at u = 0.8
+        assert!(hit(&curve, Vec3::new(0.6, 0.35, 5.0), back()).is_some());
+        assert!(hit(&curve, Vec3::new(2.4, 0.35, 5.0), back()).is_none());
+        assert!(hit(&curve, Vec3::new(2.4, 0.05, 5.0), back()).is_some());
+    }
+
+    #[test]
+    fn test_follows_the_bend() {
+        let cp = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 2.0, 0.0),
+            Vec3::new(2.0, -2.0, 0.0),
+            Vec3::new(3.0, 0.0, 1.0),
+        ];
+        let curve = Curve::new(cp, 0.05, 0.02, 0);
+        let bbox = curve.bounding_box(0.0, 1.0).unwrap();
+
+        for i in 1..20 {
+            let u = i as f64 / 20.0;
+            let (p, _) = eval_bezier(&cp, u);
+            for a in 0..3 {
+                assert!(p[a] >= bbox.min()[a] && p[a] <= bbox.max()[a]);
+            }
+
+            let origin = p + Vec3::new(0.3, -0.2, 4.0);
+            let rec = hit(&curve, origin, p - origin).unwrap();
+            assert!((rec.u() - u).abs() < 0.02, "u {} hit at {}", u, rec.u());
+            assert!(close(rec.p(), p, 0.05));
+
+            // Just off the side there is nothing
+            let (_, dpdu) = eval_bezier(&cp, u);
+            let off = Vec3::unit_vector(Vec3::new(-dpdu.y(), dpdu.x(), 0.0)) * 0.1;
+            assert!(hit(&curve, p + off + Vec3::new(0.0, 0.0, 4.0), back()).is_none());
+        }
+    }
+
+    #[test]
+    fn test_ribbon_faces_its_normal() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let ribbon = Curve::ribbon(straight(), 0.5, 0.5, up, up, 0);
+
+        let rec = hit(&ribbon, Vec3::new(1.0, 5.0, 0.2), Vec3::new(0.0, -1.0, 0.0)).unwrap();
+        assert!(close(rec.normal(), up, 1e-9));
+        assert!(close(rec.p(), Vec3::new(1.0, 0.0, 0.2), 1e-9));
+
+        // Edge on it vanishes, while a cylinder would still be there
+        assert!(hit(&ribbon, Vec3::new(1.0, 0.01, 5.0), back()).is_none());
+
+        // Twisted a quarter turn, the end faces the other way
+        let side = Vec3::new(0.0, 0.0, 1.0);
+        let twisted = Curve::ribbon(straight(), 0.5, 0.5, up, side, 0);
+        let rec = hit(&twisted, Vec3::new(2.9, 0.0, 5.0), back()).unwrap();
+        assert!(rec.normal().z() > 0.95);
+    }
+}
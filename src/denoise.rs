@@ -0,0 +1,229 @@
+use crate::framebuffer::FrameBuffer;
+use crate::vec3::Vec3;
+
+// Edge avoiding à-trous wavelet filter (Dammertz et al. 2010). Each iteration
+// blurs with a 5x5 B3 spline kernel whose taps are spread 2^i pixels apart, and
+// every tap is weighted down when its luminance, normal or albedo differs from
+// the centre pixel. The colour is divided by the albedo first so that texture
+// detail is kept and only the lighting gets smoothed.
+#[derive(Copy, Clone)]
+pub struct DenoiseSettings {
+    pub iterations: u32,
+    pub sigma_color: f64,
+    pub sigma_normal: f64,
+    pub sigma_albedo: f64,
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_color: 1.0,
+            sigma_normal: 0.1,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Keeps black albedo (the background with a black sky, lights) from blowing up
+const MIN_ALBEDO: f64 = 0.01;
+
+fn demodulate(color: Vec3, albedo: Vec3) -> Vec3 {
+    Vec3::new(
+        color.x() / albedo.x().max(MIN_ALBEDO),
+        color.y() / albedo.y().max(MIN_ALBEDO),
+        color.z() / albedo.z().max(MIN_ALBEDO),
+    )
+}
+
+fn modulate(color: Vec3, albedo: Vec3) -> Vec3 {
+    Vec3::new(
+        color.x() * albedo.x().max(MIN_ALBEDO),
+        color.y() * albedo.y().max(MIN_ALBEDO),
+        color.z() * albedo.z().max(MIN_ALBEDO),
+    )
+}
+
+fn edge_weight(a: Vec3, b: Vec3, sigma: f64) -> f64 {
+    if sigma <= 0.0 {
+        return 1.0;
+    }
+    (-(a - b).length_squared() / (sigma * sigma)).exp()
+}
+
+fn luminance_weight(a: Vec3, b: Vec3, sigma: f64) -> f64 {
+    if sigma <= 0.0 {
+        return 1.0;
+    }
+    let d = a.luminance() - b.luminance();
+    (-d * d / (sigma * sigma)).exp()
+}
+
+fn a_trous_step(
+    color: &FrameBuffer,
+    albedo: &FrameBuffer,
+    normal: &FrameBuffer,
+    step: usize,
+    sigma_color: f64,
+    settings: &DenoiseSettings,
+) -> FrameBuffer {
+    let width = color.width() as isize;
+    let height = color.height() as isize;
+    let mut out = FrameBuffer::new(color.width(), color.height());
+
+    for y in 0..color.height() {
+        for x in 0..color.width() {
+            let c = color.get(x, y);
+            let n = normal.get(x, y);
+            let a = albedo.get(x, y);
+
+            let mut sum = Vec3::empty();
+            let mut weight_sum = 0.0;
+
+            for (j, ky) in KERNEL.iter().enumerate() {
+                for (i, kx) in KERNEL.iter().enumerate() {
+                    let qx = x as isize + (i as isize - 2) * step as isize;
+                    let qy = y as isize + (j as isize - 2) * step as isize;
+                    if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                        continue;
+                    }
+                    let (qx, qy) = (qx as usize, qy as usize);
+
+                    let qc = color.get(qx, qy);
+                    let weight = kx
+                        * ky
+                        * luminance_weight(c, qc, sigma_color)
+                        * edge_weight(n, normal.get(qx, qy), settings.sigma_normal)
+                        * edge_weight(a, albedo.get(qx, qy), settings.sigma_albedo);
+
+                    sum += qc * weight;
+                    weight_sum += weight;
+                }
+            }
+
+            // The centre tap always has a weight, so weight_sum is never zero
+            out.set(x, y, sum / weight_sum);
+        }
+    }
+
+    out
+}
+
+// `albedo` and `normal` are the AOVs of the same render as `color`
+pub fn denoise(
+    color: &FrameBuffer,
+    albedo: &FrameBuffer,
+    normal: &FrameBuffer,
+    settings: &DenoiseSettings,
+) -> FrameBuffer {
+    let mut irradiance = FrameBuffer::new(color.width(), color.height());
+    for y in 0..color.height() {
+        for x in 0..color.width() {
+            irradiance.set(x, y, demodulate(color.get(x, y), albedo.get(x, y)));
+        }
+    }
+
+    // The colour differences shrink with every iteration, so the colour sigma
+    // shrinks along with them
+    let mut sigma_color = settings.sigma_color;
+    for i in 0..settings.iterations {
+        irradiance = a_trous_step(&irradiance, albedo, normal, 1 << i, sigma_color, settings);
+        sigma_color *= 0.5;
+    }
+
+    let mut out = FrameBuffer::new(color.width(), color.height());
+    for y in 0..color.height() {
+        for x in 0..color.width() {
+            out.set(x, y, modulate(irradiance.get(x, y), albedo.get(x, y)));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn filled(width: usize, height: usize, f: impl Fn(usize, usize) -> Vec3) -> FrameBuffer {
+        let mut frame = FrameBuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                frame.set(x, y, f(x, y));
+            }
+        }
+        frame
+    }
+
+    fn variance(frame: &FrameBuffer, x0: usize, x1: usize) -> f64 {
+        let values: Vec<f64> = (0..frame.height())
+            .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+            .map(|(x, y)| frame.get(x, y).x())
+            .collect();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / values.len() as f64
+    }
+
+    #[test]
+    fn test_reduces_noise_on_flat_surface() {
+        let mut rng = Rng::new(3);
+        let noise: Vec<f64> = (0..16 * 16).map(|_| rng.random_double()).collect();
+        let color = filled(16, 16, |x, y| {
+            let v = 0.25 + 0.5 * noise[y * 16 + x];
+            Vec3::new(v, v, v)
+        });
+        let albedo = filled(16, 16, |_, _| Vec3::new(0.5, 0.5, 0.5));
+        let normal = filled(16, 16, |_, _| Vec3::new(0.0, 1.0, 0.0));
+
+        let result = denoise(&color, &albedo, &normal, &DenoiseSettings::default());
+
+        assert!(variance(&result, 0, 16) < 0.25 * variance(&color, 0, 16));
+    }
+
+    #[test]
+    fn test_keeps_normal_edges() {
+        let color = filled(16, 8, |x, _| {
+            if x < 8 {
+                Vec3::new(0.1, 0.1, 0.1)
+            } else {
+                Vec3::new(0.9, 0.9, 0.9)
+            }
+        });
+        let albedo = filled(16, 8, |_, _| Vec3::new(1.0, 1.0, 1.0));
+        let normal = filled(16, 8, |x, _| {
+            if x < 8 {
+                Vec3::new(1.0, 0.0, 0.0)
+            } else {
+                Vec3::new(0.0, 1.0, 0.0)
+            }
+        });
+
+        let result = denoise(&color, &albedo, &normal, &DenoiseSettings::default());
+
+        assert!((result.get(7, 4).x() - 0.1).abs() < 1e-6);
+        assert!((result.get(8, 4).x() - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_keeps_albedo_texture() {
+        let albedo = filled(8, 8, |x, y| {
+            if (x + y) % 2 == 0 {
+                Vec3::new(0.2, 0.2, 0.2)
+            } else {
+                Vec3::new(0.8, 0.8, 0.8)
+            }
+        });
+        let color = filled(8, 8, |x, y| albedo.get(x, y) * 0.5);
+        let normal = filled(8, 8, |_, _| Vec3::new(0.0, 1.0, 0.0));
+
+        let result = denoise(&color, &albedo, &normal, &DenoiseSettings::default());
+
+        for y in 0..8 {
+            for x in 0..8 {
+                assert!((result.get(x, y) - color.get(x, y)).length() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::sync::Arc;
+
+use crate::models::Sphere;
+use crate::subdivision::ControlMesh;
+use crate::texture::Texture;
+use crate::vec3::Vec3;
+
+// Where the texture's u and v come from, since control meshes carry no UVs
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UvProjection {
+    // Straight down y onto the mesh's bounding box, u along x and v along z,
+    // which suits terrain
+    Planar,
+    // Around the centre of the bounding box, like the UVs of a sphere
+    Spherical,
+}
+
+// Moves the vertices of a mesh along their normals by the brightness of a
+// texture, giving real bumps that show in silhouettes and shadows, not only
+// in shading. A vertex moves by `scale` times the brightness less `midlevel`,
+// so with a midlevel of 0.5 dark parts go in and bright parts out. Solid
+// textures like `PerlinTexture` use the vertex positions, image textures the
+// projected UVs.
+//
+// The detail that shows is limited by the tessellation rate: the mesh is
+// subdivided first, then every vertex of the finer mesh, old and new, samples
+// the texture once, so features smaller than its edges are lost.
+pub struct Displacement {
+    texture: Arc<dyn Texture>,
+    scale: f64,
+    midlevel: f64,
+    projection: UvProjection,
+}
+
+impl Displacement {
+    pub fn new(texture: Arc<dyn Texture>, scale: f64) -> Self {
+        Self {
+            texture,
+            scale,
+            midlevel: 0.0,
+            projection: UvProjection::Planar,
+        }
+    }
+
+    pub fn with_midlevel(mut self, midlevel: f64) -> Self {
+        self.midlevel = midlevel;
+        self
+    }
+
+    pub fn with_projection(mut self, projection: UvProjection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    // Subdivides `levels` times, then displaces. Normals are recomputed from
+    // the displaced faces when the result is turned into a triangle mesh.
+    pub fn apply(&self, mesh: &ControlMesh, levels: usize) -> ControlMesh {
+        let fine = mesh.subdivided(levels);
+        let normals = fine.vertex_normals();
+
+        let (min, max) = fine.positions().iter().fold(
+            (
+                Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+                Vec3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            ),
+            |(mut min, mut max), p| {
+                for a in 0..3 {
+                    min[a] = min[a].min(p[a]);
+                    max[a] = max[a].max(p[a]);
+                }
+                (min, max)
+            },
+        );
+        let centre = (min + max) / 2.0;
+        let extent = max - min;
+
+        let positions = fine
+            .positions()
+            .iter()
+            .zip(normals.iter())
+            .map(|(&p, &n)| {
+                let (u, v) = match self.projection {
+                    UvProjection::Planar => (
+                        (p.x() - min.x()) / extent.x().max(1e-12),
+                        (p.z() - min.z()) / extent.z().max(1e-12),
+                    ),
+                    UvProjection::Spherical => {
+                        let d = p - centre;
+                        if d.length_squared() > 0.0 {
+                            Sphere::get_sphere_uv(&Vec3::unit_vector(d), 0.0, 0.0)
+                        } else {
+                            (0.0, 0.0)
+                        }
+                    }
+                };
+                let height = self.texture.value(u, v, &p).luminance() - self.midlevel;
+                p + n * (height * self.scale)
+            })
+            .collect();
+
+        fine.with_positions(positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::close;
+    use crate::hit::{HitAble, HitRecord};
+    use crate::ray::Ray;
+    use crate::texture::SolidColor;
+
+    // Brightness u, along x for a planar projection
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+            Vec3::new(u, u, u)
+        }
+    }
+
+    fn octahedron() -> ControlMesh {
+        let positions = vec![
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        ];
+        let faces = vec![
+            vec![0, 2, 4],
+            vec![2, 1, 4],
+            vec![1, 3, 4],
+            vec![3, 0, 4],
+            vec![2, 0, 5],
+            vec![1, 2, 5],
+            vec![3, 1, 5],
+            vec![0, 3, 5],
+        ];
+        ControlMesh::new(positions, faces)
+    }
+
+    fn grid() -> ControlMesh {
+        let positions = (0..16)
+            .map(|i| Vec3::new((i % 4) as f64, 0.0, (i / 4) as f64))
+            .collect();
+        let mut faces = vec![];
+        for z in 0..3 {
+            for x in 0..3 {
+                let i = z * 4 + x;
+                faces.push(vec![i, i + 4, i + 5, i + 1]);
+            }
+        }
+        ControlMesh::new(positions, faces)
+    }
+
+    #[test]
+    fn test_moves_along_normals() {
+        let white = Arc::new(SolidColor::new(Vec3::new(1.0, 1.0, 1.0)));
+        let smooth = octahedron().subdivided(2);
+        let displaced = Displacement::new(white.clone(), 0.25).apply(&octahedron(), 2);
+
+        let normals = smooth.vertex_normals();
+        for ((p, n), moved) in smooth
+            .positions()
+            .iter()
+            .zip(normals.iter())
+            .zip(displaced.positions())
+        {
+            assert!(close(*moved, *p + *n * 0.25, 1e-12));
+        }
+        assert_eq!(displaced.faces(), smooth.faces());
+
+        // At the midlevel nothing moves
+        let flat = Displacement::new(white, 0.25)
+            .with_midlevel(1.0)
+            .apply(&octahedron(), 2);
+        for (a, b) in flat.positions().iter().zip(smooth.positions()) {
+            assert!(close(*a, *b, 1e-12));
+        }
+    }
+
+    #[test]
+    fn test_silhouette_grows() {
+        let white = Arc::new(SolidColor::new(Vec3::new(1.0, 1.0, 1.0)));
+        let hit_distance = |mesh: ControlMesh| {
+            let triangles = mesh.to_mesh(0);
+            let mut rec = HitRecord::empty();
+            let r = Ray::new(&Vec3::new(0.0, 0.0, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            assert!(triangles.hit(&r, 0.001, f64::INFINITY, &mut rec));
+            rec.t()
+        };
+
+        let smooth = hit_distance(octahedron().subdivided(2));
+        let displaced = hit_distance(Displacement::new(white, 0.5).apply(&octahedron(), 2));
+        assert!((smooth - displaced - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_planar_projection_spans_the_mesh() {
+        let displaced = Displacement::new(Arc::new(Ramp), 1.0).apply(&grid(), 1);
+
+        // The grid faces up, so heights follow x from 0 at one side to 1 at
+        // the other
+        for p in displaced.positions() {
+            assert!((p.y() - p.x() / 3.0).abs() < 1e-9, "{} {}", p.x(), p.y());
+        }
+    }
+
+    #[test]
+    fn test_spherical_projection() {
+        let ramp = Displacement::new(Arc::new(Ramp), 1.0).with_projection(UvProjection::Spherical);
+        let displaced = ramp.apply(&octahedron(), 1);
+        let smooth = octahedron().subdivided(1);
+
+        // Each vertex moves out by its sphere u
+        for (a, b) in displaced.positions().iter().zip(smooth.positions()) {
+            let (u, _) = Sphere::get_sphere_uv(&Vec3::unit_vector(*b), 0.0, 0.0);
+            assert!(((*a - *b).length() - u).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use std::io::{self, Read, Write};
+
+use crate::aov::{Aov, AovPixel, AovSample};
+use crate::cryptomatte::{write_mattes, MattePixel};
+use crate::filter::Filter;
+use crate::framebuffer::FrameBuffer;
+use crate::vec3::Vec3;
+use crate::world::World;
+
+// Running sums for one pixel. The weighted sums collect the filtered splats of
+// every nearby sample. The sample count and the luminance statistics only cover
+// samples taken inside this pixel; mean and M2 follow Welford's algorithm, which
+// is what the adaptive sampler uses to judge noise.
+#[derive(Copy, Clone)]
+pub struct FilmPixel {
+    weighted_sum: Vec3,
+    weight_sum: f64,
+    samples: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl FilmPixel {
+    fn empty() -> Self {
+        Self {
+            weighted_sum: Vec3::empty(),
+            weight_sum: 0.0,
+            samples: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // Filters with negative lobes can leave a weight sum of exactly zero
+    pub fn color(&self) -> Vec3 {
+        if self.weight_sum == 0.0 {
+            return Vec3::empty();
+        }
+        self.weighted_sum / self.weight_sum
+    }
+
+    fn splat(&mut self, color: Vec3, weight: f64) {
+        self.weighted_sum += color * weight;
+        self.weight_sum += weight;
+    }
+
+    fn add_statistics(&mut self, color: Vec3) {
+        self.samples += 1;
+
+        let l = color.luminance();
+        let delta = l - self.mean;
+        self.mean += delta / self.samples as f64;
+        self.m2 += delta * (l - self.mean);
+    }
+
+    pub fn merge(&mut self, other: &FilmPixel) {
+        self.weighted_sum += other.weighted_sum;
+        self.weight_sum += other.weight_sum;
+
+        if other.samples == 0 {
+            return;
+        }
+
+        let n = (self.samples + other.samples) as f64;
+        let delta = other.mean - self.mean;
+        self.m2 += other.m2 + delta * delta * self.samples as f64 * other.samples as f64 / n;
+        self.mean += delta * other.samples as f64 / n;
+        self.samples += other.samples;
+    }
+
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for c in 0..3 {
+            out.write_all(&self.weighted_sum[c].to_le_bytes())?;
+        }
+        out.write_all(&self.weight_sum.to_le_bytes())?;
+        out.write_all(&self.samples.to_le_bytes())?;
+        out.write_all(&self.mean.to_le_bytes())?;
+        out.write_all(&self.m2.to_le_bytes())
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut pixel = FilmPixel::empty();
+        for c in 0..3 {
+            pixel.weighted_sum[c] = read_f64(input)?;
+        }
+        pixel.weight_sum = read_f64(input)?;
+
+        let mut samples = [0; 4];
+        input.read_exact(&mut samples)?;
+        pixel.samples = u32::from_le_bytes(samples);
+
+        pixel.mean = read_f64(input)?;
+        pixel.m2 = read_f64(input)?;
+        Ok(pixel)
+    }
+
+    // Standard error of the mean luminance, relative to the luminance itself.
+    // The floor keeps near black pixels from demanding endless samples.
+    pub fn relative_error(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+
+        let variance = self.m2 / (self.samples - 1) as f64;
+        (variance / self.samples as f64).sqrt() / self.mean.max(0.01)
+    }
+}
+
+fn read_f64<R: Read>(input: &mut R) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+// Accumulates samples for a rectangle of the image. A tile renders into its own
+// film, padded by the filter radius so splats can land in neighbouring pixels,
+// which is merged into the full image film afterwards.
+pub struct Film {
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    pixels: Vec<FilmPixel>,
+    aovs: Option<Vec<AovPixel>>,
+    mattes: Option<Vec<MattePixel>>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Self {
+        Film::for_region(0, 0, width, height)
+    }
+
+    pub fn for_region(x0: usize, y0: usize, x1: usize, y1: usize) -> Self {
+        Self {
+            x0,
+            y0,
+            width: x1 - x0,
+            height: y1 - y0,
+            pixels: vec![FilmPixel::empty(); (x1 - x0) * (y1 - y0)],
+            aovs: None,
+            mattes: None,
+        }
+    }
+
+    // AOVs are only accumulated once enabled. Merging a film that has them
+    // enables them here as well.
+    pub fn enable_aovs(&mut self) {
+        if self.aovs.is_none() {
+            self.aovs = Some(vec![AovPixel::empty(); self.pixels.len()]);
+        }
+    }
+
+    pub fn has_aovs(&self) -> bool {
+        self.aovs.is_some()
+    }
+
+    pub fn enable_id_mattes(&mut self) {
+        if self.mattes.is_none() {
+            self.mattes = Some(vec![MattePixel::empty(); self.pixels.len()]);
+        }
+    }
+
+    pub fn has_id_mattes(&self) -> bool {
+        self.mattes.is_some()
+    }
+
+    // Region covering a tile plus every pixel its samples can splat into
+    pub fn for_tile(
+        x0: usize,
+        y0: usize,
+        x1: usize,
+        y1: usize,
+        radius: f64,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        let pad = (radius - 0.5).max(0.0).ceil() as usize;
+        Film::for_region(
+            x0.saturating_sub(pad),
+            y0.saturating_sub(pad),
+            (x1 + pad).min(width),
+            (y1 + pad).min(height),
+        )
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (y - self.y0) * self.width + (x - self.x0)
+    }
+
+    // Coordinates are absolute image coordinates, not relative to the region
+    pub fn pixel(&self, x: usize, y: usize) -> &FilmPixel {
+        &self.pixels[self.index(x, y)]
+    }
+
+    // Adds a sample taken inside pixel (x, y) at continuous film position
+    // (film_x, film_y), spreading it over every pixel the filter reaches
+    pub fn add_sample(
+        &mut self,
+        x: usize,
+        y: usize,
+        film_x: f64,
+        film_y: f64,
+        color: Vec3,
+        filter: &dyn Filter,
+    ) {
+        let index = self.index(x, y);
+        self.pixels[index].add_statistics(color);
+
+        let radius = filter.radius();
+        let x0 = ((film_x - 0.5 - radius).ceil().max(self.x0 as f64)) as usize;
+        let y0 = ((film_y - 0.5 - radius).ceil().max(self.y0 as f64)) as usize;
+        let x1 = ((film_x - 0.5 + radius).floor() as i64).min((self.x0 + self.width) as i64 - 1);
+        let y1 = ((film_y - 0.5 + radius).floor() as i64).min((self.y0 + self.height) as i64 - 1);
+
+        for py in y0 as i64..=y1 {
+            for px in x0 as i64..=x1 {
+                let weight = filter.evaluate(px as f64 + 0.5 - film_x, py as f64 + 0.5 - film_y);
+                if weight != 0.0 {
+                    let index = self.index(px as usize, py as usize);
+                    self.pixels[index].splat(color, weight);
+                }
+            }
+        }
+    }
+
+    // First hit data of a sample taken inside pixel (x, y), for the AOVs and ID
+    // mattes. Neither is filtered.
+    pub fn add_aov(&mut self, x: usize, y: usize, sample: &AovSample) {
+        let index = self.index(x, y);
+        if let Some(aovs) = self.aovs.as_mut() {
+            aovs[index].add(sample);
+        }
+        if let Some(mattes) = self.mattes.as_mut() {
+            mattes[index].add(sample);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Film) {
+        if other.has_aovs() {
+            self.enable_aovs();
+        }
+        if other.has_id_mattes() {
+            self.enable_id_mattes();
+        }
+
+        for y in 0..other.height {
+            for x in 0..other.width {
+                let index = self.index(other.x0 + x, other.y0 + y);
+                let other_index = y * other.width + x;
+                self.pixels[index].merge(&other.pixels[other_index]);
+
+                if let (Some(aovs), Some(other_aovs)) = (self.aovs.as_mut(), other.aovs.as_ref()) {
+                    aovs[index].merge(&other_aovs[other_index]);
+                }
+                if let (Some(mattes), Some(other_mattes)) =
+                    (self.mattes.as_mut(), other.mattes.as_ref())
+                {
+                    mattes[index].merge(&other_mattes[other_index]);
+                }
+            }
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Raw accumulated sums in little endian, used by checkpoints. The AOV sums
+    // and ID matte counts follow the beauty sums when the film has them.
+    pub fn write_pixels<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for pixel in self.pixels.iter() {
+            pixel.write_to(out)?;
+        }
+        if let Some(aovs) = self.aovs.as_ref() {
+            for pixel in aovs.iter() {
+                pixel.write_to(out)?;
+            }
+        }
+        if let Some(mattes) = self.mattes.as_ref() {
+            for pixel in mattes.iter() {
+                pixel.write_to(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_pixels<R: Read>(
+        input: &mut R,
+        width: usize,
+        height: usize,
+        aovs: bool,
+        mattes: bool,
+    ) -> io::Result<Film> {
+        let mut film = Film::new(width, height);
+        for pixel in film.pixels.iter_mut() {
+            *pixel = FilmPixel::read_from(input)?;
+        }
+        if aovs {
+            film.enable_aovs();
+            for pixel in film.aovs.as_mut().unwrap().iter_mut() {
+                *pixel = AovPixel::read_from(input)?;
+            }
+        }
+        if mattes {
+            film.enable_id_mattes();
+            for pixel in film.mattes.as_mut().unwrap().iter_mut() {
+                *pixel = MattePixel::read_from(input)?;
+            }
+        }
+        Ok(film)
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.samples as u64).sum()
+    }
+
+    pub fn resolve(&self) -> FrameBuffer {
+        let mut frame = FrameBuffer::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                frame.set(x, y, self.pixels[y * self.width + x].color());
+            }
+        }
+        frame
+    }
+
+    pub fn aov_image(&self, aov: Aov) -> Option<FrameBuffer> {
+        let aovs = self.aovs.as_ref()?;
+        let mut frame = FrameBuffer::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                frame.set(x, y, aovs[y * self.width + x].value(aov));
+            }
+        }
+        Some(frame)
+    }
+
+    pub fn id_matte(&self, x: usize, y: usize) -> Option<&MattePixel> {
+        let index = self.index(x, y);
+        self.mattes.as_ref().map(|mattes| &mattes[index])
+    }
+
+    // See cryptomatte.rs. Does nothing when the film has no ID mattes.
+    pub fn write_id_mattes(&self, prefix: &str, ranks: usize, world: &World) -> io::Result<()> {
+        match self.mattes.as_ref() {
+            Some(mattes) => write_mattes(prefix, mattes, self.width, ranks, world),
+            None => Ok(()),
+        }
+    }
+
+    // Greyscale image of how many samples every pixel received, where white is
+    // the highest count in the image
+    pub fn sample_count_image(&self) -> FrameBuffer {
+        let max = self
+            .pixels
+            .iter()
+            .map(|p| p.samples)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut frame = FrameBuffer::new(self.width, self.height);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let v = self.pixels[y * self.width + x].samples as f64 / max as f64;
+                frame.set(x, y, Vec3::new(v, v, v));
+            }
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{BoxFilter, TentFilter};
+
+    // Adds a sample in the centre of the pixel with the default box filter
+    fn add(film: &mut Film, x: usize, y: usize, color: Vec3) {
+        let filter = BoxFilter::new(0.5);
+        film.add_sample(x, y, x as f64 + 0.5, y as f64 + 0.5, color, &filter);
+    }
+
+    #[test]
+    fn test_constant_pixel_has_no_error() {
+        let mut film = Film::new(1, 1);
+        for _ in 0..8 {
+            add(&mut film, 0, 0, Vec3::new(0.5, 0.5, 0.5));
+        }
+        assert_eq!(film.pixel(0, 0).relative_error(), 0.0);
+        assert_eq!(film.pixel(0, 0).samples(), 8);
+    }
+
+    #[test]
+    fn test_noisy_pixel_error_shrinks() {
+        let mut film = Film::new(1, 1);
+        let mut errors = vec![];
+        for i in 0..64 {
+            let v = if i % 2 == 0 { 0.0 } else { 1.0 };
+            add(&mut film, 0, 0, Vec3::new(v, v, v));
+            if i == 7 || i == 63 {
+                errors.push(film.pixel(0, 0).relative_error());
+            }
+        }
+        assert!(errors[1] < errors[0]);
+    }
+
+    #[test]
+    fn test_merge_matches_single_accumulation() {
+        let samples = [0.1, 0.7, 0.3, 2.0, 0.0, 1.5];
+        let mut whole = Film::new(2, 2);
+        let mut tile_a = Film::for_region(1, 1, 2, 2);
+        let mut tile_b = Film::for_region(1, 1, 2, 2);
+
+        for (i, s) in samples.iter().enumerate() {
+            let color = Vec3::new(*s, *s, *s);
+            add(&mut whole, 1, 1, color);
+            if i < 2 {
+                add(&mut tile_a, 1, 1, color);
+            } else {
+                add(&mut tile_b, 1, 1, color);
+            }
+        }
+
+        let mut merged = Film::new(2, 2);
+        merged.merge(&tile_a);
+        merged.merge(&tile_b);
+
+        let a = whole.pixel(1, 1);
+        let b = merged.pixel(1, 1);
+        assert_eq!(a.samples(), b.samples());
+        assert!((a.relative_error() - b.relative_error()).abs() < 1e-12);
+        assert!((a.color().x() - b.color().x()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_sample_count_image() {
+        let mut film = Film::new(2, 1);
+        add(&mut film, 0, 0, Vec3::empty());
+        add(&mut film, 1, 0, Vec3::empty());
+        add(&mut film, 1, 0, Vec3::empty());
+        let image = film.sample_count_image();
+        assert_eq!(image.get(0, 0).x(), 0.5);
+        assert_eq!(image.get(1, 0).x(), 1.0);
+    }
+
+    #[test]
+    fn test_box_filter_averages_pixel_samples() {
+        let filter = BoxFilter::new(0.5);
+        let mut film = Film::new(3, 3);
+        film.add_sample(1, 1, 1.1, 1.9, Vec3::new(1.0, 1.0, 1.0), &filter);
+        film.add_sample(1, 1, 1.8, 1.2, Vec3::new(3.0, 3.0, 3.0), &filter);
+
+        assert_eq!(film.pixel(1, 1).color().x(), 2.0);
+        assert_eq!(film.pixel(0, 1).color().x(), 0.0);
+        assert_eq!(film.pixel(2, 1).color().x(), 0.0);
+    }
+
+    #[test]
+    fn test_wide_filter_splats_into_neighbours() {
+        let filter = TentFilter::new(1.5);
+        let mut film = Film::new(3, 3);
+        film.add_sample(1, 1, 1.5, 1.5, Vec3::new(1.0, 1.0, 1.0), &filter);
+
+        assert_eq!(film.pixel(1, 1).samples(), 1);
+        assert_eq!(film.pixel(0, 0).samples(), 0);
+        assert_eq!(film.pixel(0, 0).color().x(), 1.0);
+        assert_eq!(film.pixel(2, 1).color().x(), 1.0);
+    }
+
+    #[test]
+    fn test_tile_padding_is_clamped_to_image() {
+        let film = Film::for_tile(0, 0, 4, 4, 2.0, 6, 6);
+        assert_eq!((film.x0, film.y0, film.width, film.height), (0, 0, 6, 6));
+
+        let film = Film::for_tile(2, 2, 4, 4, 0.5, 6, 6);
+        assert_eq!((film.x0, film.y0, film.width, film.height), (2, 2, 2, 2));
+    }
+}
@@ -0,0 +1,214 @@
+use std::f64::consts::PI;
+
+// Reconstruction filter used when splatting samples into the film. The offsets
+// are measured in pixels from the centre of the pixel that receives the sample.
+// Everything here is separable, so evaluate is the product of the 1D profiles.
+pub trait Filter: Send + Sync {
+    fn radius(&self) -> f64;
+    fn evaluate_1d(&self, x: f64) -> f64;
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+}
+
+// Radius 0.5 gives the plain per pixel average the renderer used before filters
+pub struct BoxFilter {
+    radius: f64,
+}
+
+impl BoxFilter {
+    pub fn new(radius: f64) -> Self {
+        Self { radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        if x.abs() <= self.radius {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct TentFilter {
+    radius: f64,
+}
+
+impl TentFilter {
+    pub fn new(radius: f64) -> Self {
+        Self { radius }
+    }
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0)
+    }
+}
+
+// The gaussian is shifted down so it reaches zero at the radius instead of
+// being cut off with a step
+pub struct GaussianFilter {
+    radius: f64,
+    sigma: f64,
+    edge: f64,
+}
+
+fn gaussian(x: f64, sigma: f64) -> f64 {
+    (-x * x / (2.0 * sigma * sigma)).exp() / (2.0 * PI * sigma * sigma).sqrt()
+}
+
+impl GaussianFilter {
+    pub fn new(radius: f64, sigma: f64) -> Self {
+        Self {
+            radius,
+            sigma,
+            edge: gaussian(radius, sigma),
+        }
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        (gaussian(x, self.sigma) - self.edge).max(0.0)
+    }
+}
+
+// Mitchell-Netravali cubic. B = C = 1/3 is the authors' recommendation.
+pub struct MitchellFilter {
+    radius: f64,
+    b: f64,
+    c: f64,
+}
+
+impl MitchellFilter {
+    pub fn new(radius: f64, b: f64, c: f64) -> Self {
+        Self { radius, b, c }
+    }
+
+    // The cubic is defined over [-2, 2]
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = x.abs();
+        let (b, c) = (self.b, self.c);
+
+        if x <= 1.0 {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                + (6.0 - 2.0 * b))
+                / 6.0
+        } else if x <= 2.0 {
+            ((-b - 6.0 * c) * x * x * x
+                + (6.0 * b + 30.0 * c) * x * x
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c))
+                / 6.0
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        self.mitchell(2.0 * x / self.radius)
+    }
+}
+
+// Sinc windowed by a wider sinc. `tau` is the number of sinc lobes inside the radius.
+pub struct LanczosFilter {
+    radius: f64,
+    tau: f64,
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+impl LanczosFilter {
+    pub fn new(radius: f64, tau: f64) -> Self {
+        Self { radius, tau }
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.tau)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Vec<Box<dyn Filter>> {
+        vec![
+            Box::new(BoxFilter::new(0.5)),
+            Box::new(TentFilter::new(1.0)),
+            Box::new(GaussianFilter::new(1.5, 0.5)),
+            Box::new(MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0)),
+            Box::new(LanczosFilter::new(2.0, 2.0)),
+        ]
+    }
+
+    #[test]
+    fn test_peak_at_centre() {
+        for filter in filters().iter() {
+            let centre = filter.evaluate(0.0, 0.0);
+            assert!(centre > 0.0);
+            assert!(centre >= filter.evaluate(0.25, 0.1));
+        }
+    }
+
+    #[test]
+    fn test_zero_outside_radius() {
+        for filter in filters().iter() {
+            let r = filter.radius() + 0.01;
+            assert_eq!(filter.evaluate(r, 0.0), 0.0);
+            assert_eq!(filter.evaluate(0.0, -r), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_gaussian_reaches_zero_at_radius() {
+        let filter = GaussianFilter::new(1.5, 0.5);
+        assert!(filter.evaluate_1d(1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_mitchell_has_negative_lobes() {
+        let filter = MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0);
+        assert!(filter.evaluate_1d(1.5) < 0.0);
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::denoise::{self, DenoiseSettings};
+use crate::tonemap::ToneMap;
+use crate::vec3::Vec3;
+
+// Linear radiance for every pixel of the image. Rows are stored top to bottom,
+// so (0, 0) is the top left corner of the written image.
+#[derive(Clone)]
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Vec3::empty(); width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Vec3) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    // Post process stage. The linear buffer is left untouched so it can still be
+    // written out as HDR afterwards.
+    pub fn tone_map(&self, operator: ToneMap, exposure: f64) -> FrameBuffer {
+        FrameBuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self
+                .pixels
+                .iter()
+                .map(|c| operator.apply(*c, exposure))
+                .collect(),
+        }
+    }
+
+    // Feature guided denoise of the linear image, see denoise.rs. `albedo` and
+    // `normal` are the matching AOVs of the render.
+    pub fn denoise(
+        &self,
+        albedo: &FrameBuffer,
+        normal: &FrameBuffer,
+        settings: &DenoiseSettings,
+    ) -> FrameBuffer {
+        denoise::denoise(self, albedo, normal, settings)
+    }
+
+    // Writes an 8 bit PPM with a gamma of 2. Values are expected to be tone mapped
+    // already, anything outside of [0, 1] is clamped.
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "P3\n{} {}\n255", self.width, self.height)?;
+
+        for color in self.pixels.iter() {
+            let r = color.x().max(0.0).sqrt();
+            let g = color.y().max(0.0).sqrt();
+            let b = color.z().max(0.0).sqrt();
+            writeln!(
+                out,
+                "{} {} {}",
+                (256.0 * r.clamp(0.0, 0.999)) as i32,
+                (256.0 * g.clamp(0.0, 0.999)) as i32,
+                (256.0 * b.clamp(0.0, 0.999)) as i32,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_ppm_file(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_ppm(&mut out)?;
+        out.flush()
+    }
+
+    // Writes the raw linear values as a Portable Float Map. PFM stores the bottom
+    // row first and a negative scale marks the data as little endian.
+    pub fn write_pfm(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+
+        for row in self.pixels.chunks(self.width).rev() {
+            for color in row {
+                for c in 0..3 {
+                    out.write_all(&(color[c] as f32).to_le_bytes())?;
+                }
+            }
+        }
+
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_get() {
+        let mut buffer = FrameBuffer::new(4, 2);
+        buffer.set(3, 1, Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(buffer.get(3, 1).z(), 3.0);
+        assert_eq!(buffer.pixels()[7].x(), 1.0);
+    }
+
+    #[test]
+    fn test_tone_map_keeps_linear_buffer() {
+        let mut buffer = FrameBuffer::new(1, 1);
+        buffer.set(0, 0, Vec3::new(3.0, 3.0, 3.0));
+        let mapped = buffer.tone_map(ToneMap::Reinhard, 0.0);
+        assert_eq!(mapped.get(0, 0).x(), 0.75);
+        assert_eq!(buffer.get(0, 0).x(), 3.0);
+    }
+
+    #[test]
+    fn test_write_ppm() {
+        let mut buffer = FrameBuffer::new(2, 1);
+        buffer.set(0, 0, Vec3::new(0.25, 1.0, 5.0));
+        let mut out: Vec<u8> = vec![];
+        buffer.write_ppm(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "P3\n2 1\n255\n128 255 255\n0 0 0\n"
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use std::fs::File;
+use std::io;
+
+use crate::aabb::AABB;
+use crate::hit::{HitAble, HitRecord};
+use crate::mesh::triangle_intersect;
+use crate::ray::Ray;
+use crate::texture::PerlinTexture;
+use crate::vec3::Vec3;
+
+// Keeps flat stretches of terrain from having boxes with no thickness
+const PAD: f64 = 1e-6;
+
+// Terrain over a regular grid of heights. Sample (i, k) sits at x = i and
+// z = k grid steps from `corner`, at a height of its value, between 0 and 1,
+// times `size.y()`. Each grid cell is two triangles with normals interpolated
+// from the corners. UVs run from 0 to 1 across the extent, u along x and v
+// along z, so an image texture of the same extent drapes over it.
+//
+// Rays walk a quadtree of the cells that knows the lowest and highest point
+// under every node, so only the few cells near the ray get tested.
+pub struct Heightfield {
+    nx: usize,
+    nz: usize,
+    heights: Vec<f64>,
+    normals: Vec<Vec3>,
+    corner: Vec3,
+    size: Vec3,
+    // Level 0 has a (min, max) height per cell, every level above it one per
+    // two by two block of the level below, up to a single root
+    levels: Vec<Vec<(f64, f64)>>,
+    id: usize,
+}
+
+fn level_dims(cells_x: usize, cells_z: usize, level: usize) -> (usize, usize) {
+    let shrink = |n: usize| (0..level).fold(n, |n, _| n.div_ceil(2));
+    (shrink(cells_x), shrink(cells_z))
+}
+
+// What a walk down the quadtree carries along: the ray, the range of t still
+// searched and the closest hit so far
+struct Search<'a> {
+    r: &'a Ray,
+    t_min: f64,
+    closest: f64,
+    rec: &'a mut HitRecord,
+}
+
+impl Heightfield {
+    // `values` are row by row along x, nx per row, nz rows, each between 0 and 1
+    pub fn from_values(
+        nx: usize,
+        nz: usize,
+        values: &[f64],
+        corner: Vec3,
+        size: Vec3,
+        id: usize,
+    ) -> Self {
+        assert!(
+            nx >= 2 && nz >= 2,
+            "a heightfield needs at least 2 by 2 samples"
+        );
+        assert_eq!(values.len(), nx * nz);
+
+        let heights: Vec<f64> = values.iter().map(|h| corner.y() + h * size.y()).collect();
+        let (cells_x, cells_z) = (nx - 1, nz - 1);
+
+        let mut cells = Vec::with_capacity(cells_x * cells_z);
+        for k in 0..cells_z {
+            for i in 0..cells_x {
+                let corners = [
+                    heights[k * nx + i],
+                    heights[k * nx + i + 1],
+                    heights[(k + 1) * nx + i],
+                    heights[(k + 1) * nx + i + 1],
+                ];
+                let low = corners.iter().cloned().fold(f64::INFINITY, f64::min);
+                let high = corners.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                cells.push((low, high));
+            }
+        }
+
+        let mut levels = vec![cells];
+        let mut level = 0;
+        while level_dims(cells_x, cells_z, level) != (1, 1) {
+            let (w, d) = level_dims(cells_x, cells_z, level);
+            let (pw, pd) = level_dims(cells_x, cells_z, level + 1);
+            let below = &levels[level];
+            let mut parents = vec![(f64::INFINITY, f64::NEG_INFINITY); pw * pd];
+            for k in 0..d {
+                for i in 0..w {
+                    let (low, high) = below[k * w + i];
+                    let parent = &mut parents[(k / 2) * pw + i / 2];
+                    parent.0 = parent.0.min(low);
+                    parent.1 = parent.1.max(high);
+                }
+            }
+            levels.push(parents);
+            level += 1;
+        }
+
+        let mut field = Self {
+            nx,
+            nz,
+            heights,
+            normals: vec![],
+            corner,
+            size,
+            levels,
+            id,
+        };
+        field.normals = (0..nx * nz)
+            .map(|n| field.vertex_normal(n % nx, n / nx))
+            .collect();
+        field
+    }
+
+    // Uses the first channel of a PNG, 8 or 16 bit. The top row of the image
+    // is the far edge, at the largest z, the way `ImageTexture` maps v.
+    pub fn load(path: &str, corner: Vec3, size: Vec3, id: usize) -> io::Result<Self> {
+        let decoder = png::Decoder::new(File::open(path)?);
+        let (info, mut reader) = decoder
+            .read_info()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut buf = vec![0; info.buffer_size()];
+        reader
+            .next_frame(&mut buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let width = info.width as usize;
+        let height = info.height as usize;
+        if width < 2 || height < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "heightfield images need at least 2 by 2 pixels",
+            ));
+        }
+        let bytes_per_pixel = info.line_size / width;
+        let sixteen_bit = info.bit_depth == png::BitDepth::Sixteen;
+
+        let mut values = vec![0.0; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let at = y * info.line_size + x * bytes_per_pixel;
+                let value = if sixteen_bit {
+                    u16::from_be_bytes([buf[at], buf[at + 1]]) as f64 / 65535.0
+                } else {
+                    buf[at] as f64 / 255.0
+                };
+                values[(height - 1 - y) * width + x] = value;
+            }
+        }
+
+        Ok(Heightfield::from_values(
+            width, height, &values, corner, size, id,
+        ))
+    }
+
+    // Turbulence of the noise over the square [0, frequency]^2, scaled so the
+    // highest sample is at the full height
+    pub fn from_perlin(
+        noise: &PerlinTexture,
+        nx: usize,
+        nz: usize,
+        frequency: f64,
+        corner: Vec3,
+        size: Vec3,
+        id: usize,
+    ) -> Self {
+        let mut values: Vec<f64> = (0..nx * nz)
+            .map(|n| {
+                let x = (n % nx) as f64 / (nx - 1) as f64;
+                let z = (n / nx) as f64 / (nz - 1) as f64;
+                noise.turb(&(Vec3::new(x, 0.0, z) * frequency))
+            })
+            .collect();
+
+        let highest = values.iter().cloned().fold(0.0, f64::max);
+        if highest > 0.0 {
+            for value in values.iter_mut() {
+                *value /= highest;
+            }
+        }
+
+        Heightfield::from_values(nx, nz, &values, corner, size, id)
+    }
+
+    fn step_x(&self) -> f64 {
+        self.size.x() / (self.nx - 1) as f64
+    }
+
+    fn step_z(&self) -> f64 {
+        self.size.z() / (self.nz - 1) as f64
+    }
+
+    fn vertex(&self, i: usize, k: usize) -> Vec3 {
+        Vec3::new(
+            self.corner.x() + i as f64 * self.step_x(),
+            self.heights[k * self.nx + i],
+            self.corner.z() + k as f64 * self.step_z(),
+        )
+    }
+
+    // From the slopes to the neighbours on either side, one sided at the edges
+    fn vertex_normal(&self, i: usize, k: usize) -> Vec3 {
+        let height = |i: usize, k: usize| self.heights[k * self.nx + i];
+        let (i0, i1) = (i.saturating_sub(1), (i + 1).min(self.nx - 1));
+        let (k0, k1) = (k.saturating_sub(1), (k + 1).min(self.nz - 1));
+
+        let dx = (height(i1, k) - height(i0, k)) / ((i1 - i0) as f64 * self.step_x());
+        let dz = (height(i, k1) - height(i, k0)) / ((k1 - k0) as f64 * self.step_z());
+        Vec3::unit_vector(Vec3::new(-dx, 1.0, -dz))
+    }
+
+    fn node_hit(&self, level: usize, i: usize, k: usize, search: &mut Search) -> bool {
+        let (cells_x, cells_z) = (self.nx - 1, self.nz - 1);
+        let (w, _) = level_dims(cells_x, cells_z, level);
+        let (low, high) = self.levels[level][k * w + i];
+
+        // Cells under the node
+        let span = 1 << level;
+        let (i0, k0) = (i * span, k * span);
+        let (i1, k1) = ((i0 + span).min(cells_x), (k0 + span).min(cells_z));
+        let min = Vec3::new(
+            self.corner.x() + i0 as f64 * self.step_x(),
+            low,
+            self.corner.z() + k0 as f64 * self.step_z(),
+        );
+        let max = Vec3::new(
+            self.corner.x() + i1 as f64 * self.step_x(),
+            high,
+            self.corner.z() + k1 as f64 * self.step_z(),
+        );
+        let pad = Vec3::new(PAD, PAD, PAD);
+        if AABB::new(min - pad, max + pad)
+            .clip(search.r, search.t_min, search.closest)
+            .is_none()
+        {
+            return false;
+        }
+
+        if level == 0 {
+            return self.cell_hit(i, k, search);
+        }
+
+        // Children nearer the ray origin first, so farther ones get culled
+        let (cw, cd) = level_dims(cells_x, cells_z, level - 1);
+        let flip_x = search.r.direction().x() < 0.0;
+        let flip_z = search.r.direction().z() < 0.0;
+        let mut hit_anything = false;
+        for n in 0..4 {
+            let ci = 2 * i + ((n & 1 == 1) != flip_x) as usize;
+            let ck = 2 * k + ((n & 2 == 2) != flip_z) as usize;
+            if ci < cw && ck < cd && self.node_hit(level - 1, ci, ck, search) {
+                hit_anything = true;
+            }
+        }
+        hit_anything
+    }
+
+    fn cell_hit(&self, i: usize, k: usize, search: &mut Search) -> bool {
+        let r = search.r;
+        let corners = [(i, k), (i + 1, k), (i + 1, k + 1), (i, k + 1)];
+        let mut hit_anything = false;
+
+        for triangle in [[0, 1, 2], [0, 2, 3]].iter() {
+            let [a, b, c] = [
+                corners[triangle[0]],
+                corners[triangle[1]],
+                corners[triangle[2]],
+            ];
+            let (p0, p1, p2) = (
+                self.vertex(a.0, a.1),
+                self.vertex(b.0, b.1),
+                self.vertex(c.0, c.1),
+            );
+            let (t, beta, gamma) =
+                match triangle_intersect(r, p0, p1, p2, search.t_min, search.closest) {
+                    Some(found) => found,
+                    None => continue,
+                };
+
+            let normal = |(i, k): (usize, usize)| self.normals[k * self.nx + i];
+            let alpha = 1.0 - beta - gamma;
+            let shading =
+                Vec3::unit_vector(normal(a) * alpha + normal(b) * beta + normal(c) * gamma);
+
+            let p = r.at(t);
+            search.closest = t;
+            let rec = &mut *search.rec;
+            rec.set_t(t);
+            rec.set_p(p);
+            rec.set_face_normal(r, &shading);
+            rec.set_uv((
+                (p.x() - self.corner.x()) / self.size.x(),
+                (p.z() - self.corner.z()) / self.size.z(),
+            ));
+            // u and v follow x and z, so the tangents climb with the triangle
+            let flat = Vec3::cross(&(p1 - p0), &(p2 - p0));
+            let slope =
+                |dx: f64, dz: f64| Vec3::new(dx, -(flat.x() * dx + flat.z() * dz) / flat.y(), dz);
+            rec.set_dpdu(slope(self.size.x(), 0.0));
+            rec.set_dpdv(slope(0.0, self.size.z()));
+            rec.set_id(Some(self.id));
+            hit_anything = true;
+        }
+
+        hit_anything
+    }
+}
+
+impl HitAble for Heightfield {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let mut search = Search {
+            r,
+            t_min,
+            closest: t_max,
+            rec,
+        };
+        self.node_hit(self.levels.len() - 1, 0, 0, &mut search)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        let (low, high) = self.levels[self.levels.len() - 1][0];
+        Some(AABB::new(
+            Vec3::new(self.corner.x(), low - PAD, self.corner.z()),
+            Vec3::new(
+                self.corner.x() + self.size.x(),
+                high + PAD,
+                self.corner.z() + self.size.z(),
+            ),
+        ))
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit};
+    use crate::rng::Rng;
+
+    fn down() -> Vec3 {
+        Vec3::new(0.0, -1.0, 0.0)
+    }
+
+    // Height x / 10 over [0, 10]^2, sampled 11 by 11
+    fn ramp() -> Heightfield {
+        let values: Vec<f64> = (0..121).map(|n| (n % 11) as f64 / 10.0).collect();
+        Heightfield::from_values(
+            11,
+            11,
+            &values,
+            Vec3::empty(),
+            Vec3::new(10.0, 1.0, 10.0),
+            4,
+        )
+    }
+
+    #[test]
+    fn test_ramp_hits_and_uvs() {
+        let field = ramp();
+
+        let rec = hit(&field, Vec3::new(2.5, 5.0, 7.5), down()).unwrap();
+        assert!(close(rec.p(), Vec3::new(2.5, 0.25, 7.5), 1e-9));
+        assert!((rec.u() - 0.25).abs() < 1e-9);
+        assert!((rec.v() - 0.75).abs() < 1e-9);
+        assert!(rec.front_face());
+        assert_eq!(rec.id(), Some(4));
+        // Away from the edges the normal is the plane's
+        let slope = Vec3::unit_vector(Vec3::new(-0.1, 1.0, 0.0));
+        assert!(close(rec.normal(), slope, 1e-9));
+
+        assert!(hit(&field, Vec3::new(11.0, 5.0, 5.0), down()).is_none());
+        // Grazing along the ramp from the low side and below it
+        assert!(hit(&field, Vec3::new(-1.0, -0.5, 5.0), Vec3::new(1.0, 0.0, 0.0)).is_none());
+        let rec = hit(&field, Vec3::new(-1.0, 0.5, 5.0), Vec3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!(close(rec.p(), Vec3::new(5.0, 0.5, 5.0), 1e-9));
+
+        let bbox = field.bounding_box(0.0, 1.0).unwrap();
+        assert!(close(*bbox.max(), Vec3::new(10.0, 1.0, 10.0), 1e-5));
+    }
+
+    #[test]
+    fn test_normals_are_smooth() {
+        // A single bump in the middle of a flat 3 by 3 grid
+        let mut values = vec![0.0; 9];
+        values[4] = 1.0;
+        let field =
+            Heightfield::from_values(3, 3, &values, Vec3::empty(), Vec3::new(2.0, 1.0, 2.0), 0);
+
+        let top = hit(&field, Vec3::new(1.0, 5.0, 1.0), down()).unwrap();
+        assert!(close(top.normal(), Vec3::new(0.0, 1.0, 0.0), 1e-9));
+
+        // Halfway down the slope the normal is halfway between its ends
+        let side = hit(&field, Vec3::new(1.5, 5.0, 1.0), down()).unwrap();
+        assert!(side.normal().x() > 0.0);
+        assert!(side.normal().x() < Vec3::unit_vector(Vec3::new(1.0, 1.0, 0.0)).x());
+    }
+
+    #[test]
+    fn test_matches_every_triangle() {
+        let mut rng = Rng::new(7);
+        let noise = PerlinTexture::new(1.0, &mut rng);
+        let field = Heightfield::from_perlin(
+            &noise,
+            33,
+            17,
+            4.0,
+            Vec3::new(-8.0, -1.0, -4.0),
+            Vec3::new(16.0, 3.0, 8.0),
+            0,
+        );
+
+        let flat = field.levels[0].iter().all(|(low, high)| low == high);
+        assert!(!flat);
+        assert_eq!(field.levels.last().unwrap().len(), 1);
+
+        for n in 0..200 {
+            let origin = Vec3::new(
+                rng.random_double_from_values(-10.0, 10.0),
+                rng.random_double_from_values(3.0, 6.0),
+                rng.random_double_from_values(-6.0, 6.0),
+            );
+            let target = Vec3::new(
+                rng.random_double_from_values(-8.0, 8.0),
+                rng.random_double_from_values(-1.0, 2.0),
+                rng.random_double_from_values(-4.0, 4.0),
+            );
+            let r = Ray::new(&origin, &(target - origin), 0.0);
+
+            // Test every cell without the quadtree
+            let mut expected = HitRecord::empty();
+            let mut search = Search {
+                r: &r,
+                t_min: 0.001,
+                closest: f64::INFINITY,
+                rec: &mut expected,
+            };
+            for k in 0..field.nz - 1 {
+                for i in 0..field.nx - 1 {
+                    field.cell_hit(i, k, &mut search);
+                }
+            }
+            let closest = search.closest;
+
+            let mut rec = HitRecord::empty();
+            let found = field.hit(&r, 0.001, f64::INFINITY, &mut rec);
+            assert_eq!(found, closest.is_finite(), "ray {}", n);
+            if found {
+                assert!((rec.t() - expected.t()).abs() < 1e-9, "ray {}", n);
+            }
+        }
+    }
+}
@@ -2,6 +2,7 @@ use crate::aabb::AABB;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 
+#[derive(Clone)]
 pub struct HitRecord {
     p: Vec3,
     normal: Vec3,
@@ -10,6 +11,10 @@ pub struct HitRecord {
     v: f64,
     front_face: bool,
     id: Option<usize>,
+    // Derivatives of the position along u and v, for materials that need a
+    // tangent frame. Zero where a primitive has none.
+    dpdu: Vec3,
+    dpdv: Vec3,
 }
 
 impl HitRecord {
@@ -30,6 +35,8 @@ impl HitRecord {
             v,
             front_face: front_face,
             id,
+            dpdu: Vec3::empty(),
+            dpdv: Vec3::empty(),
         }
     }
 
@@ -42,6 +49,8 @@ impl HitRecord {
             v: 0.0,
             front_face: true,
             id: Option::None,
+            dpdu: Vec3::empty(),
+            dpdv: Vec3::empty(),
         }
     }
 
@@ -117,14 +126,53 @@ impl HitRecord {
     pub fn set_id(&mut self, new_id: Option<usize>) {
         self.id = new_id;
     }
+
+    pub fn dpdu(&self) -> Vec3 {
+        self.dpdu
+    }
+
+    pub fn set_dpdu(&mut self, v: Vec3) {
+        self.dpdu = v;
+    }
+
+    pub fn dpdv(&self) -> Vec3 {
+        self.dpdv
+    }
+
+    pub fn set_dpdv(&mut self, v: Vec3) {
+        self.dpdv = v;
+    }
 }
 
-pub trait HitAble {
+pub trait HitAble: Send + Sync {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool;
     fn bounding_box(&self, time0: f64, time1: f64) -> Option<AABB>;
     fn id(&self) -> Option<usize>;
 }
 
+// Helpers shared by the tests of other modules
+#[cfg(test)]
+pub mod test_util {
+    use super::*;
+
+    pub fn close(a: Vec3, b: Vec3, tolerance: f64) -> bool {
+        (a - b).length() < tolerance
+    }
+
+    pub fn hit_ray(object: &dyn HitAble, r: &Ray) -> Option<HitRecord> {
+        let mut rec = HitRecord::empty();
+        if object.hit(r, 0.001, f64::INFINITY, &mut rec) {
+            Some(rec)
+        } else {
+            None
+        }
+    }
+
+    pub fn hit(object: &dyn HitAble, origin: Vec3, direction: Vec3) -> Option<HitRecord> {
+        hit_ray(object, &Ray::new(&origin, &direction, 0.0))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -0,0 +1,577 @@
+use std::fs;
+use std::io;
+
+use crate::camera::{basis, CameraModel};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::shutter::Shutter;
+use crate::vec3::Vec3;
+
+// One surface of a lens prescription. Lengths are in millimetres. A radius of
+// 0 marks the aperture stop, `ior` is the index of the glass (or air) between
+// this surface and the next one towards the film.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LensElement {
+    pub radius: f64,
+    pub thickness: f64,
+    pub ior: f64,
+    pub aperture_radius: f64,
+}
+
+impl LensElement {
+    fn is_stop(&self) -> bool {
+        self.radius == 0.0
+    }
+}
+
+// Reads a table with one surface per line, front to back: curvature radius,
+// thickness, index of refraction and aperture diameter. Blank lines and
+// anything after a # are ignored.
+pub fn parse_lens_table(text: &str) -> Result<Vec<LensElement>, String> {
+    let mut elements = vec![];
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let values: Result<Vec<f64>, _> = line.split_whitespace().map(|v| v.parse()).collect();
+        match values {
+            Ok(values) if values.len() == 4 => elements.push(LensElement {
+                radius: values[0],
+                thickness: values[1],
+                ior: values[2],
+                aperture_radius: values[3] / 2.0,
+            }),
+            _ => return Err(format!("line {}: expected four numbers", number + 1)),
+        }
+    }
+
+    if elements.is_empty() {
+        return Err("the lens has no elements".to_string());
+    }
+    Ok(elements)
+}
+
+// Snell's law for a unit `wi` pointing away from the surface and a normal on
+// the same side. None on total internal reflection.
+fn refract(wi: Vec3, n: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = Vec3::dot(&n, &wi);
+    let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+    let sin2_t = eta * eta * sin2_i;
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wi * eta + n * (eta * cos_i - cos_t))
+}
+
+// Hit of a spherical surface centred on the axis at `center_z`, with the normal
+// turned towards the incoming ray
+fn intersect_spherical(radius: f64, center_z: f64, o: Vec3, d: Vec3) -> Option<(f64, Vec3)> {
+    let o = o - Vec3::new(0.0, 0.0, center_z);
+    let a = d.length_squared();
+    let b = 2.0 * Vec3::dot(&d, &o);
+    let c = o.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let root = discriminant.sqrt();
+    let q = if b < 0.0 {
+        -0.5 * (b - root)
+    } else {
+        -0.5 * (b + root)
+    };
+    let (t0, t1) = (q / a, c / q);
+    let (near, far) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+
+    // Which of the two hits is the lens surface depends on the way the ray
+    // travels and on the way the surface bends
+    let t = if (d.z() > 0.0) ^ (radius < 0.0) {
+        near
+    } else {
+        far
+    };
+    if t < 0.0 {
+        return None;
+    }
+
+    let n = Vec3::unit_vector(o + d * t);
+    let n = if Vec3::dot(&n, &-d) < 0.0 { -n } else { n };
+    Some((t, n))
+}
+
+// Bounds on the plane of the rear element, per film radius, of where rays that
+// make it through the lens leave it
+struct PupilBounds {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl PupilBounds {
+    fn area(&self) -> f64 {
+        (self.max_x - self.min_x).max(0.0) * (self.max_y - self.min_y).max(0.0)
+    }
+}
+
+const PUPIL_INTERVALS: usize = 64;
+const PUPIL_GRID: usize = 64;
+
+// How a lens is used. `film_height` is in millimetres, `focus_dist` in scene
+// units measured from the film, which sits at lookfrom.
+#[derive(Copy, Clone)]
+pub struct LensSettings {
+    pub film_height: f64,
+    pub aspect_ratio: f64,
+    pub focus_dist: f64,
+    pub scene_scale: f64,
+}
+
+// Camera that traces rays through a real lens prescription, so it gets the
+// distortion, vignetting and focus breathing of that lens. Lens space has the
+// film at z = 0 and the lens towards -z, with x and y along the camera's u and
+// v, so -z is the view direction. Lens space is in millimetres, `scene_scale`
+// is the number of scene units per millimetre.
+pub struct LensCamera {
+    elements: Vec<LensElement>,
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    scene_scale: f64,
+    film_width: f64,
+    film_height: f64,
+    pupil_bounds: Vec<PupilBounds>,
+    // Area of the rear plane that lets light through to the film centre
+    centre_area: f64,
+    shutter: Shutter,
+}
+
+impl LensCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        elements: Vec<LensElement>,
+        settings: &LensSettings,
+        time0: f64,
+        time1: f64,
+    ) -> Result<Self, String> {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        let mut camera = Self {
+            elements,
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            scene_scale: settings.scene_scale,
+            film_width: settings.film_height * settings.aspect_ratio,
+            film_height: settings.film_height,
+            pupil_bounds: vec![],
+            centre_area: 0.0,
+            shutter: Shutter::new(time0, time1),
+        };
+
+        camera.focus(settings.focus_dist / settings.scene_scale)?;
+        camera.compute_pupil_bounds()?;
+        Ok(camera)
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    pub fn load(
+        path: &str,
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        settings: &LensSettings,
+        time0: f64,
+        time1: f64,
+    ) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let elements = parse_lens_table(&fs::read_to_string(path)?).map_err(invalid)?;
+        LensCamera::new(lookfrom, lookat, vup, elements, settings, time0, time1).map_err(invalid)
+    }
+
+    fn front_z(&self) -> f64 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear(&self) -> &LensElement {
+        self.elements.last().unwrap()
+    }
+
+    fn trace_from_film(&self, mut o: Vec3, mut d: Vec3) -> Option<(Vec3, Vec3)> {
+        let mut z = 0.0;
+
+        for i in (0..self.elements.len()).rev() {
+            let element = &self.elements[i];
+            z -= element.thickness;
+
+            let (t, n) = if element.is_stop() {
+                if d.z() >= 0.0 {
+                    return None;
+                }
+                ((z - o.z()) / d.z(), Vec3::empty())
+            } else {
+                intersect_spherical(element.radius, z + element.radius, o, d)?
+            };
+
+            o += d * t;
+            if o.x() * o.x() + o.y() * o.y() > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+
+            if !element.is_stop() {
+                let eta_i = element.ior;
+                let eta_t = if i > 0 && self.elements[i - 1].ior != 0.0 {
+                    self.elements[i - 1].ior
+                } else {
+                    1.0
+                };
+                d = refract(Vec3::unit_vector(-d), n, eta_i / eta_t)?;
+            }
+        }
+
+        Some((o, d))
+    }
+
+    fn trace_from_scene(&self, mut o: Vec3, mut d: Vec3) -> Option<(Vec3, Vec3)> {
+        let mut z = -self.front_z();
+
+        for i in 0..self.elements.len() {
+            let element = &self.elements[i];
+
+            let (t, n) = if element.is_stop() {
+                if d.z() <= 0.0 {
+                    return None;
+                }
+                ((z - o.z()) / d.z(), Vec3::empty())
+            } else {
+                intersect_spherical(element.radius, z + element.radius, o, d)?
+            };
+
+            o += d * t;
+            if o.x() * o.x() + o.y() * o.y() > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+
+            if !element.is_stop() {
+                let eta_i = if i == 0 || self.elements[i - 1].ior == 0.0 {
+                    1.0
+                } else {
+                    self.elements[i - 1].ior
+                };
+                let eta_t = if element.ior != 0.0 { element.ior } else { 1.0 };
+                d = refract(Vec3::unit_vector(-d), n, eta_i / eta_t)?;
+            }
+            z += element.thickness;
+        }
+
+        Some((o, d))
+    }
+
+    // Where light from a point `distance` millimetres in front of the film, or
+    // from infinitely far away, comes to a focus behind the lens. Positive is
+    // behind the film.
+    fn image_z(&self, distance: f64) -> Option<f64> {
+        let h = 0.05 * self.elements[0].aperture_radius;
+        let front = -self.front_z();
+        let (o, d) = if distance.is_finite() {
+            let o = Vec3::new(0.0, 0.0, -distance);
+            (o, Vec3::new(h, 0.0, front) - o)
+        } else {
+            (Vec3::new(h, 0.0, front - 1.0), Vec3::new(0.0, 0.0, 1.0))
+        };
+
+        let (o, d) = self.trace_from_scene(o, d)?;
+        if d.x() == 0.0 {
+            return None;
+        }
+        Some(o.z() - o.x() / d.x() * d.z())
+    }
+
+    // Moves the lens away from the film until the focus distance is sharp, by
+    // adjusting the gap between the rear element and the film. The field of
+    // view changes with it, which is the focus breathing of the real lens.
+    fn focus(&mut self, distance: f64) -> Result<(), String> {
+        let error = || "the lens can not focus at that distance".to_string();
+        let last = self.elements.len() - 1;
+
+        let mut a = self.elements[last].thickness;
+        let mut ga = self.image_z(distance).ok_or_else(error)?;
+        let mut b = a + ga;
+
+        // Secant steps. Moving the lens shifts the image by about as much.
+        for _ in 0..50 {
+            self.elements[last].thickness = b;
+            let gb = self.image_z(distance).ok_or_else(error)?;
+            if gb.abs() < 1e-9 || gb == ga {
+                break;
+            }
+            let next = b - gb * (b - a) / (gb - ga);
+            a = b;
+            ga = gb;
+            b = next;
+        }
+
+        self.elements[last].thickness = b;
+        if b <= 0.0 || self.image_z(distance).ok_or_else(error)?.abs() > 1e-6 {
+            return Err(error());
+        }
+        Ok(())
+    }
+
+    // For every ring of film radii, traces a grid of rays from the ring towards
+    // the rear element and keeps the bounds of the ones that get through.
+    // Sampling inside those bounds instead of the whole rear element wastes
+    // far fewer rays on the lens barrel.
+    fn compute_pupil_bounds(&mut self) -> Result<(), String> {
+        let rear_z = -self.rear().thickness;
+        let extent = 1.5 * self.rear().aperture_radius;
+        let cell = 2.0 * extent / PUPIL_GRID as f64;
+        let film_radius =
+            (self.film_width * self.film_width + self.film_height * self.film_height).sqrt() / 2.0;
+
+        let mut bounds = vec![];
+        for i in 0..PUPIL_INTERVALS {
+            let radii = [
+                film_radius * i as f64 / PUPIL_INTERVALS as f64,
+                film_radius * (i + 1) as f64 / PUPIL_INTERVALS as f64,
+            ];
+            let mut b = PupilBounds {
+                min_x: f64::INFINITY,
+                min_y: f64::INFINITY,
+                max_x: -f64::INFINITY,
+                max_y: -f64::INFINITY,
+            };
+            let mut passed = 0;
+
+            for r in radii.iter() {
+                let film = Vec3::new(*r, 0.0, 0.0);
+                for gy in 0..PUPIL_GRID {
+                    for gx in 0..PUPIL_GRID {
+                        let x = -extent + (gx as f64 + 0.5) * cell;
+                        let y = -extent + (gy as f64 + 0.5) * cell;
+                        let target = Vec3::new(x, y, rear_z);
+                        if self.trace_from_film(film, target - film).is_some() {
+                            b.min_x = b.min_x.min(x);
+                            b.min_y = b.min_y.min(y);
+                            b.max_x = b.max_x.max(x);
+                            b.max_y = b.max_y.max(y);
+                            if i == 0 && *r == 0.0 {
+                                passed += 1;
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Grow by a cell, the grid can miss a sliver at the edges
+            b.min_x -= cell;
+            b.min_y -= cell;
+            b.max_x += cell;
+            b.max_y += cell;
+            if i == 0 {
+                self.centre_area = passed as f64 * cell * cell;
+            }
+            bounds.push(b);
+        }
+
+        if self.centre_area == 0.0 {
+            return Err("no light reaches the centre of the film".to_string());
+        }
+        self.pupil_bounds = bounds;
+        Ok(())
+    }
+
+    fn film_point(&self, s: f64, t: f64) -> Vec3 {
+        // The lens turns the image upside down and left to right
+        Vec3::new(
+            -(s - 0.5) * self.film_width,
+            -(t - 0.5) * self.film_height,
+            0.0,
+        )
+    }
+
+    fn to_world(&self, d: Vec3) -> Vec3 {
+        self.u * d.x() + self.v * d.y() + self.w * d.z()
+    }
+}
+
+impl CameraModel for LensCamera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        self.get_weighted_ray(s, t, sampler).map(|(r, _)| r)
+    }
+
+    // Samples the rear element inside the exit pupil bounds for the film
+    // radius. The weight makes up for the size of the bounds and adds the
+    // cos^4 falloff of a real sensor, normalised to 1 at the film centre.
+    fn get_weighted_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<(Ray, f64)> {
+        let film = self.film_point(s, t);
+        let film_radius = (film.x() * film.x() + film.y() * film.y()).sqrt();
+        let max_radius =
+            (self.film_width * self.film_width + self.film_height * self.film_height).sqrt() / 2.0;
+        let interval =
+            ((film_radius / max_radius * PUPIL_INTERVALS as f64) as usize).min(PUPIL_INTERVALS - 1);
+        let bounds = &self.pupil_bounds[interval];
+
+        let (u1, u2) = sampler.get_2d();
+        let px = bounds.min_x + u1 * (bounds.max_x - bounds.min_x);
+        let py = bounds.min_y + u2 * (bounds.max_y - bounds.min_y);
+
+        // The bounds were found for film points on the x axis
+        let (sin, cos) = if film_radius > 0.0 {
+            (film.y() / film_radius, film.x() / film_radius)
+        } else {
+            (0.0, 1.0)
+        };
+        let pupil = Vec3::new(
+            cos * px - sin * py,
+            sin * px + cos * py,
+            -self.rear().thickness,
+        );
+
+        let time = self.shutter.sample(sampler);
+        let d = pupil - film;
+        let (o, d) = self.trace_from_film(film, d)?;
+
+        let cos_theta = Vec3::unit_vector(pupil - film).z().abs();
+        let weight = cos_theta.powi(4) * bounds.area() / self.centre_area;
+
+        let origin = self.origin + self.to_world(o) * self.scene_scale;
+        Some((Ray::new(&origin, &self.to_world(d), time), weight))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{create_sampler, SamplerType};
+
+    const DOUBLE_GAUSS: &str = "
+        # radius thickness ior aperture
+        29.475   3.76   1.67   25.2
+        84.83    0.12   1      25.2
+        19.275   4.025  1.67   23
+        40.77    3.275  1.699  23
+        12.75    5.705  1      18
+        0        4.5    0      17.1
+        -14.495  1.18   1.603  17
+        40.77    6.065  1.658  20
+        -20.385  0.19   1      20
+        437.065  3.22   1.717  20
+        -39.73   0      1      20
+    ";
+
+    fn camera(focus_dist: f64) -> LensCamera {
+        LensCamera::new(
+            Vec3::empty(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            parse_lens_table(DOUBLE_GAUSS).unwrap(),
+            &LensSettings {
+                film_height: 24.0,
+                aspect_ratio: 1.5,
+                focus_dist,
+                scene_scale: 1.0,
+            },
+            0.0,
+            1.0,
+        )
+        .unwrap()
+    }
+
+    fn rays(cam: &LensCamera, s: f64, t: f64) -> Vec<Option<(Ray, f64)>> {
+        let mut sampler = create_sampler(SamplerType::Sobol, 256, 2);
+        (0..256)
+            .map(|i| {
+                sampler.start_pixel_sample(0, 0, i);
+                cam.get_weighted_ray(s, t, sampler.as_mut())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_lens_table() {
+        let elements = parse_lens_table(DOUBLE_GAUSS).unwrap();
+        assert_eq!(elements.len(), 11);
+        assert!(elements[5].is_stop());
+        assert_eq!(elements[0].aperture_radius, 12.6);
+
+        assert!(parse_lens_table("1 2 3").is_err());
+        assert!(parse_lens_table("# nothing").is_err());
+    }
+
+    #[test]
+    fn test_focused_rays_meet_at_focus_distance() {
+        let cam = camera(1000.0);
+
+        // Rays from the film centre cross the axis near the focus distance. Not
+        // exactly, the outer ones are off by the spherical aberration of the lens.
+        for (r, _) in rays(&cam, 0.5, 0.5).iter().flatten() {
+            let t = -r.origin().x() / r.direction().x();
+            let z = r.at(t).z();
+            assert!((z + 1000.0).abs() < 50.0, "crossed the axis at {}", z);
+        }
+    }
+
+    #[test]
+    fn test_focus_breathing() {
+        // Focusing closer moves the lens away from the film
+        let far = camera(f64::INFINITY);
+        let near = camera(500.0);
+        assert!(near.rear().thickness > far.rear().thickness);
+
+        // About 50mm of focal length puts the back focus some 30-40mm out
+        assert!(far.rear().thickness > 25.0 && far.rear().thickness < 45.0);
+    }
+
+    #[test]
+    fn test_image_is_inverted_and_points_forward() {
+        let cam = camera(1000.0);
+        let top: Vec<Ray> = rays(&cam, 0.5, 1.0)
+            .into_iter()
+            .flatten()
+            .map(|(r, _)| r)
+            .collect();
+
+        assert!(!top.is_empty());
+        for r in top.iter() {
+            assert!(r.direction().z() < 0.0);
+            assert!(r.direction().y() > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_vignetting_darkens_corners() {
+        let cam = camera(1000.0);
+        let mean = |samples: Vec<Option<(Ray, f64)>>| {
+            samples
+                .iter()
+                .map(|s| s.as_ref().map_or(0.0, |(_, w)| *w))
+                .sum::<f64>()
+                / 256.0
+        };
+
+        let centre = mean(rays(&cam, 0.5, 0.5));
+        let corner = mean(rays(&cam, 0.0, 0.0));
+        assert!((centre - 1.0).abs() < 0.1);
+        assert!(corner < 0.8 * centre);
+    }
+
+    #[test]
+    fn test_exit_pupil_sampling_is_efficient() {
+        let cam = camera(1000.0);
+        let passed = rays(&cam, 0.7, 0.6).iter().filter(|r| r.is_some()).count();
+        assert!(passed > 128);
+    }
+}
@@ -0,0 +1,40 @@
+pub mod aabb;
+pub mod aov;
+pub mod aperture;
+pub mod bvh;
+pub mod camera;
+pub mod checkpoint;
+pub mod cryptomatte;
+pub mod csg;
+pub mod curve;
+pub mod denoise;
+pub mod displacement;
+pub mod film;
+pub mod filter;
+pub mod framebuffer;
+pub mod heightfield;
+pub mod hit;
+pub mod lens;
+pub mod material;
+pub mod mesh;
+pub mod models;
+pub mod motion;
+pub mod panorama;
+pub mod quad;
+pub mod quadrics;
+pub mod ray;
+pub mod render;
+pub mod rng;
+pub mod sampler;
+pub mod sdf;
+pub mod shutter;
+pub mod stereo;
+pub mod subdivision;
+pub mod texture;
+pub mod tonemap;
+pub mod transforms;
+pub mod utility;
+pub mod vec3;
+pub mod world;
+
+pub use hit::HitAble;
@@ -1,82 +1,46 @@
-mod aabb;
-mod bvh;
-mod camera;
-mod hit;
-mod material;
-mod models;
-mod ray;
-mod texture;
-mod utility;
-mod vec3;
-mod world;
-mod transforms;
-
+use std::io;
+use std::thread;
 use std::time::{Duration, SystemTime};
 
-use crate::material::Material;
-use crate::transforms::RotateY;
-use crate::transforms::Translate;
-use ray::Ray;
-use texture::{CheckerTexture, ImageTexture, PerlinTexture};
-use vec3::Vec3;
-
-use std::rc::Rc;
-
-use hit::HitAble;
-use std::f64::INFINITY;
-
-use camera::Camera;
-use material::Lambertian;
-use material::Metal;
-use material::{Dielectric, DiffuseLight};
-use models::{Sphere, XYRect, XZRect, YZRect, Box3D};
-use utility::{random_double, random_double_from_values};
-use world::World;
-
-fn ray_color(r: &Ray, background: Vec3, world: &World, depth: i32) -> Vec3 {
-    if depth <= 0 {
-        return Vec3::empty();
-    }
-
-    let hit_res = match world.hit(r, 0.001, INFINITY) {
-        Some(v) => v,
-        None => {
-            return background;
-        }
-    };
-
-    let mut scattered = Ray::empty();
-    let mut attenuation: Vec3 = Vec3::empty();
-
-    let hit_index = hit_res.id().unwrap();
-
-    let hit_pair = world.get(hit_index);
-
-    let emitted = hit_pair.1.emitted(hit_res.u(), hit_res.v(), &hit_res.p());
-
-    let scatter = hit_pair
-        .1
-        .scatter(r, &hit_res, &mut attenuation, &mut scattered);
-
-
-    if !scatter {
-        return emitted;
-    }
-
-    return emitted + attenuation * ray_color(&scattered, background, world, depth - 1);
-}
-
-fn random_scene_new() -> World {
+use ray_trace::material::Material;
+use ray_trace::transforms::RotateY;
+use ray_trace::transforms::Translate;
+use ray_trace::texture::{CheckerTexture, ImageTexture, PerlinTexture};
+use ray_trace::vec3::Vec3;
+
+use std::sync::Arc;
+
+use ray_trace::hit::HitAble;
+
+use ray_trace::aov::{write_aovs, Aov};
+use ray_trace::camera::{Camera, CameraModel, OrthographicCamera};
+use ray_trace::checkpoint::Checkpoint;
+use ray_trace::denoise::DenoiseSettings;
+use ray_trace::film::Film;
+use ray_trace::filter::BoxFilter;
+use ray_trace::framebuffer::FrameBuffer;
+use ray_trace::material::Lambertian;
+use ray_trace::material::Metal;
+use ray_trace::material::{Dielectric, DiffuseLight};
+use ray_trace::models::{Sphere, Box3D};
+use ray_trace::quad::Quad;
+use ray_trace::render::{render_progressive, AdaptiveSettings, RenderSettings};
+use ray_trace::rng::Rng;
+use ray_trace::sampler::SamplerType;
+use ray_trace::tonemap::ToneMap;
+use ray_trace::world::World;
+
+fn random_scene_new(rng: &mut Rng) -> World {
     let mut objects: Vec<Box<dyn HitAble>> = vec![];
-    let mut materials: Vec<Rc<dyn Material>> = vec![];
+    let mut materials: Vec<Arc<dyn Material>> = vec![];
 
     let mut id = 0;
 
-    // let ground_material = Rc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+    // let ground_material = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
 
     let checker = CheckerTexture::new(Vec3::new(0.2, 0.3, 0.1), Vec3::new(0.9, 0.9, 0.9));
 
-    let ground_material = Rc::new(Lambertian::from_checker(checker));
+    let ground_material = Arc::new(Lambertian::from_checker(checker));
     objects.push(Box::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
         1000.0,
@@ -88,32 +52,32 @@ fn random_scene_new() -> World {
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = random_double();
+            let choose_mat = rng.random_double();
 
             let center = Vec3::new(
-                (a as f64) + 0.9 * random_double(),
+                (a as f64) + 0.9 * rng.random_double(),
                 0.2,
-                b as f64 + 0.9 * random_double(),
+                b as f64 + 0.9 * rng.random_double(),
             );
 
             if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 if choose_mat < 0.8 {
                     // diffuse
-                    let albedo = Vec3::random() * Vec3::random();
-                    let sphere_material = Rc::new(Lambertian::new(albedo));
+                    let albedo = Vec3::random(rng) * Vec3::random(rng);
+                    let sphere_material = Arc::new(Lambertian::new(albedo));
                     objects.push(Box::new(Sphere::new(center, 0.2, id)));
                     materials.push(sphere_material);
                 } else if choose_mat < 0.95 {
                     // metal
-                    let albedo = Vec3::random_from_values(0.5, 1.0);
-                    let fuzz = random_double_from_values(0.0, 0.5);
-                    let sphere_material = Rc::new(Metal::new(albedo, fuzz));
+                    let albedo = Vec3::random_from_values(0.5, 1.0, rng);
+                    let fuzz = rng.random_double_from_values(0.0, 0.5);
+                    let sphere_material = Arc::new(Metal::new(albedo, fuzz));
                     objects.push(Box::new(Sphere::new(center, 0.2, id)));
                     materials.push(sphere_material);
                 } else {
                     // glass
 
-                    let sphere_material = Rc::new(Dielectric::new(1.5));
+                    let sphere_material = Arc::new(Dielectric::new(1.5));
                     objects.push(Box::new(Sphere::new(center, 0.2, id)));
                     materials.push(sphere_material);
                 }
@@ -122,33 +86,33 @@ fn random_scene_new() -> World {
         }
     }
 
-    let material1 = Rc::new(Dielectric::new(1.5));
+    let material1 = Arc::new(Dielectric::new(1.5));
     objects.push(Box::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, id)));
     materials.push(material1);
 
     id += 1;
 
-    let material2 = Rc::new(Lambertian::new(Vec3::new(0.4, 0.2, 0.1)));
+    let material2 = Arc::new(Lambertian::new(Vec3::new(0.4, 0.2, 0.1)));
     objects.push(Box::new(Sphere::new(Vec3::new(-4.0, 1.0, 0.0), 1.0, id)));
     materials.push(material2);
 
     id += 1;
 
-    let material3 = Rc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0));
+    let material3 = Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0));
     objects.push(Box::new(Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, id)));
     materials.push(material3);
 
     id += 1;
 
-    World::new(objects, materials)
+    World::new(objects, materials, rng)
 }
 
 // fn two_spheres_scene() -> World {
 //     let mut spheres: Vec<Sphere> = vec![];
-//     let mut materials: Vec<Rc<dyn Material>> = vec![];
+//     let mut materials: Vec<Arc<dyn Material>> = vec![];
 
 //     let checker = CheckerTexture::new(Vec3::new(0.2, 0.3, 0.1), Vec3::new(0.9, 0.9, 0.9));
-//     let ground_material = Rc::new(Lambertian::from_checker(checker));
+//     let ground_material = Arc::new(Lambertian::from_checker(checker));
 //     spheres.push(Sphere::new(
 //         Vec3::new(0.0, -10.0, 0.0),
 //         10.0,
@@ -157,7 +121,7 @@ fn random_scene_new() -> World {
 //     materials.push(ground_material);
 
 //     let checker2 = CheckerTexture::new(Vec3::new(0.2, 0.3, 0.1), Vec3::new(0.9, 0.9, 0.9));
-//     let ground_material2 = Rc::new(Lambertian::from_checker(checker2));
+//     let ground_material2 = Arc::new(Lambertian::from_checker(checker2));
 //     spheres.push(Sphere::new(
 //         Vec3::new(0.0, 10.0, 0.0),
 //         10.0,
@@ -170,11 +134,11 @@ fn random_scene_new() -> World {
 
 // fn two_perlin_spheres() -> World {
 //     let mut spheres: Vec<Sphere> = vec![];
-//     let mut materials: Vec<Rc<dyn Material>> = vec![];
+//     let mut materials: Vec<Arc<dyn Material>> = vec![];
 
-//     let pertext1 = PerlinTexture::new(4.0);
+//     let pertext1 = PerlinTexture::new(4.0, rng);
 
-//     let ground_material = Rc::new(Lambertian::from_perlin(pertext1));
+//     let ground_material = Arc::new(Lambertian::from_perlin(pertext1));
 //     spheres.push(Sphere::new(
 //         Vec3::new(0.0, -1000.0, 0.0),
 //         1000.0,
@@ -182,9 +146,9 @@ fn random_scene_new() -> World {
 //     ));
 //     materials.push(ground_material);
 
-//     let pertext2 = PerlinTexture::new(4.0);
+//     let pertext2 = PerlinTexture::new(4.0, rng);
 
-//     let ground_material2 = Rc::new(Lambertian::from_perlin(pertext2));
+//     let ground_material2 = Arc::new(Lambertian::from_perlin(pertext2));
 //     spheres.push(Sphere::new(
 //         Vec3::new(0.0, 2.0, 0.0),
 //         2.0,
@@ -195,36 +159,36 @@ fn random_scene_new() -> World {
 //     World::new(spheres, materials, vec![], vec![])
 // }
 
-fn earth() -> World {
+fn earth(rng: &mut Rng) -> World {
     let mut objects: Vec<Box<dyn HitAble>> = vec![];
-    let mut materials: Vec<Rc<dyn Material>> = vec![];
+    let mut materials: Vec<Arc<dyn Material>> = vec![];
 
     let mut id = 0;
 
     let texture = ImageTexture::new("");
 
-    let ground_material = Rc::new(Lambertian::from_image(texture));
+    let ground_material = Arc::new(Lambertian::from_image(texture));
     objects.push(Box::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 2.0, id)));
     materials.push(ground_material);
 
     id += 1;
 
-    let diff_light = Rc::new(DiffuseLight::new(Vec3::new(20.0, 20.0, 20.0)));
+    let diff_light = Arc::new(DiffuseLight::new(Vec3::new(20.0, 20.0, 20.0)));
     objects.push(Box::new(Sphere::new(Vec3::new(3.0, 0.0, -3.0), 1.0, id)));
     materials.push(diff_light);
 
-    World::new(objects, materials)
+    World::new(objects, materials, rng)
 }
 
-fn simple_light() -> World {
+fn simple_light(rng: &mut Rng) -> World {
     let mut objects: Vec<Box<dyn HitAble>> = vec![];
-    let mut materials: Vec<Rc<dyn Material>> = vec![];
+    let mut materials: Vec<Arc<dyn Material>> = vec![];
 
     let mut id = 0;
 
-    let pertext1 = PerlinTexture::new(4.0);
+    let pertext1 = PerlinTexture::new(4.0, rng);
 
-    let ground_material = Rc::new(Lambertian::from_perlin(pertext1));
+    let ground_material = Arc::new(Lambertian::from_perlin(pertext1));
     objects.push(Box::new(Sphere::new(
         Vec3::new(0.0, -1000.0, 0.0),
         1000.0,
@@ -234,10 +198,10 @@ fn simple_light() -> World {
 
     id += 1;
 
-    let pertext2 = PerlinTexture::new(4.0);
+    let pertext2 = PerlinTexture::new(4.0, rng);
 
-    // let ground_material2 = Rc::new(Lambertian::from_perlin(pertext2));
-    // let ground_material2 = Rc::new(Lambertian::new(Vec3::new(1.0, 0.0, 0.0)));
+    // let ground_material2 = Arc::new(Lambertian::from_perlin(pertext2));
+    // let ground_material2 = Arc::new(Lambertian::new(Vec3::new(1.0, 0.0, 0.0)));
     // objects.push(Box::new(Sphere::new(
     //     Vec3::new(0.0, 2.0, 0.0),
     //     2.0,
@@ -247,15 +211,15 @@ fn simple_light() -> World {
 
     // id += 1;
 
-    let white = Rc::new(Lambertian::new(Vec3::new(0.73, 0.73, 0.73)));
+    let white = Arc::new(Lambertian::new(Vec3::new(0.73, 0.73, 0.73)));
     objects.push(Box::new(Box3D::new(Vec3::new(-1.0, 0.0, -0.8), Vec3::new(3.0, 3.0, 2.0), id as usize)));
     materials.push(white.clone());
     id += 1;
 
 
 
-    let diff_light = Rc::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0)));
-    objects.push(Box::new(XYRect::new(3.0, 5.0, 1.0, 3.0, -2.0, id as usize)));
+    let diff_light = Arc::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0)));
+    objects.push(Box::new(Quad::xy(3.0, 5.0, 1.0, 3.0, -2.0, id as usize)));
     objects.push(Box::new(Sphere::new(
         Vec3::new(2.0, 12.0, 5.0),
         3.0,
@@ -263,43 +227,43 @@ fn simple_light() -> World {
     )));
     materials.push(diff_light);
 
-    World::new(objects, materials)
+    World::new(objects, materials, rng)
 }
 
-fn cornell_box() -> World {
+fn cornell_box(rng: &mut Rng) -> World {
     let mut objects: Vec<Box<dyn HitAble>> = vec![];
-    let mut materials: Vec<Rc<dyn Material>> = vec![];
+    let mut materials: Vec<Arc<dyn Material>> = vec![];
 
     let mut id = 0;
 
-    let red = Rc::new(Lambertian::new(Vec3::new(0.65, 0.05, 0.05)));
-    let white = Rc::new(Lambertian::new(Vec3::new(0.73, 0.73, 0.73)));
-    let green = Rc::new(Lambertian::new(Vec3::new(0.12, 0.45, 0.15)));
-    let light = Rc::new(DiffuseLight::new(Vec3::new(15.0, 15.0, 15.0)));
+    let red = Arc::new(Lambertian::new(Vec3::new(0.65, 0.05, 0.05)));
+    let white = Arc::new(Lambertian::new(Vec3::new(0.73, 0.73, 0.73)));
+    let green = Arc::new(Lambertian::new(Vec3::new(0.12, 0.45, 0.15)));
+    let light = Arc::new(DiffuseLight::new(Vec3::new(15.0, 15.0, 15.0)));
     
 
 
-    objects.push(Box::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 555.0, id as usize)));
+    objects.push(Box::new(Quad::yz(0.0, 555.0, 0.0, 555.0, 555.0, id as usize)));
     materials.push(green.clone());
     id += 1;
 
-    objects.push(Box::new(YZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, id as usize)));
+    objects.push(Box::new(Quad::yz(0.0, 555.0, 0.0, 555.0, 0.0, id as usize)));
     materials.push(red.clone());
     id += 1;
 
-    objects.push(Box::new(XZRect::new(213.0, 343.0, 227.0, 332.0, 554.0, id as usize)));
+    objects.push(Box::new(Quad::xz(213.0, 343.0, 227.0, 332.0, 554.0, id as usize)));
     materials.push(light);
     id += 1;
 
-    objects.push(Box::new(XZRect::new(0.0, 555.0, 0.0, 555.0, 0.0, id as usize)));
+    objects.push(Box::new(Quad::xz(0.0, 555.0, 0.0, 555.0, 0.0, id as usize)));
     materials.push(white.clone());
     id += 1;
 
-    objects.push(Box::new(XZRect::new(0.0, 555.0, 0.0, 555.0, 555.0, id as usize)));
+    objects.push(Box::new(Quad::xz(0.0, 555.0, 0.0, 555.0, 555.0, id as usize)));
     materials.push(white.clone());
     id += 1;
 
-    objects.push(Box::new(XYRect::new(0.0, 555.0, 0.0, 555.0, 555.0, id as usize)));
+    objects.push(Box::new(Quad::xy(0.0, 555.0, 0.0, 555.0, 555.0, id as usize)));
     materials.push(white.clone());
     id += 1;
     
@@ -314,8 +278,18 @@ fn cornell_box() -> World {
     id += 1;
     
 
-    World::new(objects, materials)
+    let mut world = World::new(objects, materials, rng);
+
+    let object_names = ["left_wall", "right_wall", "light", "floor", "ceiling", "back_wall", "tall_box", "short_box"];
+    for (id, name) in object_names.iter().enumerate() {
+        world.set_object_name(id, name);
+    }
+    world.set_material_name(world.material_id(0), "green");
+    world.set_material_name(world.material_id(1), "red");
+    world.set_material_name(world.material_id(2), "light");
+    world.set_material_name(world.material_id(3), "white");
 
+    world
 }
 
 fn main() {
@@ -327,10 +301,44 @@ fn main() {
     let samples_per_pixel = 200;
     let max_depth = 50;
 
-    print!("P3\n{} {}\n255\n", image_width, image_height);
-
-    // let world = simple_light();
-    let world = cornell_box();
+    // Set to Some to let converged pixels stop early and spend the samples on
+    // noisy ones instead. The sample count image shows where they went.
+    let adaptive: Option<AdaptiveSettings> = None;
+    let sample_count_output: Option<&str> = None;
+
+    // Progressive rendering. Every pass adds up to `samples_per_pass` samples to
+    // each pixel and then refreshes the preview and the checkpoint. With `resume`
+    // the render carries on from the checkpoint, which also adds samples to a
+    // finished render when `samples_per_pixel` has been raised.
+    let samples_per_pass = 16;
+    let preview_output: Option<&str> = None;
+    let checkpoint_path: Option<&str> = None;
+    let resume = false;
+
+    // The same seed always produces the same image, whatever the thread count
+    let seed = 0;
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut rng = Rng::new(seed);
+
+    // Post processing. Exposure is in EV stops, the HDR file keeps the linear values
+    let tone_map = ToneMap::Clamp;
+    let exposure = 0.0;
+    let hdr_output: Option<&str> = None;
+
+    // Set to a file prefix to also write the first hit normal, depth, albedo,
+    // object id, material id, UV and position as `<prefix>_<name>.pfm`
+    let aov_output: Option<&str> = None;
+
+    // Set to Some to denoise the final image, guided by the albedo and normal AOVs
+    let denoise: Option<DenoiseSettings> = None;
+
+    // Set to a file prefix to write Cryptomatte style ID mattes for objects and
+    // materials, the top `id_matte_ranks` ids of every pixel with their coverage
+    let id_matte_output: Option<&str> = None;
+    let id_matte_ranks = 6;
+
+    // let world = simple_light(&mut rng);
+    let world = cornell_box(&mut rng);
 
     
     // let lookfrom = Vec3::new(26.0, 3.0, 6.0);
@@ -348,33 +356,133 @@ fn main() {
     let aperture = 0.0;
     let background = Vec3::empty();
 
-    let cam = Camera::new(
-        lookfrom,
-        lookat,
-        vup,
-        40.0,
-        aspect_ratio,
-        aperture,
-        dist_to_focus,
-        0.0,
-        1.0,
-    );
-
-    for j in (0..image_height).rev() {
-        eprintln!("Scanlines remaining: {}", j);
-        for i in 0..image_width {
-            let mut color = Vec3::empty();
-            for _s in 0..samples_per_pixel {
-                let u = (i as f64 + random_double()) / image_width as f64;
-                let v = (j as f64 + random_double()) / image_height as f64;
-
-                let r = cam.get_ray(u, v);
-                color += ray_color(&r, background, &world, max_depth);
+    // An orthographic view shows `view_height` scene units from top to bottom
+    let orthographic = false;
+    let view_height = 600.0;
+
+    let cam: Box<dyn CameraModel> = if orthographic {
+        Box::new(OrthographicCamera::new(
+            lookfrom,
+            lookat,
+            vup,
+            view_height * aspect_ratio,
+            view_height,
+            0.0,
+            1.0,
+        )
+        .with_focus(aperture, dist_to_focus))
+    } else {
+        Box::new(Camera::new(
+            lookfrom,
+            lookat,
+            vup,
+            40.0,
+            aspect_ratio,
+            aperture,
+            dist_to_focus,
+            0.0,
+            1.0,
+        ))
+    };
+
+    // A box of radius 0.5 is the plain per pixel average. Wider filters such as
+    // GaussianFilter or MitchellFilter splat every sample into its neighbours.
+    let mut settings = RenderSettings {
+        image_width: image_width as usize,
+        image_height: image_height as usize,
+        samples_per_pixel,
+        max_depth,
+        background,
+        seed,
+        sampler: SamplerType::Sobol,
+        sampler_samples: None,
+        adaptive,
+        filter: Box::new(BoxFilter::new(0.5)),
+        tile_size: 16,
+        threads,
+        aovs: aov_output.is_some() || denoise.is_some(),
+        id_mattes: id_matte_output.is_some(),
+    };
+
+    let mut film = Film::new(settings.image_width, settings.image_height);
+    if let (true, Some(path)) = (resume, checkpoint_path) {
+        let checkpoint =
+            Checkpoint::load(path).and_then(|c| c.check_settings(&settings).map(|_| c));
+        match checkpoint {
+            Ok(checkpoint) => {
+                settings.sampler_samples = Some(checkpoint.sampler_samples);
+                film = checkpoint.film;
             }
-            color.write_color(samples_per_pixel);
+            Err(e) => {
+                eprintln!("Could not resume from {}: {:?}", path, e);
+                return;
+            }
+        }
+    }
+
+    let film = render_progressive(&world, cam.as_ref(), &settings, film, samples_per_pass, |film| {
+        if let Some(path) = preview_output {
+            let preview = film.resolve().tone_map(tone_map, exposure);
+            if let Err(e) = preview.write_ppm_file(path) {
+                eprintln!("Could not write {}: {:?}", path, e);
+            }
+        }
+        if let Some(path) = checkpoint_path {
+            let saved = Checkpoint::save(
+                path,
+                film,
+                settings.seed,
+                settings.sampler,
+                settings.sampler_samples(),
+            );
+            if let Err(e) = saved {
+                eprintln!("Could not write {}: {:?}", path, e);
+            }
+        }
+    });
+    let mut frame = film.resolve();
+
+    if let Some(denoise) = denoise {
+        let albedo = film.aov_image(Aov::Albedo);
+        let normal = film.aov_image(Aov::Normal);
+        if let (Some(albedo), Some(normal)) = (albedo, normal) {
+            frame = frame.denoise(&albedo, &normal, &denoise);
+        }
+    }
+
+    if let Some(path) = sample_count_output {
+        if let Err(e) = film.sample_count_image().write_ppm_file(path) {
+            eprintln!("Could not write {}: {:?}", path, e);
         }
     }
 
+    if let Some(path) = hdr_output {
+        if let Err(e) = frame.write_pfm(path) {
+            eprintln!("Could not write {}: {:?}", path, e);
+        }
+    }
+
+    if let Some(prefix) = aov_output {
+        let images: Vec<(Aov, FrameBuffer)> = Aov::ALL
+            .iter()
+            .filter_map(|aov| film.aov_image(*aov).map(|image| (*aov, image)))
+            .collect();
+        if let Err(e) = write_aovs(prefix, &images) {
+            eprintln!("Could not write AOVs {}: {:?}", prefix, e);
+        }
+    }
+
+    if let Some(prefix) = id_matte_output {
+        if let Err(e) = film.write_id_mattes(prefix, id_matte_ranks, &world) {
+            eprintln!("Could not write ID mattes {}: {:?}", prefix, e);
+        }
+    }
+
+    let display = frame.tone_map(tone_map, exposure);
+    if let Err(e) = display.write_ppm(&mut io::stdout().lock()) {
+        eprintln!("Error: {:?}", e);
+    }
+
     eprint!("done");
     match now.elapsed() {
         Ok(elapsed) => {
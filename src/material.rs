@@ -1,52 +1,65 @@
-use std::rc::Rc;
+use std::f64::consts::PI;
+use std::sync::Arc;
 
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::texture::{SolidColor, Texture};
 use crate::vec3::Vec3;
 use crate::{
     hit::HitRecord,
     texture::{CheckerTexture, PerlinTexture},
 };
-use crate::{random_double, texture::ImageTexture};
+use crate::texture::ImageTexture;
+use crate::rng::mix_bits;
 
-pub trait Material {
+pub trait Material: Send + Sync {
     fn scatter(
         &self,
         ray_in: &Ray,
         rec: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool;
 
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3;
+
+    // Surface colour without lighting, used for the albedo AOV
+    fn albedo(&self, rec: &HitRecord) -> Vec3;
+
+    // Whether the hit is on a part of the surface that is cut away, which
+    // rays pass straight through
+    fn cut_away(&self, _r: &Ray, _rec: &HitRecord) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian {
-    albedo: Rc<dyn Texture>,
+    albedo: Arc<dyn Texture>,
 }
 
 impl Lambertian {
     pub fn new(color: Vec3) -> Lambertian {
         Lambertian {
-            albedo: Rc::new(SolidColor::new(color)),
+            albedo: Arc::new(SolidColor::new(color)),
         }
     }
 
     pub fn from_checker(texture: CheckerTexture) -> Lambertian {
         Lambertian {
-            albedo: Rc::new(texture),
+            albedo: Arc::new(texture),
         }
     }
 
     pub fn from_perlin(texture: PerlinTexture) -> Lambertian {
         Lambertian {
-            albedo: Rc::new(texture),
+            albedo: Arc::new(texture),
         }
     }
 
     pub fn from_image(texture: ImageTexture) -> Lambertian {
         Lambertian {
-            albedo: Rc::new(texture),
+            albedo: Arc::new(texture),
         }
     }
 }
@@ -58,8 +71,9 @@ impl Material for Lambertian {
         rec: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
-        let scatter_direction = rec.normal() + Vec3::random_unit_vector();
+        let scatter_direction = rec.normal() + Vec3::random_unit_vector(sampler);
         *scattered = Ray::new(&rec.p(), &scatter_direction, ray_in.time());
         *attenuation = self.albedo.value(rec.u(), rec.v(), &rec.p());
         true
@@ -68,6 +82,10 @@ impl Material for Lambertian {
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
         Vec3::empty()
     }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec.u(), rec.v(), &rec.p())
+    }
 }
 
 pub struct Metal {
@@ -92,11 +110,12 @@ impl Material for Metal {
         rec: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
         let reflected: Vec3 = Vec3::reflect(&Vec3::unit_vector(ray_in.direction()), &rec.normal());
         *scattered = Ray::new(
             &rec.p(),
-            &(reflected + Vec3::random_in_unit_sphere() * self.fuzz),
+            &(reflected + Vec3::random_in_unit_sphere(sampler) * self.fuzz),
             ray_in.time(),
         );
         *attenuation = Vec3::new(self.albedo.x(), self.albedo.y(), self.albedo.z());
@@ -109,6 +128,10 @@ impl Material for Metal {
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
         Vec3::empty()
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
+    }
 }
 
 pub fn schlick(cosine: f64, ref_idx: f64) -> f64 {
@@ -135,6 +158,7 @@ impl Material for Dielectric {
         rec: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
         *attenuation = Vec3::new(1.0, 1.0, 1.0);
         let etai_over_etat = if rec.front_face() {
@@ -156,7 +180,7 @@ impl Material for Dielectric {
 
         let reflect_prob = schlick(cos_theta, etai_over_etat);
 
-        if random_double() < reflect_prob {
+        if sampler.get_1d() < reflect_prob {
             let reflected = Vec3::reflect(&unit_direction, &rec.normal());
             *scattered = Ray::new(&rec.p(), &reflected, ray_in.time());
 
@@ -173,16 +197,20 @@ impl Material for Dielectric {
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
         Vec3::empty()
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
 }
 
 pub struct DiffuseLight {
-    emit: Rc<dyn Texture>,
+    emit: Arc<dyn Texture>,
 }
 
 impl DiffuseLight {
     pub fn new(color: Vec3) -> DiffuseLight {
         DiffuseLight {
-            emit: Rc::new(SolidColor::new(color)),
+            emit: Arc::new(SolidColor::new(color)),
         }
     }
 }
@@ -194,6 +222,7 @@ impl Material for DiffuseLight {
         rec: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
         false
     }
@@ -201,4 +230,865 @@ impl Material for DiffuseLight {
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
         self.emit.value(u, v, p)
     }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        let e = self.emit.value(rec.u(), rec.v(), &rec.p());
+        Vec3::new(e.x().min(1.0), e.y().min(1.0), e.z().min(1.0))
+    }
+}
+
+// Exact Fresnel reflectance for unpolarised light between two dielectrics
+fn fr_dielectric(cos_theta_i: f64, eta_i: f64, eta_t: f64) -> f64 {
+    let mut cos_i = cos_theta_i.clamp(-1.0, 1.0);
+    let (eta_i, eta_t) = if cos_i < 0.0 {
+        cos_i = -cos_i;
+        (eta_t, eta_i)
+    } else {
+        (eta_i, eta_t)
+    };
+
+    let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    let sin_t = eta_i / eta_t * sin_i;
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+
+    let parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+// Lobes of the hair BSDF: reflection, two transmissions, one internal
+// reflection, with everything after that lumped into the last one
+const HAIR_P_MAX: usize = 3;
+
+// Modified Bessel function of the first kind, order zero
+fn bessel_i0(x: f64) -> f64 {
+    let mut value = 0.0;
+    let mut x2i = 1.0;
+    let mut factorial = 1.0;
+    let mut four_i = 1.0;
+    for i in 0..10 {
+        if i > 1 {
+            factorial *= i as f64;
+        }
+        value += x2i / (four_i * factorial * factorial);
+        x2i *= x * x;
+        four_i *= 4.0;
+    }
+    value
+}
+
+fn log_bessel_i0(x: f64) -> f64 {
+    if x > 12.0 {
+        x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        bessel_i0(x).ln()
+    }
+}
+
+// Longitudinal scattering with variance v
+fn hair_mp(cos_theta_i: f64, cos_theta_o: f64, sin_theta_i: f64, sin_theta_o: f64, v: f64) -> f64 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        (log_bessel_i0(a) - b - 1.0 / v + std::f64::consts::LN_2 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        (-b).exp() * bessel_i0(a) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+// How much light leaves through each lobe, given the transmittance of one
+// pass through the fibre
+fn hair_ap(cos_theta_o: f64, eta: f64, h: f64, transmittance: Vec3) -> [Vec3; HAIR_P_MAX + 1] {
+    let cos_gamma_o = (1.0 - h * h).max(0.0).sqrt();
+    let f = fr_dielectric(cos_theta_o * cos_gamma_o, 1.0, eta);
+
+    let mut ap = [Vec3::empty(); HAIR_P_MAX + 1];
+    ap[0] = Vec3::new(f, f, f);
+    ap[1] = transmittance * (1.0 - f) * (1.0 - f);
+    for p in 2..HAIR_P_MAX {
+        ap[p] = ap[p - 1] * transmittance * f;
+    }
+    let tf = transmittance * f;
+    ap[HAIR_P_MAX] = ap[HAIR_P_MAX - 1] * tf / Vec3::new(1.0 - tf.x(), 1.0 - tf.y(), 1.0 - tf.z());
+    ap
+}
+
+fn hair_phi(p: usize, gamma_o: f64, gamma_t: f64) -> f64 {
+    2.0 * p as f64 * gamma_t - 2.0 * gamma_o + p as f64 * PI
+}
+
+fn logistic(x: f64, s: f64) -> f64 {
+    let e = (-x.abs() / s).exp();
+    e / (s * (1.0 + e) * (1.0 + e))
+}
+
+fn logistic_cdf(x: f64, s: f64) -> f64 {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+fn trimmed_logistic(x: f64, s: f64, a: f64, b: f64) -> f64 {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+
+fn sample_trimmed_logistic(u: f64, s: f64, a: f64, b: f64) -> f64 {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(a, s)) - 1.0).ln();
+    x.clamp(a, b)
+}
+
+// Azimuthal scattering of lobe p
+fn hair_np(phi: f64, p: usize, s: f64, gamma_o: f64, gamma_t: f64) -> f64 {
+    let mut dphi = phi - hair_phi(p, gamma_o, gamma_t);
+    while dphi > PI {
+        dphi -= 2.0 * PI;
+    }
+    while dphi < -PI {
+        dphi += 2.0 * PI;
+    }
+    trimmed_logistic(dphi, s, -PI, PI)
+}
+
+// Denominator of the fit from hair colour to absorption, for a given
+// azimuthal roughness
+fn hair_color_fit(beta_n: f64) -> f64 {
+    5.969 - 0.215 * beta_n + 2.532 * beta_n.powi(2) - 10.73 * beta_n.powi(3)
+        + 5.574 * beta_n.powi(4)
+        + 0.245 * beta_n.powi(5)
+}
+
+// Hair fibres after d'Eon et al. and Chiang et al., as in pbrt: a rough
+// dielectric cylinder with absorbing pigment inside and tilted cuticle
+// scales. For `Curve`s, which set the tangent and give the offset across the
+// fibre in v. `sigma_a` is the absorption per unit of fibre diameter,
+// `beta_m` and `beta_n` the longitudinal and azimuthal roughness, between 0
+// and 1, and `alpha` the scale tilt in degrees.
+pub struct Hair {
+    sigma_a: Vec3,
+    eta: f64,
+    beta_m: f64,
+    beta_n: f64,
+    alpha: f64,
+    // Longitudinal variance per lobe, azimuthal logistic scale and the
+    // scale tilt doubled once, twice and four times
+    v: [f64; HAIR_P_MAX + 1],
+    s: f64,
+    sin_2k_alpha: [f64; 3],
+    cos_2k_alpha: [f64; 3],
+}
+
+impl Hair {
+    pub fn new(sigma_a: Vec3, eta: f64, beta_m: f64, beta_n: f64, alpha: f64) -> Hair {
+        let v0 = (0.726 * beta_m + 0.812 * beta_m.powi(2) + 3.7 * beta_m.powi(20)).powi(2);
+        let mut v = [4.0 * v0; HAIR_P_MAX + 1];
+        v[0] = v0;
+        v[1] = 0.25 * v0;
+
+        let s =
+            (PI / 8.0).sqrt() * (0.265 * beta_n + 1.194 * beta_n.powi(2) + 5.372 * beta_n.powi(22));
+
+        let mut sin_2k_alpha = [alpha.to_radians().sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [(1.0 - sin_2k_alpha[0].powi(2)).max(0.0).sqrt(), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+
+        Hair {
+            sigma_a,
+            eta,
+            beta_m,
+            beta_n,
+            alpha,
+            v,
+            s,
+            sin_2k_alpha,
+            cos_2k_alpha,
+        }
+    }
+
+    // Eumelanin makes hair brown to black, pheomelanin red. Around 0.3 of
+    // eumelanin is blond, 1.3 brown and 8 black.
+    pub fn from_melanin(eumelanin: f64, pheomelanin: f64) -> Hair {
+        let sigma_a =
+            Vec3::new(0.419, 0.697, 1.37) * eumelanin + Vec3::new(0.187, 0.4, 1.05) * pheomelanin;
+        Hair::new(sigma_a, 1.55, 0.3, 0.3, 2.0)
+    }
+
+    // Absorption that gives roughly this colour once light has bounced
+    // around many fibres
+    pub fn from_color(color: Vec3) -> Hair {
+        let mut hair = Hair::new(Vec3::empty(), 1.55, 0.3, 0.3, 2.0);
+        hair.sigma_a = hair.sigma_a_for(color);
+        hair
+    }
+
+    pub fn with_roughness(self, beta_m: f64, beta_n: f64) -> Hair {
+        Hair::new(self.sigma_a, self.eta, beta_m, beta_n, self.alpha)
+    }
+
+    pub fn with_scale_angle(self, alpha: f64) -> Hair {
+        Hair::new(self.sigma_a, self.eta, self.beta_m, self.beta_n, alpha)
+    }
+
+    pub fn with_eta(self, eta: f64) -> Hair {
+        Hair::new(self.sigma_a, eta, self.beta_m, self.beta_n, self.alpha)
+    }
+
+    fn sigma_a_for(&self, color: Vec3) -> Vec3 {
+        let fit = hair_color_fit(self.beta_n);
+        let channel = |c: f64| (c.max(1e-4).ln() / fit).powi(2);
+        Vec3::new(channel(color.x()), channel(color.y()), channel(color.z()))
+    }
+
+    // Outgoing angle as seen by lobe p, which the scales tilt
+    fn tilt(&self, p: usize, sin_theta_o: f64, cos_theta_o: f64) -> (f64, f64) {
+        let (sin_op, cos_op) = match p {
+            0 => (
+                sin_theta_o * self.cos_2k_alpha[1] - cos_theta_o * self.sin_2k_alpha[1],
+                cos_theta_o * self.cos_2k_alpha[1] + sin_theta_o * self.sin_2k_alpha[1],
+            ),
+            1 => (
+                sin_theta_o * self.cos_2k_alpha[0] + cos_theta_o * self.sin_2k_alpha[0],
+                cos_theta_o * self.cos_2k_alpha[0] - sin_theta_o * self.sin_2k_alpha[0],
+            ),
+            2 => (
+                sin_theta_o * self.cos_2k_alpha[2] + cos_theta_o * self.sin_2k_alpha[2],
+                cos_theta_o * self.cos_2k_alpha[2] - sin_theta_o * self.sin_2k_alpha[2],
+            ),
+            _ => (sin_theta_o, cos_theta_o),
+        };
+        (sin_op, cos_op.abs())
+    }
+
+    // Lobe weights and the refracted azimuth for light leaving at theta_o
+    // from offset h
+    fn lobes(&self, sin_theta_o: f64, cos_theta_o: f64, h: f64) -> ([Vec3; HAIR_P_MAX + 1], f64) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).max(0.0).sqrt();
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = (h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = (1.0 - sin_gamma_t * sin_gamma_t).max(0.0).sqrt();
+
+        let path = 2.0 * cos_gamma_t / cos_theta_t;
+        let transmittance = Vec3::new(
+            (-self.sigma_a.x() * path).exp(),
+            (-self.sigma_a.y() * path).exp(),
+            (-self.sigma_a.z() * path).exp(),
+        );
+        (
+            hair_ap(cos_theta_o, self.eta, h, transmittance),
+            sin_gamma_t.asin(),
+        )
+    }
+
+    // Chance of picking each lobe when sampling, by luminance
+    fn lobe_pdf(ap: &[Vec3; HAIR_P_MAX + 1]) -> [f64; HAIR_P_MAX + 1] {
+        let total: f64 = ap.iter().map(|a| a.luminance()).sum();
+        let mut pdf = [0.0; HAIR_P_MAX + 1];
+        for p in 0..=HAIR_P_MAX {
+            pdf[p] = if total > 0.0 {
+                ap[p].luminance() / total
+            } else {
+                0.0
+            };
+        }
+        pdf
+    }
+
+    // BSDF times the cosine to the fibre axis, and the density of sampling wi,
+    // in the fibre frame: x along the fibre, z towards the viewer
+    fn eval(&self, wo: Vec3, wi: Vec3, h: f64) -> (Vec3, f64) {
+        let sin_theta_o = wo.x();
+        let cos_theta_o = (1.0 - sin_theta_o * sin_theta_o).max(0.0).sqrt();
+        let phi_o = wo.z().atan2(wo.y());
+        let sin_theta_i = wi.x();
+        let cos_theta_i = (1.0 - sin_theta_i * sin_theta_i).max(0.0).sqrt();
+        let phi_i = wi.z().atan2(wi.y());
+
+        let gamma_o = h.clamp(-1.0, 1.0).asin();
+        let (ap, gamma_t) = self.lobes(sin_theta_o, cos_theta_o, h);
+        let ap_pdf = Hair::lobe_pdf(&ap);
+        let phi = phi_i - phi_o;
+
+        let mut f = Vec3::empty();
+        let mut pdf = 0.0;
+        for p in 0..HAIR_P_MAX {
+            let (sin_op, cos_op) = self.tilt(p, sin_theta_o, cos_theta_o);
+            let mp = hair_mp(cos_theta_i, cos_op, sin_theta_i, sin_op, self.v[p]);
+            let np = hair_np(phi, p, self.s, gamma_o, gamma_t);
+            f += ap[p] * (mp * np);
+            pdf += mp * ap_pdf[p] * np;
+        }
+        let mp = hair_mp(
+            cos_theta_i,
+            cos_theta_o,
+            sin_theta_i,
+            sin_theta_o,
+            self.v[HAIR_P_MAX],
+        );
+        f += ap[HAIR_P_MAX] * (mp / (2.0 * PI));
+        pdf += mp * ap_pdf[HAIR_P_MAX] / (2.0 * PI);
+
+        (f, pdf)
+    }
+
+    // Picks a lobe with u0.0, its azimuth with u0.1 and its longitudinal
+    // angle with u1. Returns wi and the weight f cos / pdf.
+    fn sample(&self, wo: Vec3, h: f64, u0: (f64, f64), u1: (f64, f64)) -> Option<(Vec3, Vec3)> {
+        let sin_theta_o = wo.x();
+        let cos_theta_o = (1.0 - sin_theta_o * sin_theta_o).max(0.0).sqrt();
+        let phi_o = wo.z().atan2(wo.y());
+
+        let (ap, gamma_t) = self.lobes(sin_theta_o, cos_theta_o, h);
+        let ap_pdf = Hair::lobe_pdf(&ap);
+        let mut u = u0.0;
+        let mut p = 0;
+        while p < HAIR_P_MAX {
+            if u < ap_pdf[p] {
+                break;
+            }
+            u -= ap_pdf[p];
+            p += 1;
+        }
+
+        let (sin_op, cos_op) = self.tilt(p, sin_theta_o, cos_theta_o);
+        let v = self.v[p];
+        let u1_0 = u1.0.max(1e-5);
+        let cos_theta = 1.0 + v * (u1_0 + (1.0 - u1_0) * (-2.0 / v).exp()).ln();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let cos_phi = (2.0 * PI * u1.1).cos();
+        let sin_theta_i = (-cos_theta * sin_op + sin_theta * cos_phi * cos_op).clamp(-1.0, 1.0);
+        let cos_theta_i = (1.0 - sin_theta_i * sin_theta_i).max(0.0).sqrt();
+
+        let gamma_o = h.clamp(-1.0, 1.0).asin();
+        let dphi = if p < HAIR_P_MAX {
+            hair_phi(p, gamma_o, gamma_t) + sample_trimmed_logistic(u0.1, self.s, -PI, PI)
+        } else {
+            2.0 * PI * u0.1
+        };
+        let phi_i = phi_o + dphi;
+        let wi = Vec3::new(
+            sin_theta_i,
+            cos_theta_i * phi_i.cos(),
+            cos_theta_i * phi_i.sin(),
+        );
+
+        let (f, pdf) = self.eval(wo, wi, h);
+        if pdf > 0.0 {
+            Some((wi, f / pdf))
+        } else {
+            None
+        }
+    }
+}
+
+impl Material for Hair {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let wo = -Vec3::unit_vector(ray_in.direction());
+
+        // Surfaces without a tangent get one across the normal
+        let x = if rec.dpdu().length_squared() > 0.0 {
+            Vec3::unit_vector(rec.dpdu())
+        } else {
+            let n = rec.normal();
+            let other = if n.x().abs() > 0.9 {
+                Vec3::new(0.0, 1.0, 0.0)
+            } else {
+                Vec3::new(1.0, 0.0, 0.0)
+            };
+            Vec3::unit_vector(Vec3::cross(&n, &other))
+        };
+        let towards_viewer = wo - x * Vec3::dot(&wo, &x);
+        let z = if towards_viewer.length_squared() > 1e-12 {
+            Vec3::unit_vector(towards_viewer)
+        } else {
+            rec.normal()
+        };
+        let y = Vec3::cross(&z, &x);
+
+        let local_wo = Vec3::new(Vec3::dot(&wo, &x), Vec3::dot(&wo, &y), Vec3::dot(&wo, &z));
+        let h = (2.0 * rec.v() - 1.0).clamp(-1.0, 1.0);
+        let u0 = sampler.get_2d();
+        let u1 = sampler.get_2d();
+
+        match self.sample(local_wo, h, u0, u1) {
+            Some((wi, weight)) => {
+                let direction = x * wi.x() + y * wi.y() + z * wi.z();
+                *scattered = Ray::new(&rec.p(), &direction, ray_in.time());
+                *attenuation = weight;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+        Vec3::empty()
+    }
+
+    // Inverse of the fit in from_color
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        let fit = hair_color_fit(self.beta_n);
+        let channel = |sigma: f64| (-sigma.sqrt() * fit).exp();
+        Vec3::new(
+            channel(self.sigma_a.x()),
+            channel(self.sigma_a.y()),
+            channel(self.sigma_a.z()),
+        )
+    }
+}
+
+// Where Bumped gets its shading normals from
+enum NormalSource {
+    // Tangent space normals, with red along dp/du, green along dp/dv and blue
+    // out of the surface, each mapped from 0..1 to -1..1
+    Map(Arc<dyn Texture>),
+    // Heights from the brightness of a texture
+    Bump(Arc<dyn Texture>),
+}
+
+// Lowest cosine allowed between the shading normal and the way back to the
+// viewer
+const MIN_SHADING_COS: f64 = 0.01;
+
+// Tangents at the hit, made perpendicular to the normal n. Surfaces without
+// dp/du or dp/dv get unit ones, with v a quarter turn from u around n.
+fn tangent_frame(n: Vec3, dpdu: Vec3, dpdv: Vec3) -> (Vec3, Vec3) {
+    let t = dpdu - n * Vec3::dot(&n, &dpdu);
+    let t = if t.length_squared() > 1e-20 {
+        t
+    } else {
+        let other = if n.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        Vec3::unit_vector(Vec3::cross(&n, &other))
+    };
+    let b = dpdv - n * Vec3::dot(&n, &dpdv);
+    let b = if b.length_squared() > 1e-20 {
+        b
+    } else {
+        Vec3::cross(&n, &t)
+    };
+    (t, b)
+}
+
+// Gives another material detail it does not have geometry for, by changing
+// the normal it shades with. Either a normal map or a bump map, both scaled
+// by `with_strength`.
+//
+// The new normal can face away from the viewer at grazing angles, or send
+// light out on the wrong side of the real surface. The first is bent back
+// towards the viewer, and rays that would go through the surface are dropped
+// rather than let light leak in or out.
+pub struct Bumped {
+    material: Arc<dyn Material>,
+    source: NormalSource,
+    strength: f64,
+    // Step in u and v for the slope of a bump map
+    delta: f64,
+}
+
+impl Bumped {
+    pub fn normal_map(material: Arc<dyn Material>, texture: Arc<dyn Texture>) -> Self {
+        Self {
+            material,
+            source: NormalSource::Map(texture),
+            strength: 1.0,
+            delta: 0.001,
+        }
+    }
+
+    // A height of 1 is the length of dp/du, so on a quad the size of its u
+    // side
+    pub fn bump_map(material: Arc<dyn Material>, heights: Arc<dyn Texture>) -> Self {
+        Self {
+            material,
+            source: NormalSource::Bump(heights),
+            strength: 1.0,
+            delta: 0.001,
+        }
+    }
+
+    pub fn with_strength(mut self, strength: f64) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    // Image textures are flat between texels, so bump maps from them want
+    // about a texel
+    pub fn with_delta(mut self, delta: f64) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    // Perturbed normal facing the same way as rec.normal()
+    fn shading_normal(&self, ray_in: &Ray, rec: &HitRecord) -> Vec3 {
+        // Maps are made for the outside, so work with the outward normal
+        let n = if rec.front_face() {
+            rec.normal()
+        } else {
+            -rec.normal()
+        };
+        let (t, b) = tangent_frame(n, rec.dpdu(), rec.dpdv());
+        let (u, v, p) = (rec.u(), rec.v(), rec.p());
+
+        let perturbed = match &self.source {
+            NormalSource::Map(texture) => {
+                let c = texture.value(u, v, &p) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
+                // Green follows dp/dv, which is not always a quarter turn
+                // from dp/du
+                let side = Vec3::cross(&n, &Vec3::unit_vector(t));
+                let side = if Vec3::dot(&side, &b) < 0.0 {
+                    -side
+                } else {
+                    side
+                };
+                Vec3::unit_vector(t) * (c.x() * self.strength)
+                    + side * (c.y() * self.strength)
+                    + n * c.z()
+            }
+            NormalSource::Bump(heights) => {
+                let height = |du: f64, dv: f64| {
+                    let moved = p + rec.dpdu() * du + rec.dpdv() * dv;
+                    heights.value(u + du, v + dv, &moved).luminance() * self.strength
+                };
+                let h = height(0.0, 0.0);
+                let dhdu = (height(self.delta, 0.0) - h) / self.delta;
+                let dhdv = (height(0.0, self.delta) - h) / self.delta;
+
+                // Normal of the surface pushed out along n by the height
+                let bumped = Vec3::cross(&(t + n * dhdu), &(b + n * dhdv));
+                if Vec3::dot(&bumped, &n) < 0.0 {
+                    -bumped
+                } else {
+                    bumped
+                }
+            }
+        };
+        let perturbed = if perturbed.length_squared() > 0.0 {
+            Vec3::unit_vector(perturbed)
+        } else {
+            n
+        };
+        let shading = if rec.front_face() {
+            perturbed
+        } else {
+            -perturbed
+        };
+
+        let wo = -Vec3::unit_vector(ray_in.direction());
+        let cosine = Vec3::dot(&shading, &wo);
+        if cosine < MIN_SHADING_COS {
+            Vec3::unit_vector(shading + wo * (MIN_SHADING_COS - cosine))
+        } else {
+            shading
+        }
+    }
+}
+
+impl Material for Bumped {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let mut shaded = rec.clone();
+        shaded.set_normal(self.shading_normal(ray_in, rec));
+        if !self
+            .material
+            .scatter(ray_in, &shaded, attenuation, scattered, sampler)
+        {
+            return false;
+        }
+
+        // Leaving on one side of the shading normal but the other side of the
+        // real surface
+        let d = scattered.direction();
+        let real = Vec3::dot(&d, &rec.normal());
+        let shading = Vec3::dot(&d, &shaded.normal());
+        real * shading > 0.0
+    }
+
+    fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        self.material.emitted(u, v, p)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.material.albedo(rec)
+    }
+
+    fn cut_away(&self, r: &Ray, rec: &HitRecord) -> bool {
+        self.material.cut_away(r, rec)
+    }
+}
+
+// How Cutout turns opacity into a hit or a miss
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AlphaMode {
+    // Cut away where the opacity is below the threshold, for hard edges
+    Threshold(f64),
+    // Cut away with a chance of one less the opacity, so over many samples
+    // half opaque parts let half the rays through
+    Stochastic,
+}
+
+// Number in [0, 1) that is the same every time for the same vectors
+fn hash_to_unit(vectors: &[Vec3]) -> f64 {
+    let h = vectors.iter().fold(0, |h: u64, v| {
+        (0..3).fold(h, |h, a| mix_bits(h ^ v[a].to_bits()))
+    });
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Gives another material holes, for leaves on cards, decals and fences. The
+// brightness of `opacity` is how solid the surface is, and where it is cut
+// away the world carries on looking for a hit behind it, so everything seen
+// through the holes and the light coming through them is right.
+pub struct Cutout {
+    material: Arc<dyn Material>,
+    opacity: Arc<dyn Texture>,
+    mode: AlphaMode,
+}
+
+impl Cutout {
+    pub fn new(material: Arc<dyn Material>, opacity: Arc<dyn Texture>) -> Self {
+        Self {
+            material,
+            opacity,
+            mode: AlphaMode::Threshold(0.5),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: AlphaMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Material for Cutout {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        self.material
+            .scatter(ray_in, rec, attenuation, scattered, sampler)
+    }
+
+    fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Vec3 {
+        self.material.emitted(u, v, p)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.material.albedo(rec)
+    }
+
+    fn cut_away(&self, r: &Ray, rec: &HitRecord) -> bool {
+        let alpha = self.opacity.value(rec.u(), rec.v(), &rec.p()).luminance();
+        let cut = match self.mode {
+            AlphaMode::Threshold(threshold) => alpha < threshold,
+            AlphaMode::Stochastic => hash_to_unit(&[r.origin(), r.direction(), rec.p()]) >= alpha,
+        };
+        cut || self.material.cut_away(r, rec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::close;
+    use crate::rng::Rng;
+    use crate::sampler::{create_sampler, SamplerType};
+
+    fn random_wo(rng: &mut Rng) -> Vec3 {
+        let z = rng.random_double_from_values(-1.0, 1.0);
+        let phi = rng.random_double_from_values(0.0, 2.0 * PI);
+        let r = (1.0 - z * z).sqrt();
+        Vec3::new(z, r * phi.cos(), r * phi.sin())
+    }
+
+    fn pair(rng: &mut Rng) -> (f64, f64) {
+        (rng.random_double(), rng.random_double())
+    }
+
+    #[test]
+    fn test_hair_sample_weights_without_absorption() {
+        // Sampling follows the BSDF exactly when no light is absorbed
+        let mut rng = Rng::new(3);
+        for &(beta_m, beta_n) in [(0.2, 0.3), (0.5, 0.5), (0.9, 0.8)].iter() {
+            let hair = Hair::new(Vec3::empty(), 1.55, beta_m, beta_n, 0.0);
+            for _ in 0..200 {
+                let wo = random_wo(&mut rng);
+                let h = rng.random_double_from_values(-1.0, 1.0);
+                if let Some((_, weight)) = hair.sample(wo, h, pair(&mut rng), pair(&mut rng)) {
+                    assert!(
+                        (weight.luminance() - 1.0).abs() < 1e-3,
+                        "{}",
+                        weight.luminance()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hair_white_furnace() {
+        // Nothing absorbed, so all the light comes back out, here estimated
+        // by sampling the sphere uniformly
+        let mut rng = Rng::new(5);
+        let hair = Hair::new(Vec3::empty(), 1.55, 0.5, 0.5, 2.0);
+        let count = 40000;
+        let mut total = 0.0;
+        for _ in 0..count {
+            let wo = random_wo(&mut rng);
+            let wi = random_wo(&mut rng);
+            let h = rng.random_double_from_values(-1.0, 1.0);
+            total += hair.eval(wo, wi, h).0.luminance() * 4.0 * PI;
+        }
+        let mean = total / count as f64;
+        assert!((mean - 1.0).abs() < 0.05, "{}", mean);
+    }
+
+    #[test]
+    fn test_darker_hair_absorbs_more() {
+        let mut rng = Rng::new(7);
+        let mean_weight = |hair: &Hair, rng: &mut Rng| {
+            let mut total = 0.0;
+            for _ in 0..2000 {
+                let wo = random_wo(rng);
+                let h = rng.random_double_from_values(-1.0, 1.0);
+                if let Some((_, weight)) = hair.sample(wo, h, pair(rng), pair(rng)) {
+                    total += weight.luminance();
+                }
+            }
+            total / 2000.0
+        };
+
+        let blond = mean_weight(&Hair::from_melanin(0.3, 0.0), &mut rng);
+        let black = mean_weight(&Hair::from_melanin(8.0, 0.0), &mut rng);
+        assert!(blond > black);
+        assert!(blond < 1.0 && black > 0.0);
+
+        // The colour fit goes both ways
+        let red = Hair::from_color(Vec3::new(0.6, 0.3, 0.2));
+        let albedo = red.albedo(&HitRecord::empty());
+        assert!((albedo.x() - 0.6).abs() < 1e-9 && (albedo.z() - 0.2).abs() < 1e-9);
+    }
+
+    // Facing +z with u along x and v along y, seen from straight above
+    fn flat_hit() -> (Ray, HitRecord) {
+        let r = Ray::new(&Vec3::new(0.2, 0.3, 1.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let mut rec = HitRecord::new(
+            Vec3::new(0.2, 0.3, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            0.2,
+            0.3,
+            true,
+            None,
+        );
+        rec.set_dpdu(Vec3::new(1.0, 0.0, 0.0));
+        rec.set_dpdv(Vec3::new(0.0, 1.0, 0.0));
+        (r, rec)
+    }
+
+    // Brightness u
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+            Vec3::new(u, u, u)
+        }
+    }
+
+    #[test]
+    fn test_normal_map() {
+        let (r, rec) = flat_hit();
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0)));
+
+        let flat = Bumped::normal_map(
+            white.clone(),
+            Arc::new(SolidColor::new(Vec3::new(0.5, 0.5, 1.0))),
+        );
+        assert!(close(flat.shading_normal(&r, &rec), rec.normal(), 1e-6));
+
+        // Red tilts the normal along dp/du, green along dp/dv
+        let color = Vec3::new(0.75, 0.25, 1.0);
+        let tilted = Bumped::normal_map(white.clone(), Arc::new(SolidColor::new(color)));
+        let expected = Vec3::unit_vector(Vec3::new(0.5, -0.5, 1.0));
+        assert!(close(tilted.shading_normal(&r, &rec), expected, 1e-6));
+        let half = Bumped::normal_map(white, Arc::new(SolidColor::new(color))).with_strength(0.5);
+        let expected = Vec3::unit_vector(Vec3::new(0.25, -0.25, 1.0));
+        assert!(close(half.shading_normal(&r, &rec), expected, 1e-6));
+    }
+
+    #[test]
+    fn test_bump_map_slope() {
+        let (r, rec) = flat_hit();
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0)));
+
+        // Heights rising by 0.5 per unit of u lean the normal back towards -u
+        let bumped = Bumped::bump_map(white, Arc::new(Ramp)).with_strength(0.5);
+        let expected = Vec3::unit_vector(Vec3::new(-0.5, 0.0, 1.0));
+        assert!(close(bumped.shading_normal(&r, &rec), expected, 1e-6));
+
+        // From behind the normal still faces the ray
+        let mut back = rec.clone();
+        back.set_face_normal(
+            &Ray::new(&Vec3::empty(), &Vec3::new(0.0, 0.0, 1.0), 0.0),
+            &rec.normal(),
+        );
+        let from_behind = Ray::new(&Vec3::new(0.2, 0.3, -1.0), &Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert!(close(
+            bumped.shading_normal(&from_behind, &back),
+            -expected,
+            1e-6
+        ));
+    }
+
+    #[test]
+    fn test_bumped_light_does_not_leak() {
+        let (_, rec) = flat_hit();
+        let white: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(1.0, 1.0, 1.0)));
+        let steep = Bumped::normal_map(white, Arc::new(SolidColor::new(Vec3::new(1.0, 0.5, 0.55))));
+
+        // Seen at a grazing angle from the side the normal leans away from
+        let r = Ray::new(
+            &Vec3::new(10.2, 0.3, 0.5),
+            &Vec3::new(-10.0, 0.0, -0.5),
+            0.0,
+        );
+        let shading = steep.shading_normal(&r, &rec);
+        assert!(Vec3::dot(&shading, &-r.direction()) > 0.0);
+
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 9);
+        let mut kept = 0;
+        for _ in 0..1000 {
+            let mut attenuation = Vec3::empty();
+            let mut scattered = Ray::empty();
+            if steep.scatter(&r, &rec, &mut attenuation, &mut scattered, sampler.as_mut()) {
+                assert!(scattered.direction().z() > 0.0);
+                kept += 1;
+            }
+        }
+        assert!(kept > 0 && kept < 1000);
+    }
 }
@@ -0,0 +1,329 @@
+use crate::aabb::AABB;
+use crate::hit::{HitAble, HitRecord};
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+// Triangles per BVH leaf
+const LEAF_SIZE: usize = 4;
+
+// Keeps flat meshes from having boxes with no thickness
+const PAD: f64 = 1e-9;
+
+struct MeshNode {
+    bbox: AABB,
+    // Leaves hold `count` triangles from `start` in the sorted order, inner
+    // nodes have count 0 and their children at `start` and `start + 1`
+    start: usize,
+    count: usize,
+}
+
+// Triangles sharing vertices, all with one id. With a normal per vertex they
+// are shaded smooth, otherwise flat. The triangles get a BVH of their own, so
+// the mesh is one object to the world however many triangles it has.
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    triangles: Vec<[usize; 3]>,
+    nodes: Vec<MeshNode>,
+    id: usize,
+}
+
+impl MeshNode {
+    fn empty() -> Self {
+        Self {
+            bbox: AABB::empty(),
+            start: 0,
+            count: 0,
+        }
+    }
+}
+
+fn triangle_box(positions: &[Vec3], triangle: &[usize; 3]) -> AABB {
+    let mut min = positions[triangle[0]];
+    let mut max = min;
+    for &v in triangle.iter() {
+        for a in 0..3 {
+            min[a] = min[a].min(positions[v][a]);
+            max[a] = max[a].max(positions[v][a]);
+        }
+    }
+    let pad = Vec3::new(PAD, PAD, PAD);
+    AABB::new(min - pad, max + pad)
+}
+
+impl TriangleMesh {
+    // `normals` is either empty or one per position
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        mut triangles: Vec<[usize; 3]>,
+        id: usize,
+    ) -> Self {
+        assert!(normals.is_empty() || normals.len() == positions.len());
+
+        let mut nodes = vec![];
+        if !triangles.is_empty() {
+            let count = triangles.len();
+            nodes.push(MeshNode::empty());
+            TriangleMesh::build(&positions, &mut triangles, 0, count, &mut nodes, 0);
+        }
+
+        Self {
+            positions,
+            normals,
+            triangles,
+            nodes,
+            id,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    // Fills node `index` for the triangles from start to end, splitting them
+    // in the middle of the longest axis of their centres
+    fn build(
+        positions: &[Vec3],
+        triangles: &mut [[usize; 3]],
+        start: usize,
+        end: usize,
+        nodes: &mut Vec<MeshNode>,
+        index: usize,
+    ) {
+        let bbox = triangles[start..end]
+            .iter()
+            .map(|t| triangle_box(positions, t))
+            .reduce(AABB::surrounding_box)
+            .unwrap();
+        if end - start <= LEAF_SIZE {
+            nodes[index] = MeshNode {
+                bbox,
+                start,
+                count: end - start,
+            };
+            return;
+        }
+
+        let centre = |t: &[usize; 3]| (positions[t[0]] + positions[t[1]] + positions[t[2]]) / 3.0;
+        let extent = *bbox.max() - *bbox.min();
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+            0
+        } else if extent.y() > extent.z() {
+            1
+        } else {
+            2
+        };
+        triangles[start..end].sort_by(|a, b| {
+            centre(a)[axis]
+                .partial_cmp(&centre(b)[axis])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // Children go next to each other so the node only needs the first
+        let children = nodes.len();
+        nodes.push(MeshNode::empty());
+        nodes.push(MeshNode::empty());
+        nodes[index] = MeshNode {
+            bbox,
+            start: children,
+            count: 0,
+        };
+
+        let mid = (start + end) / 2;
+        TriangleMesh::build(positions, triangles, start, mid, nodes, children);
+        TriangleMesh::build(positions, triangles, mid, end, nodes, children + 1);
+    }
+
+    fn triangle_hit(
+        &self,
+        triangle: &[usize; 3],
+        r: &Ray,
+        t_min: f64,
+        closest: &mut f64,
+        rec: &mut HitRecord,
+    ) -> bool {
+        let [p0, p1, p2] = [
+            self.positions[triangle[0]],
+            self.positions[triangle[1]],
+            self.positions[triangle[2]],
+        ];
+        let (t, beta, gamma) = match triangle_intersect(r, p0, p1, p2, t_min, *closest) {
+            Some(found) => found,
+            None => return false,
+        };
+        let (e1, e2) = (p1 - p0, p2 - p0);
+
+        let outward_normal = if self.normals.is_empty() {
+            Vec3::unit_vector(Vec3::cross(&e1, &e2))
+        } else {
+            let n = |i: usize| self.normals[triangle[i]];
+            Vec3::unit_vector(n(0) * (1.0 - beta - gamma) + n(1) * beta + n(2) * gamma)
+        };
+
+        *closest = t;
+        rec.set_t(t);
+        rec.set_p(r.at(t));
+        rec.set_face_normal(r, &outward_normal);
+        rec.set_uv((beta, gamma));
+        rec.set_dpdu(e1);
+        rec.set_dpdv(e2);
+        rec.set_id(Some(self.id));
+        true
+    }
+}
+
+// Moller-Trumbore. The t of a hit strictly inside (t_min, t_max), with the
+// barycentric weights of p1 and p2 at it
+pub fn triangle_intersect(
+    r: &Ray,
+    p0: Vec3,
+    p1: Vec3,
+    p2: Vec3,
+    t_min: f64,
+    t_max: f64,
+) -> Option<(f64, f64, f64)> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let pvec = Vec3::cross(&r.direction(), &e2);
+    let det = Vec3::dot(&e1, &pvec);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let tvec = r.origin() - p0;
+    let beta = Vec3::dot(&tvec, &pvec) / det;
+    if !(0.0..=1.0).contains(&beta) {
+        return None;
+    }
+    let qvec = Vec3::cross(&tvec, &e1);
+    let gamma = Vec3::dot(&r.direction(), &qvec) / det;
+    if gamma < 0.0 || beta + gamma > 1.0 {
+        return None;
+    }
+    let t = Vec3::dot(&e2, &qvec) / det;
+    if t <= t_min || t >= t_max {
+        return None;
+    }
+    Some((t, beta, gamma))
+}
+
+impl HitAble for TriangleMesh {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut closest = t_max;
+        let mut hit_anything = false;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bbox.hit(r, t_min, closest) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.start);
+                stack.push(node.start + 1);
+                continue;
+            }
+            for triangle in self.triangles[node.start..node.start + node.count].iter() {
+                if self.triangle_hit(triangle, r, t_min, &mut closest, rec) {
+                    hit_anything = true;
+                }
+            }
+        }
+        hit_anything
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        self.nodes.first().map(|node| node.bbox)
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::hit;
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_flat_and_smooth_triangle() {
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ];
+        let flat = TriangleMesh::new(positions.clone(), vec![], vec![[0, 1, 2]], 3);
+
+        let rec = hit(&flat, Vec3::new(0.25, 0.25, 2.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!((rec.t() - 2.0).abs() < 1e-12);
+        assert!((rec.normal().z() - 1.0).abs() < 1e-12);
+        assert_eq!(rec.id(), Some(3));
+        assert!(hit(&flat, Vec3::new(0.75, 0.75, 2.0), Vec3::new(0.0, 0.0, -1.0)).is_none());
+
+        let tilted = Vec3::unit_vector(Vec3::new(1.0, 0.0, 1.0));
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let smooth = TriangleMesh::new(positions, vec![up, tilted, up], vec![[0, 1, 2]], 0);
+        let rec = hit(
+            &smooth,
+            Vec3::new(0.5, 0.25, 2.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        )
+        .unwrap();
+        assert!(rec.normal().x() > 0.3 && rec.normal().x() < tilted.x());
+    }
+
+    #[test]
+    fn test_bvh_matches_every_triangle() {
+        // A bumpy grid of 2 * 30 * 30 triangles
+        let n = 31;
+        let positions: Vec<Vec3> = (0..n * n)
+            .map(|i| {
+                let (x, z) = ((i % n) as f64, (i / n) as f64);
+                Vec3::new(x, (x * 0.7).sin() * (z * 0.4).cos(), z)
+            })
+            .collect();
+        let mut triangles = vec![];
+        for z in 0..n - 1 {
+            for x in 0..n - 1 {
+                let i = z * n + x;
+                triangles.push([i, i + 1, i + n + 1]);
+                triangles.push([i, i + n + 1, i + n]);
+            }
+        }
+        let mesh = TriangleMesh::new(positions, vec![], triangles, 0);
+        assert_eq!(mesh.triangle_count(), 1800);
+
+        let mut rng = Rng::new(11);
+        for _ in 0..300 {
+            let origin = Vec3::new(
+                rng.random_double_from_values(-5.0, 35.0),
+                rng.random_double_from_values(2.0, 5.0),
+                rng.random_double_from_values(-5.0, 35.0),
+            );
+            let target = Vec3::new(
+                rng.random_double_from_values(0.0, 30.0),
+                rng.random_double_from_values(-1.0, 1.0),
+                rng.random_double_from_values(0.0, 30.0),
+            );
+            let r = Ray::new(&origin, &(target - origin), 0.0);
+
+            let mut closest = f64::INFINITY;
+            let mut expected = HitRecord::empty();
+            for triangle in mesh.triangles.iter() {
+                mesh.triangle_hit(triangle, &r, 0.001, &mut closest, &mut expected);
+            }
+
+            let mut rec = HitRecord::empty();
+            let found = mesh.hit(&r, 0.001, f64::INFINITY, &mut rec);
+            assert_eq!(found, closest.is_finite());
+            if found {
+                assert!((rec.t() - closest).abs() < 1e-12);
+            }
+        }
+    }
+}
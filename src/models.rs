@@ -1,6 +1,7 @@
-use crate::{aabb::{self, AABB}, world::World};
+use crate::{aabb::AABB, world::World};
 use crate::hit::{HitAble, HitRecord};
 use crate::material::Material;
+use crate::quad::Quad;
 use crate::ray::Ray;
 use crate::vec3::Vec3;
 use std::rc::Rc;
@@ -37,6 +38,17 @@ impl Sphere {
 
         (u, v)
     }
+
+    // dp/du and dp/dv for the UVs above, at the point with unit normal `n`.
+    // u goes around from -x through +z, v from the bottom to the top.
+    pub fn get_sphere_tangents(n: &Vec3, radius: f64) -> (Vec3, Vec3) {
+        let dpdu = Vec3::new(n.z(), 0.0, -n.x()) * (2.0 * std::f64::consts::PI * radius);
+        // Distance from the y axis, which the poles are on
+        let ring = (n.x() * n.x() + n.z() * n.z()).sqrt().max(1e-12);
+        let dpdv = Vec3::new(-n.x() * n.y() / ring, ring, -n.z() * n.y() / ring)
+            * (std::f64::consts::PI * radius);
+        (dpdu, dpdv)
+    }
 }
 
 impl HitAble for Sphere {
@@ -58,6 +70,9 @@ impl HitAble for Sphere {
                 let outward_normal = (rec.p() - self.center) / self.radius;
                 rec.set_face_normal(r, &outward_normal);
                 rec.set_uv(Sphere::get_sphere_uv(&outward_normal, rec.u(), rec.v()));
+                let (dpdu, dpdv) = Sphere::get_sphere_tangents(&outward_normal, self.radius);
+                rec.set_dpdu(dpdu);
+                rec.set_dpdv(dpdv);
                 rec.set_id(Some(self.id));
                 return true;
             }
@@ -70,6 +85,9 @@ impl HitAble for Sphere {
                 let outward_normal = (rec.p() - self.center) / self.radius;
                 rec.set_face_normal(r, &outward_normal);
                 rec.set_uv(Sphere::get_sphere_uv(&outward_normal, rec.u(), rec.v()));
+                let (dpdu, dpdv) = Sphere::get_sphere_tangents(&outward_normal, self.radius);
+                rec.set_dpdu(dpdu);
+                rec.set_dpdv(dpdv);
                 rec.set_id(Some(self.id));
                 return true;
             }
@@ -141,6 +159,10 @@ impl HitAble for MovingSphere {
                 rec.set_p(r.at(rec.t()));
                 let outward_normal = (rec.p() - self.center(r.time())) / self.radius;
                 rec.set_face_normal(r, &outward_normal);
+                rec.set_uv(Sphere::get_sphere_uv(&outward_normal, rec.u(), rec.v()));
+                let (dpdu, dpdv) = Sphere::get_sphere_tangents(&outward_normal, self.radius);
+                rec.set_dpdu(dpdu);
+                rec.set_dpdv(dpdv);
                 rec.set_id(Some(self.id));
                 return true;
             }
@@ -152,6 +174,10 @@ impl HitAble for MovingSphere {
                 rec.set_p(r.at(rec.t()));
                 let outward_normal = (rec.p() - self.center(r.time())) / self.radius;
                 rec.set_face_normal(r, &outward_normal);
+                rec.set_uv(Sphere::get_sphere_uv(&outward_normal, rec.u(), rec.v()));
+                let (dpdu, dpdv) = Sphere::get_sphere_tangents(&outward_normal, self.radius);
+                rec.set_dpdu(dpdu);
+                rec.set_dpdv(dpdv);
                 rec.set_id(Some(self.id));
                 return true;
             }
@@ -177,196 +203,6 @@ impl HitAble for MovingSphere {
     }
 }
 
-pub struct XYRect {
-    x0: f64,
-    x1: f64,
-    y0: f64,
-    y1: f64,
-    k: f64,
-    id: usize,
-}
-
-impl XYRect {
-    pub fn new(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, id: usize) -> XYRect {
-        XYRect {
-            x0,
-            x1,
-            y0,
-            y1,
-            k,
-            id,
-        }
-    }
-}
-
-impl HitAble for XYRect {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
-        let t = (self.k - r.origin().z()) / r.direction().z();
-
-        if t < t_min || t > t_max {
-            return false;
-        }
-
-        let x = r.origin().x() + t * r.direction().x();
-        let y = r.origin().y() + t * r.direction().y();
-
-        if x < self.x0 || x > self.x1 || y < self.y0 || y > self.y1 {
-            return false;
-        }
-
-        rec.set_u((x - self.x0) / (self.x1 - self.x0));
-        rec.set_u((y - self.y0) / (self.y1 - self.y0));
-
-        rec.set_t(t);
-
-        let outward_normal = Vec3::new(0.0, 0.0, 1.0);
-
-        rec.set_face_normal(r, &outward_normal);
-        rec.set_p(r.at(t));
-        rec.set_id(Some(self.id));
-
-        true
-    }
-
-    fn bounding_box(&self, time0: f64, time1: f64) -> Option<AABB> {
-        Some(aabb::AABB::new(
-            Vec3::new(self.x0, self.y0, self.k - 0.0001),
-            Vec3::new(self.x1, self.y1, self.k + 0.0001),
-        ))
-    }
-
-    fn id(&self) -> Option<usize> {
-        Some(self.id)
-    }
-}
-
-pub struct XZRect {
-    x0: f64,
-    x1: f64,
-    z0: f64,
-    z1: f64,
-    k: f64,
-    id: usize,
-}
-
-impl XZRect {
-    pub fn new(x0: f64, x1: f64, z0: f64, z1: f64, k: f64, id: usize) -> XZRect {
-        XZRect {
-            x0,
-            x1,
-            z0,
-            z1,
-            k,
-            id,
-        }
-    }
-}
-
-impl HitAble for XZRect {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
-        let t = (self.k - r.origin().y()) / r.direction().y();
-
-        if t < t_min || t > t_max {
-            return false;
-        }
-
-        let x = r.origin().x() + t * r.direction().x();
-        let z = r.origin().z() + t * r.direction().z();
-
-        if x < self.x0 || x > self.x1 || z < self.z0 || z > self.z1 {
-            return false;
-        }
-
-        rec.set_u((x - self.x0) / (self.x1 - self.x0));
-        rec.set_u((z - self.z0) / (self.z1 - self.z0));
-
-        rec.set_t(t);
-
-        let outward_normal = Vec3::new(0.0, 1.0, 0.0);
-
-        rec.set_face_normal(r, &outward_normal);
-        rec.set_p(r.at(t));
-        rec.set_id(Some(self.id));
-
-        true
-    }
-
-    fn bounding_box(&self, time0: f64, time1: f64) -> Option<AABB> {
-        Some(aabb::AABB::new(
-            Vec3::new(self.x0, self.z0, self.k - 0.0001),
-            Vec3::new(self.x1, self.z1, self.k + 0.0001),
-        ))
-    }
-
-    fn id(&self) -> Option<usize> {
-        Some(self.id)
-    }
-}
-
-
-pub struct YZRect {
-    y0: f64,
-    y1: f64,
-    z0: f64,
-    z1: f64,
-    k: f64,
-    id: usize,
-}
-
-impl YZRect {
-    pub fn new(y0: f64, y1: f64, z0: f64, z1: f64, k: f64, id: usize) -> YZRect {
-        YZRect {
-            y0,
-            y1,
-            z0,
-            z1,
-            k,
-            id,
-        }
-    }
-}
-
-impl HitAble for YZRect {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
-        let t = (self.k - r.origin().x()) / r.direction().x();
-
-        if t < t_min || t > t_max {
-            return false;
-        }
-
-        let y = r.origin().y() + t * r.direction().y();
-        let z = r.origin().z() + t * r.direction().z();
-
-        if y < self.y0 || y > self.y1 || z < self.z0 || z > self.z1 {
-            return false;
-        }
-
-        rec.set_u((y - self.y0) / (self.y1 - self.y0));
-        rec.set_u((z - self.z0) / (self.z1 - self.z0));
-
-        rec.set_t(t);
-
-        let outward_normal = Vec3::new(1.0, 0.0, 0.0);
-
-        rec.set_face_normal(r, &outward_normal);
-        rec.set_p(r.at(t));
-        rec.set_id(Some(self.id));
-
-        true
-    }
-
-    fn bounding_box(&self, time0: f64, time1: f64) -> Option<AABB> {
-        Some(aabb::AABB::new(
-            Vec3::new(self.y0, self.z0, self.k - 0.0001),
-            Vec3::new(self.y1, self.z1, self.k + 0.0001),
-        ))
-    }
-
-    fn id(&self) -> Option<usize> {
-        Some(self.id)
-    }
-}
-
 pub struct Box3D {
     id: usize,
     box_min: Vec3,
@@ -375,17 +211,20 @@ pub struct Box3D {
 }
 
 impl Box3D {
-    pub fn new(p0: Vec3, p1: Vec3, id: usize) -> Self{
-        let mut sides: Vec<Box<dyn HitAble>>  = vec![];
-
-        sides.push(Box::new(XYRect::new(p0.x(), p1.x(), p0.y(), p1.y(), p1.z(), id)));
-        sides.push(Box::new(XYRect::new(p0.x(), p1.x(), p0.y(), p1.y(), p0.z(), id)));
-
-        sides.push(Box::new(XZRect::new(p0.x(), p1.x(), p0.z(), p1.z(), p1.y(), id)));
-        sides.push(Box::new(XZRect::new(p0.x(), p1.x(), p0.z(), p1.z(), p0.y(), id)));
-
-        sides.push(Box::new(YZRect::new(p0.y(), p1.y(), p0.z(), p1.z(), p1.x(), id)));
-        sides.push(Box::new(YZRect::new(p0.y(), p1.y(), p0.z(), p1.z(), p0.x(), id)));
+    // Six quads facing out
+    pub fn new(p0: Vec3, p1: Vec3, id: usize) -> Self {
+        let dx = Vec3::new(p1.x() - p0.x(), 0.0, 0.0);
+        let dy = Vec3::new(0.0, p1.y() - p0.y(), 0.0);
+        let dz = Vec3::new(0.0, 0.0, p1.z() - p0.z());
+
+        let sides: Vec<Box<dyn HitAble>> = vec![
+            Box::new(Quad::new(Vec3::new(p0.x(), p0.y(), p1.z()), dx, dy, id)),
+            Box::new(Quad::new(Vec3::new(p1.x(), p0.y(), p1.z()), -dz, dy, id)),
+            Box::new(Quad::new(Vec3::new(p1.x(), p0.y(), p0.z()), -dx, dy, id)),
+            Box::new(Quad::new(Vec3::new(p0.x(), p0.y(), p0.z()), dz, dy, id)),
+            Box::new(Quad::new(Vec3::new(p0.x(), p1.y(), p1.z()), dx, -dz, id)),
+            Box::new(Quad::new(Vec3::new(p0.x(), p0.y(), p0.z()), dx, dz, id)),
+        ];
 
         Box3D {
             id,
@@ -0,0 +1,404 @@
+use crate::aabb::AABB;
+use crate::hit::{HitAble, HitRecord};
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+// Unit quaternion for rotations. Rotations are interpolated on the sphere of
+// these so the object turns at a steady rate between keys.
+#[derive(Copy, Clone)]
+pub struct Quaternion {
+    w: f64,
+    v: Vec3,
+}
+
+impl Quaternion {
+    pub fn identity() -> Self {
+        Self {
+            w: 1.0,
+            v: Vec3::empty(),
+        }
+    }
+
+    // Turn of `degrees` around `axis`, counter clockwise looking down the axis
+    pub fn from_axis_angle(axis: Vec3, degrees: f64) -> Self {
+        let half = degrees.to_radians() / 2.0;
+        Self {
+            w: half.cos(),
+            v: Vec3::unit_vector(axis) * half.sin(),
+        }
+    }
+
+    fn dot(&self, other: &Quaternion) -> f64 {
+        self.w * other.w + Vec3::dot(&self.v, &other.v)
+    }
+
+    fn conjugate(&self) -> Self {
+        Self {
+            w: self.w,
+            v: -self.v,
+        }
+    }
+
+    pub fn rotate(&self, p: Vec3) -> Vec3 {
+        let t = Vec3::cross(&self.v, &p) * 2.0;
+        p + t * self.w + Vec3::cross(&self.v, &t)
+    }
+
+    pub fn slerp(&self, other: &Quaternion, t: f64) -> Self {
+        // q and -q are the same rotation, take the short way round
+        let mut cos = self.dot(other);
+        let other = if cos < 0.0 {
+            cos = -cos;
+            Quaternion {
+                w: -other.w,
+                v: -other.v,
+            }
+        } else {
+            *other
+        };
+
+        let (a, b) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+
+        let w = self.w * a + other.w * b;
+        let v = self.v * a + other.v * b;
+        let length = (w * w + v.length_squared()).sqrt();
+        Self {
+            w: w / length,
+            v: v / length,
+        }
+    }
+}
+
+// Scale, then rotate, then translate
+#[derive(Copy, Clone)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            translation: Vec3::empty(),
+            rotation: Quaternion::identity(),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        Self {
+            translation: offset,
+            ..self
+        }
+    }
+
+    pub fn rotate(self, axis: Vec3, degrees: f64) -> Self {
+        Self {
+            rotation: Quaternion::from_axis_angle(axis, degrees),
+            ..self
+        }
+    }
+
+    pub fn scale(self, scale: Vec3) -> Self {
+        Self { scale, ..self }
+    }
+
+    pub fn lerp(&self, other: &Transform, t: f64) -> Self {
+        Self {
+            translation: self.translation * (1.0 - t) + other.translation * t,
+            rotation: self.rotation.slerp(&other.rotation, t),
+            scale: self.scale * (1.0 - t) + other.scale * t,
+        }
+    }
+
+    pub fn apply_point(&self, p: Vec3) -> Vec3 {
+        self.rotation.rotate(p * self.scale) + self.translation
+    }
+
+    fn apply_vector(&self, v: Vec3) -> Vec3 {
+        self.rotation.rotate(v * self.scale)
+    }
+
+    // Normals take the inverse transpose, which undoes the scale instead
+    fn apply_normal(&self, n: Vec3) -> Vec3 {
+        Vec3::unit_vector(self.rotation.rotate(n / self.scale))
+    }
+
+    fn invert_point(&self, p: Vec3) -> Vec3 {
+        self.rotation.conjugate().rotate(p - self.translation) / self.scale
+    }
+
+    fn invert_vector(&self, v: Vec3) -> Vec3 {
+        self.rotation.conjugate().rotate(v) / self.scale
+    }
+}
+
+// Steps per pair of keys used to find the swept bounding box
+const BOUNDS_STEPS: usize = 32;
+
+// Moves an object along keyframed transforms. Times between keys interpolate
+// the two around them, times outside the keys hold the first or last one.
+// Unlike MovingSphere this works for anything hittable and for rotation and
+// scale as well as translation.
+pub struct MotionTransform {
+    object: Box<dyn HitAble>,
+    keys: Vec<(f64, Transform)>,
+}
+
+impl MotionTransform {
+    // Keys are sorted by time, there has to be at least one
+    pub fn new(object: Box<dyn HitAble>, mut keys: Vec<(f64, Transform)>) -> Self {
+        assert!(!keys.is_empty(), "a motion transform needs a key");
+        keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        Self { object, keys }
+    }
+
+    pub fn transform(&self, time: f64) -> Transform {
+        let first = &self.keys[0];
+        let last = &self.keys[self.keys.len() - 1];
+        if time <= first.0 {
+            return first.1;
+        }
+        if time >= last.0 {
+            return last.1;
+        }
+
+        let i = self.keys.iter().position(|k| k.0 > time).unwrap();
+        let (t0, a) = &self.keys[i - 1];
+        let (t1, b) = &self.keys[i];
+        a.lerp(b, (time - t0) / (t1 - t0))
+    }
+
+    // Times the transform is looked at to bound the sweep: the ends, every key
+    // in between and evenly spaced steps between those
+    fn sample_times(&self, time0: f64, time1: f64) -> Vec<f64> {
+        let mut stops = vec![time0];
+        stops.extend(
+            self.keys
+                .iter()
+                .map(|k| k.0)
+                .filter(|t| *t > time0 && *t < time1),
+        );
+        stops.push(time1);
+
+        let mut times = vec![];
+        for pair in stops.windows(2) {
+            for step in 0..BOUNDS_STEPS {
+                times.push(pair[0] + (pair[1] - pair[0]) * step as f64 / BOUNDS_STEPS as f64);
+            }
+        }
+        times.push(time1);
+        times
+    }
+}
+
+impl HitAble for MotionTransform {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let transform = self.transform(r.time());
+
+        // The direction is not normalised, so t is the same in both spaces
+        let local = Ray::new(
+            &transform.invert_point(r.origin()),
+            &transform.invert_vector(r.direction()),
+            r.time(),
+        );
+        if !self.object.hit(&local, t_min, t_max, rec) {
+            return false;
+        }
+
+        // The normal already faces the ray and keeps doing so after the
+        // transform, which also keeps front_face as the object set it
+        rec.set_p(transform.apply_point(rec.p()));
+        rec.set_normal(transform.apply_normal(rec.normal()));
+        rec.set_dpdu(transform.apply_vector(rec.dpdu()));
+        rec.set_dpdv(transform.apply_vector(rec.dpdv()));
+        true
+    }
+
+    // Corners of the object's box along the sweep. Between the sampled times a
+    // rotating corner moves on an arc, so the box grows by the longest step
+    // any corner takes, which covers how far such an arc bulges out.
+    fn bounding_box(&self, time0: f64, time1: f64) -> Option<AABB> {
+        let object_box = self.object.bounding_box(time0, time1)?;
+        let (lo, hi) = (*object_box.min(), *object_box.max());
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                Vec3::new(
+                    if i & 1 == 0 { lo.x() } else { hi.x() },
+                    if i & 2 == 0 { lo.y() } else { hi.y() },
+                    if i & 4 == 0 { lo.z() } else { hi.z() },
+                )
+            })
+            .collect();
+
+        let mut min = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = -min;
+        let mut pad: f64 = 0.0;
+        let mut previous: Option<Vec<Vec3>> = None;
+
+        for time in self.sample_times(time0, time1) {
+            let transform = self.transform(time);
+            let moved: Vec<Vec3> = corners.iter().map(|c| transform.apply_point(*c)).collect();
+
+            for p in moved.iter() {
+                for a in 0..3 {
+                    min[a] = min[a].min(p[a]);
+                    max[a] = max[a].max(p[a]);
+                }
+            }
+            if let Some(previous) = previous {
+                for (p, q) in moved.iter().zip(previous.iter()) {
+                    pad = pad.max((*p - *q).length());
+                }
+            }
+            previous = Some(moved);
+        }
+
+        let pad = Vec3::new(pad, pad, pad);
+        Some(AABB::new(min - pad, max + pad))
+    }
+
+    fn id(&self) -> Option<usize> {
+        self.object.id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit_ray};
+    use crate::models::Sphere;
+
+    fn sphere() -> Box<dyn HitAble> {
+        Box::new(Sphere::new(Vec3::new(1.0, 0.0, 0.0), 0.5, 0))
+    }
+
+    #[test]
+    fn test_quaternion_rotation() {
+        let q = Quaternion::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 90.0);
+        assert!(close(
+            q.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            Vec3::new(0.0, 0.0, -1.0),
+            1e-9
+        ));
+
+        // Half way between 0 and 90 degrees is 45 degrees
+        let half = Quaternion::identity().slerp(&q, 0.5);
+        let expected = Quaternion::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 45.0);
+        assert!(close(
+            half.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            expected.rotate(Vec3::new(1.0, 0.0, 0.0)),
+            1e-9
+        ));
+    }
+
+    #[test]
+    fn test_keys_interpolate_and_hold() {
+        let motion = MotionTransform::new(
+            sphere(),
+            vec![
+                (
+                    1.0,
+                    Transform::identity().translate(Vec3::new(0.0, 2.0, 0.0)),
+                ),
+                (0.0, Transform::identity()),
+                (2.0, Transform::identity().scale(Vec3::new(2.0, 2.0, 2.0))),
+            ],
+        );
+
+        assert!(close(
+            motion.transform(-1.0).translation,
+            Vec3::empty(),
+            1e-9
+        ));
+        assert!(close(
+            motion.transform(0.5).translation,
+            Vec3::new(0.0, 1.0, 0.0),
+            1e-9
+        ));
+        assert!(close(
+            motion.transform(1.5).scale,
+            Vec3::new(1.5, 1.5, 1.5),
+            1e-9
+        ));
+        assert!(close(
+            motion.transform(3.0).scale,
+            Vec3::new(2.0, 2.0, 2.0),
+            1e-9
+        ));
+    }
+
+    #[test]
+    fn test_hit_follows_the_object_in_time() {
+        let motion = MotionTransform::new(
+            sphere(),
+            vec![
+                (0.0, Transform::identity()),
+                (
+                    1.0,
+                    Transform::identity().translate(Vec3::new(0.0, 3.0, 0.0)),
+                ),
+            ],
+        );
+        let down = Vec3::new(0.0, 0.0, -1.0);
+
+        let at = |time: f64| Ray::new(&Vec3::new(1.0, 3.0, 5.0), &down, time);
+        assert!(hit_ray(&motion, &at(0.0)).is_none());
+        let rec = hit_ray(&motion, &at(1.0)).unwrap();
+        assert!(close(rec.p(), Vec3::new(1.0, 3.0, 0.5), 1e-9));
+        assert!(close(rec.normal(), Vec3::new(0.0, 0.0, 1.0), 1e-9));
+        assert!(rec.front_face());
+    }
+
+    #[test]
+    fn test_scaled_normals_stay_perpendicular() {
+        // Squashed to a disc in y, the top is flat and the normal points up
+        let motion = MotionTransform::new(
+            sphere(),
+            vec![(0.0, Transform::identity().scale(Vec3::new(1.0, 0.2, 1.0)))],
+        );
+        let r = Ray::new(&Vec3::new(1.0, 5.0, 0.0), &Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let rec = hit_ray(&motion, &r).unwrap();
+
+        assert!((rec.t() - 4.9).abs() < 1e-9);
+        assert!(close(rec.normal(), Vec3::new(0.0, 1.0, 0.0), 1e-9));
+    }
+
+    #[test]
+    fn test_swept_box_contains_the_whole_rotation() {
+        let motion = MotionTransform::new(
+            sphere(),
+            vec![
+                (0.0, Transform::identity()),
+                (
+                    1.0,
+                    Transform::identity().rotate(Vec3::new(0.0, 1.0, 0.0), 180.0),
+                ),
+            ],
+        );
+        let bbox = motion.bounding_box(0.0, 1.0).unwrap();
+
+        // Half way round the sphere is at z = -1, which neither end key shows
+        for i in 0..=100 {
+            let centre = motion
+                .transform(i as f64 / 100.0)
+                .apply_point(Vec3::new(1.0, 0.0, 0.0));
+            for a in 0..3 {
+                assert!(centre[a] - 0.5 >= bbox.min()[a]);
+                assert!(centre[a] + 0.5 <= bbox.max()[a]);
+            }
+        }
+        assert!(bbox.min().z() <= -1.5);
+
+        // Only the part of the sweep inside the shutter counts
+        let early = motion.bounding_box(0.0, 0.1).unwrap();
+        assert!(early.min().x() > 0.0);
+    }
+}
@@ -0,0 +1,372 @@
+use std::f64::consts::PI;
+
+use crate::camera::{basis, CameraModel};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::shutter::Shutter;
+use crate::vec3::Vec3;
+
+// Panoramic cameras. They all sit at lookfrom, have no depth of field and look
+// along -w at the centre of the image (or of the front face for the cube map).
+
+// Full 360 x 180 degree latitude / longitude panorama, meant for a 2:1 image.
+// s = 0 and s = 1 meet behind the camera.
+pub struct EquirectangularCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    shutter: Shutter,
+}
+
+impl EquirectangularCamera {
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, time0: f64, time1: f64) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            shutter: Shutter::new(time0, time1),
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    // Direction in camera space: x right, y up, z backwards
+    pub fn direction(s: f64, t: f64) -> Vec3 {
+        let phi = (s - 0.5) * 2.0 * PI;
+        let theta = (t - 0.5) * PI;
+        Vec3::new(
+            theta.cos() * phi.sin(),
+            theta.sin(),
+            -theta.cos() * phi.cos(),
+        )
+    }
+}
+
+impl CameraModel for EquirectangularCamera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let d = EquirectangularCamera::direction(s, t);
+        let direction = self.u * d.x() + self.v * d.y() + self.w * d.z();
+        Some(Ray::new(
+            &self.origin,
+            &direction,
+            self.shutter.sample(sampler),
+        ))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FisheyeMapping {
+    // Distance from the centre grows linearly with the angle
+    Equidistant,
+    // Equal areas on the image cover equal solid angles
+    Equisolid,
+}
+
+// The image circle touches the top and bottom of the image and spans 180
+// degrees across its diameter, or what `with_fov` sets. Equisolid goes up to
+// 360 degrees.
+pub struct FisheyeCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    mapping: FisheyeMapping,
+    half_fov: f64,
+    aspect_ratio: f64,
+    shutter: Shutter,
+}
+
+impl FisheyeCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        mapping: FisheyeMapping,
+        aspect_ratio: f64,
+        time0: f64,
+        time1: f64,
+    ) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            mapping,
+            half_fov: PI / 2.0,
+            aspect_ratio,
+            shutter: Shutter::new(time0, time1),
+        }
+    }
+
+    // In degrees
+    pub fn with_fov(mut self, fov: f64) -> Self {
+        self.half_fov = fov.to_radians() / 2.0;
+        self
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    // Position on the image circle, which has a radius of 1
+    fn circle_position(&self, s: f64, t: f64) -> (f64, f64) {
+        ((2.0 * s - 1.0) * self.aspect_ratio, 2.0 * t - 1.0)
+    }
+
+    // Angle away from the view direction for a distance r from the centre
+    fn angle(&self, r: f64) -> f64 {
+        match self.mapping {
+            FisheyeMapping::Equidistant => r * self.half_fov,
+            FisheyeMapping::Equisolid => 2.0 * (r * (self.half_fov / 2.0).sin()).asin(),
+        }
+    }
+}
+
+impl CameraModel for FisheyeCamera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (x, y) = self.circle_position(s, t);
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = self.angle(r);
+
+        let sideways = if r > 0.0 {
+            (self.u * x + self.v * y) / r
+        } else {
+            Vec3::empty()
+        };
+        let direction = sideways * theta.sin() - self.w * theta.cos();
+
+        Some(Ray::new(
+            &self.origin,
+            &direction,
+            self.shutter.sample(sampler),
+        ))
+    }
+}
+
+// Six 90 degree faces laid out in a 3x2 grid, meant for a 3:2 image:
+//
+//     front  right  back
+//     left   up     down
+//
+// Front, right, back and left turn around vup. Up and down are seen by tilting
+// the head from the front face, so the top of the up face points backwards.
+pub struct CubeMapCamera {
+    origin: Vec3,
+    // forward, right and up of every face, in the order of the layout
+    faces: [(Vec3, Vec3, Vec3); 6],
+    shutter: Shutter,
+}
+
+impl CubeMapCamera {
+    pub fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, time0: f64, time1: f64) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            faces: [
+                (-w, u, v),
+                (u, w, v),
+                (w, -u, v),
+                (-u, -w, v),
+                (v, u, w),
+                (-v, u, -w),
+            ],
+            shutter: Shutter::new(time0, time1),
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    // Face index and the position on that face, from -1 to 1 both ways
+    fn face(s: f64, t: f64) -> (usize, f64, f64) {
+        let column = ((s * 3.0) as usize).min(2);
+        let row = (((1.0 - t) * 2.0) as usize).min(1);
+        let a = (s * 3.0 - column as f64) * 2.0 - 1.0;
+        let b = (t * 2.0 - (1 - row) as f64) * 2.0 - 1.0;
+        (row * 3 + column, a, b)
+    }
+}
+
+impl CameraModel for CubeMapCamera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (index, a, b) = CubeMapCamera::face(s, t);
+        let (forward, right, up) = self.faces[index];
+        let direction = forward + right * a + up * b;
+
+        Some(Ray::new(
+            &self.origin,
+            &direction,
+            self.shutter.sample(sampler),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::close;
+    use crate::sampler::{create_sampler, SamplerType};
+
+    fn lookfrom() -> Vec3 {
+        Vec3::new(0.0, 0.0, 5.0)
+    }
+
+    fn unit_direction(cam: &dyn CameraModel, s: f64, t: f64) -> Vec3 {
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+        Vec3::unit_vector(cam.get_ray(s, t, sampler.as_mut()).unwrap().direction())
+    }
+
+    #[test]
+    fn test_equirectangular_directions() {
+        let cam = EquirectangularCamera::new(
+            lookfrom(),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            1.0,
+        );
+
+        assert!(close(
+            unit_direction(&cam, 0.5, 0.5),
+            Vec3::new(0.0, 0.0, -1.0),
+            1e-9
+        ));
+        assert!(close(
+            unit_direction(&cam, 0.75, 0.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            1e-9
+        ));
+        assert!(close(
+            unit_direction(&cam, 0.0, 0.5),
+            Vec3::new(0.0, 0.0, 1.0),
+            1e-9
+        ));
+        assert!(close(
+            unit_direction(&cam, 0.3, 1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            1e-9
+        ));
+    }
+
+    #[test]
+    fn test_fisheye_edge_is_half_fov() {
+        for mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid].iter() {
+            let cam = FisheyeCamera::new(
+                lookfrom(),
+                Vec3::empty(),
+                Vec3::new(0.0, 1.0, 0.0),
+                *mapping,
+                1.0,
+                0.0,
+                1.0,
+            );
+
+            assert!(close(
+                unit_direction(&cam, 0.5, 0.5),
+                Vec3::new(0.0, 0.0, -1.0),
+                1e-9
+            ));
+            assert!(close(
+                unit_direction(&cam, 1.0, 0.5),
+                Vec3::new(1.0, 0.0, 0.0),
+                1e-9
+            ));
+            assert!(close(
+                unit_direction(&cam, 0.5, 1.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                1e-9
+            ));
+            let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+            assert!(cam.get_ray(0.5, 0.0, sampler.as_mut()).is_some());
+            assert!(cam.get_ray(0.0, 0.0, sampler.as_mut()).is_none());
+
+            let narrow = cam.with_fov(90.0);
+            let edge = unit_direction(&narrow, 1.0, 0.5);
+            assert!((Vec3::dot(&edge, &Vec3::new(0.0, 0.0, -1.0)) - 0.5_f64.sqrt()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_fisheye_mappings_differ_inside_circle() {
+        let make = |mapping| {
+            FisheyeCamera::new(
+                lookfrom(),
+                Vec3::empty(),
+                Vec3::new(0.0, 1.0, 0.0),
+                mapping,
+                1.0,
+                0.0,
+                1.0,
+            )
+        };
+        let equidistant = unit_direction(&make(FisheyeMapping::Equidistant), 0.75, 0.5);
+        let equisolid = unit_direction(&make(FisheyeMapping::Equisolid), 0.75, 0.5);
+
+        // Half way out is 45 degrees for equidistant, a bit less for equisolid
+        assert!((equidistant.x() - (PI / 4.0).sin()).abs() < 1e-9);
+        assert!(equisolid.x() < equidistant.x());
+    }
+
+    #[test]
+    fn test_cube_map_faces() {
+        let cam = CubeMapCamera::new(
+            lookfrom(),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            1.0,
+        );
+
+        let centres = [
+            ((0.5, 0.75), Vec3::new(0.0, 0.0, -1.0)),
+            ((1.5, 0.75), Vec3::new(1.0, 0.0, 0.0)),
+            ((2.5, 0.75), Vec3::new(0.0, 0.0, 1.0)),
+            ((0.5, 0.25), Vec3::new(-1.0, 0.0, 0.0)),
+            ((1.5, 0.25), Vec3::new(0.0, 1.0, 0.0)),
+            ((2.5, 0.25), Vec3::new(0.0, -1.0, 0.0)),
+        ];
+        for ((column, t), expected) in centres.iter() {
+            assert!(close(
+                unit_direction(&cam, column / 3.0, *t),
+                *expected,
+                1e-9
+            ));
+        }
+    }
+
+    #[test]
+    fn test_cube_map_faces_meet_at_edges() {
+        let cam = CubeMapCamera::new(
+            lookfrom(),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            1.0,
+        );
+        let e = 1e-9;
+
+        // Right edge of the front face and left edge of the right face
+        let front = unit_direction(&cam, 1.0 / 3.0 - e, 0.8);
+        let right = unit_direction(&cam, 1.0 / 3.0 + e, 0.8);
+        assert!((front - right).length() < 1e-6);
+
+        // Top edge of the front face and bottom edge of the up face
+        let front_top = unit_direction(&cam, 1.0 / 6.0, 1.0);
+        let (forward, right, up) = cam.faces[4];
+        let up_bottom = Vec3::unit_vector(forward + right * 0.0 - up);
+        assert!((front_top - up_bottom).length() < 1e-6);
+    }
+}
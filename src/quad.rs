@@ -0,0 +1,383 @@
+use crate::aabb::{AABB, FLAT};
+use crate::hit::{HitAble, HitRecord};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// Parallelogram with a corner at `q` and sides `u` and `v`. It faces along
+// u x v, and the texture coordinates run along u and v from the corner.
+pub struct Quad {
+    q: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    // Distance of the plane from the origin along the normal
+    d: f64,
+    // Turns a point on the plane into its (u, v) coordinates
+    w: Vec3,
+    area: f64,
+    id: usize,
+}
+
+impl Quad {
+    pub fn new(q: Vec3, u: Vec3, v: Vec3, id: usize) -> Self {
+        let n = Vec3::cross(&u, &v);
+        let normal = Vec3::unit_vector(n);
+        Self {
+            q,
+            u,
+            v,
+            normal,
+            d: Vec3::dot(&normal, &q),
+            w: n / n.length_squared(),
+            area: n.length(),
+            id,
+        }
+    }
+
+    // The axis aligned rectangles at z, y or x = k. They face +z, +y and +x.
+    pub fn xy(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, id: usize) -> Self {
+        Quad::new(
+            Vec3::new(x0, y0, k),
+            Vec3::new(x1 - x0, 0.0, 0.0),
+            Vec3::new(0.0, y1 - y0, 0.0),
+            id,
+        )
+    }
+
+    // u runs along x and v along z like the other two, so x cross z would
+    // face it down
+    pub fn xz(x0: f64, x1: f64, z0: f64, z1: f64, k: f64, id: usize) -> Self {
+        Quad::new(
+            Vec3::new(x0, k, z0),
+            Vec3::new(x1 - x0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, z1 - z0),
+            id,
+        )
+        .flipped()
+    }
+
+    pub fn yz(y0: f64, y1: f64, z0: f64, z1: f64, k: f64, id: usize) -> Self {
+        Quad::new(
+            Vec3::new(k, y0, z0),
+            Vec3::new(0.0, y1 - y0, 0.0),
+            Vec3::new(0.0, 0.0, z1 - z0),
+            id,
+        )
+    }
+
+    // Faces the other way, the texture coordinates stay the same
+    fn flipped(mut self) -> Self {
+        self.normal = -self.normal;
+        self.d = -self.d;
+        self
+    }
+
+    pub fn normal(&self) -> Vec3 {
+        self.normal
+    }
+
+    pub fn area(&self) -> f64 {
+        self.area
+    }
+
+    // Uniformly distributed point on the quad
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        let (s, t) = sampler.get_2d();
+        self.q + self.u * s + self.v * t
+    }
+
+    // Direction from `origin` towards a random point on the quad, for using it
+    // as an area light
+    pub fn random(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.sample(sampler) - origin
+    }
+
+    // Solid angle density of `random` for that direction, 0 if it misses
+    pub fn pdf_value(&self, origin: Vec3, direction: Vec3) -> f64 {
+        let mut rec = HitRecord::empty();
+        let r = Ray::new(&origin, &direction, 0.0);
+        if !self.hit(&r, 0.001, f64::INFINITY, &mut rec) {
+            return 0.0;
+        }
+
+        let distance_squared = rec.t() * rec.t() * direction.length_squared();
+        let cosine = Vec3::dot(&direction, &self.normal).abs() / direction.length();
+        distance_squared / (cosine * self.area)
+    }
+}
+
+impl HitAble for Quad {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let denom = Vec3::dot(&self.normal, &r.direction());
+        if denom.abs() < 1e-12 {
+            return false;
+        }
+
+        let t = (self.d - Vec3::dot(&self.normal, &r.origin())) / denom;
+        if t < t_min || t > t_max {
+            return false;
+        }
+
+        let p = r.at(t);
+        let planar = p - self.q;
+        let alpha = Vec3::dot(&self.w, &Vec3::cross(&planar, &self.v));
+        let beta = Vec3::dot(&self.w, &Vec3::cross(&self.u, &planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return false;
+        }
+
+        rec.set_t(t);
+        rec.set_p(p);
+        rec.set_uv((alpha, beta));
+        rec.set_dpdu(self.u);
+        rec.set_dpdv(self.v);
+        rec.set_face_normal(r, &self.normal);
+        rec.set_id(Some(self.id));
+        true
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        let corners = [self.q + self.u, self.q + self.v, self.q + self.u + self.v];
+        let mut min = self.q;
+        let mut max = self.q;
+        for c in corners.iter() {
+            for a in 0..3 {
+                min[a] = min[a].min(c[a]);
+                max[a] = max[a].max(c[a]);
+            }
+        }
+        for a in 0..3 {
+            if max[a] - min[a] < FLAT {
+                min[a] -= FLAT;
+                max[a] += FLAT;
+            }
+        }
+        Some(AABB::new(min, max))
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+// Infinite plane through `point`. It has no bounding box, so the world keeps
+// it out of the BVH. The texture repeats every `uv_scale` units along two
+// directions in the plane.
+pub struct Plane {
+    point: Vec3,
+    normal: Vec3,
+    tangent: Vec3,
+    bitangent: Vec3,
+    uv_scale: f64,
+    id: usize,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3, id: usize) -> Self {
+        let normal = Vec3::unit_vector(normal);
+        // Any direction not along the normal gives a frame in the plane
+        let helper = if normal.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let tangent = Vec3::unit_vector(Vec3::cross(&helper, &normal));
+        let bitangent = Vec3::cross(&normal, &tangent);
+        Self {
+            point,
+            normal,
+            tangent,
+            bitangent,
+            uv_scale: 1.0,
+            id,
+        }
+    }
+
+    pub fn with_uv_scale(mut self, uv_scale: f64) -> Self {
+        self.uv_scale = uv_scale;
+        self
+    }
+}
+
+impl HitAble for Plane {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let denom = Vec3::dot(&self.normal, &r.direction());
+        if denom.abs() < 1e-12 {
+            return false;
+        }
+
+        let t = Vec3::dot(&self.normal, &(self.point - r.origin())) / denom;
+        if t < t_min || t > t_max {
+            return false;
+        }
+
+        let p = r.at(t);
+        let planar = p - self.point;
+        let u = Vec3::dot(&planar, &self.tangent) / self.uv_scale;
+        let v = Vec3::dot(&planar, &self.bitangent) / self.uv_scale;
+
+        rec.set_t(t);
+        rec.set_p(p);
+        rec.set_uv((u.rem_euclid(1.0), v.rem_euclid(1.0)));
+        rec.set_dpdu(self.tangent * self.uv_scale);
+        rec.set_dpdv(self.bitangent * self.uv_scale);
+        rec.set_face_normal(r, &self.normal);
+        rec.set_id(Some(self.id));
+        true
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        None
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit};
+    use crate::material::{Lambertian, Material};
+    use crate::rng::Rng;
+    use crate::sampler::{create_sampler, SamplerType};
+    use crate::world::World;
+    use std::sync::Arc;
+
+    // Tilted about x and z at once, which the rects can not do
+    fn tilted() -> Quad {
+        Quad::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 1.0, 0.0),
+            Vec3::new(0.0, 1.0, 2.0),
+            0,
+        )
+    }
+
+    #[test]
+    fn test_quad_uv_and_normal() {
+        let quad = tilted();
+        let target = Vec3::new(0.0, 0.0, 0.0)
+            + Vec3::new(2.0, 1.0, 0.0) * 0.25
+            + Vec3::new(0.0, 1.0, 2.0) * 0.75;
+        let rec = hit(&quad, target + quad.normal() * 3.0, -quad.normal()).unwrap();
+
+        assert!(close(rec.p(), target, 1e-9));
+        assert!((rec.u() - 0.25).abs() < 1e-9);
+        assert!((rec.v() - 0.75).abs() < 1e-9);
+        assert!(rec.front_face());
+        let expected = Vec3::unit_vector(Vec3::new(2.0, -4.0, 2.0));
+        assert!(close(quad.normal(), expected, 1e-9));
+
+        // Past the corner
+        let outside = target + Vec3::new(2.0, 1.0, 0.0);
+        assert!(hit(&quad, outside + quad.normal() * 3.0, -quad.normal()).is_none());
+    }
+
+    #[test]
+    fn test_axis_aligned_quads() {
+        let floor = Quad::xz(0.0, 2.0, 0.0, 4.0, 1.0, 0);
+        assert!(close(floor.normal(), Vec3::new(0.0, 1.0, 0.0), 1e-9));
+        assert_eq!(floor.area(), 8.0);
+        let rec = hit(&floor, Vec3::new(1.0, 5.0, 1.0), Vec3::new(0.0, -1.0, 0.0)).unwrap();
+        assert!(close(rec.p(), Vec3::new(1.0, 1.0, 1.0), 1e-9));
+
+        let bbox = floor.bounding_box(0.0, 1.0).unwrap();
+        assert!(close(*bbox.min(), Vec3::new(0.0, 1.0 - FLAT, 0.0), 1e-9));
+        assert!(close(*bbox.max(), Vec3::new(2.0, 1.0 + FLAT, 4.0), 1e-9));
+
+        assert!(close(
+            Quad::xy(0.0, 1.0, 0.0, 1.0, 0.0, 0).normal(),
+            Vec3::new(0.0, 0.0, 1.0),
+            1e-9
+        ));
+        assert!(close(
+            Quad::yz(0.0, 1.0, 0.0, 1.0, 0.0, 0).normal(),
+            Vec3::new(1.0, 0.0, 0.0),
+            1e-9
+        ));
+    }
+
+    #[test]
+    fn test_xz_uv_runs_along_x_then_z() {
+        // The mapping XZRect had, u along x and v along z
+        let floor = Quad::xz(1.0, 3.0, -2.0, 2.0, 0.5, 0);
+        let rec = hit(&floor, Vec3::new(1.5, 4.0, 1.0), Vec3::new(0.0, -1.0, 0.0)).unwrap();
+        assert!((rec.u() - 0.25).abs() < 1e-9);
+        assert!((rec.v() - 0.75).abs() < 1e-9);
+        assert!(rec.front_face());
+        assert!(close(rec.normal(), Vec3::new(0.0, 1.0, 0.0), 1e-9));
+        assert!(close(rec.dpdu(), Vec3::new(2.0, 0.0, 0.0), 1e-9));
+
+        let rec = hit(&floor, Vec3::new(1.5, -4.0, 1.0), Vec3::new(0.0, 1.0, 0.0)).unwrap();
+        assert!(!rec.front_face());
+        assert!((rec.u() - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_samples_and_pdf() {
+        let quad = tilted();
+        let origin = Vec3::new(1.0, 5.0, 1.0);
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 3);
+
+        // Every direction towards a sample hits the quad, and the pdf is the
+        // inverse of the solid angle the quad covers, on average
+        let n = 4096;
+        let mut solid_angle = 0.0;
+        for _ in 0..n {
+            let direction = quad.random(origin, sampler.as_mut());
+            let pdf = quad.pdf_value(origin, direction);
+            assert!(pdf > 0.0);
+            solid_angle += 1.0 / pdf / n as f64;
+        }
+        // Estimate the solid angle by shooting directions over the sphere
+        let mut covered = 0.0;
+        for _ in 0..n * 16 {
+            let d = Vec3::random_unit_vector(sampler.as_mut());
+            if hit(&quad, origin, d).is_some() {
+                covered += 4.0 * std::f64::consts::PI / (n * 16) as f64;
+            }
+        }
+        assert!((solid_angle - covered).abs() / covered < 0.1);
+
+        assert_eq!(quad.pdf_value(origin, Vec3::new(0.0, 1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn test_plane_is_unbounded() {
+        let plane =
+            Plane::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 2.0, 0.0), 0).with_uv_scale(10.0);
+        assert!(plane.bounding_box(0.0, 1.0).is_none());
+
+        let rec = hit(&plane, Vec3::new(1e6, 5.0, -3e5), Vec3::new(0.1, -1.0, 0.0)).unwrap();
+        assert!((rec.p().y() - 1.0).abs() < 1e-6);
+        assert!((0.0..1.0).contains(&rec.u()) && (0.0..1.0).contains(&rec.v()));
+        assert!(hit(&plane, Vec3::new(0.0, 5.0, 0.0), Vec3::new(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn test_world_takes_planes() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let objects: Vec<Box<dyn HitAble>> = vec![
+            Box::new(Plane::new(Vec3::empty(), Vec3::new(0.0, 1.0, 0.0), 0)),
+            Box::new(Quad::xz(-1.0, 1.0, -1.0, 1.0, 2.0, 1)),
+        ];
+        let world = World::new(objects, vec![material.clone(), material], &mut Rng::new(1));
+
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let rec = world.hit(
+            &Ray::new(&Vec3::new(0.0, 5.0, 0.0), &down, 0.0),
+            0.001,
+            f64::INFINITY,
+        );
+        assert_eq!(rec.unwrap().id(), Some(1));
+        let rec = world.hit(
+            &Ray::new(&Vec3::new(5.0, 5.0, 0.0), &down, 0.0),
+            0.001,
+            f64::INFINITY,
+        );
+        assert_eq!(rec.unwrap().id(), Some(0));
+    }
+}
@@ -0,0 +1,870 @@
+use std::f64::consts::PI;
+
+use crate::aabb::{AABB, FLAT};
+use crate::hit::{HitAble, HitRecord};
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+// Round primitives around a vertical axis through `center`, which is the
+// middle of the base (of the torus, its middle). They sweep around the axis
+// from +x towards +z, all the way unless `with_phi_max` cuts them short. u runs
+// along the sweep and v up the side, or out from the axis on flat parts.
+
+// A hit in the primitive's own space, with the normal pointing out
+struct LocalHit {
+    t: f64,
+    p: Vec3,
+    normal: Vec3,
+    uv: (f64, f64),
+    dpdu: Vec3,
+    dpdv: Vec3,
+}
+
+// Angle around the axis, from 0 to 2 pi
+fn phi(p: Vec3) -> f64 {
+    let phi = p.z().atan2(p.x());
+    if phi < 0.0 {
+        phi + 2.0 * PI
+    } else {
+        phi
+    }
+}
+
+// How p moves as u goes from 0 to 1
+fn sweep_tangent(p: Vec3, phi_max: f64) -> Vec3 {
+    Vec3::new(-p.z(), 0.0, p.x()) * phi_max
+}
+
+// Unit vector from the axis out to p, flat
+fn outwards(p: Vec3) -> Vec3 {
+    let r = (p.x() * p.x() + p.z() * p.z()).sqrt();
+    if r > 0.0 {
+        Vec3::new(p.x() / r, 0.0, p.z() / r)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+}
+
+fn sweep(degrees: f64) -> f64 {
+    degrees.clamp(0.0, 360.0).to_radians()
+}
+
+// Both roots of a t^2 + b t + c in order, or the single one when a is 0
+fn quadratic(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        return Some((-c / b, -c / b));
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let q = if b < 0.0 {
+        -0.5 * (b - root)
+    } else {
+        -0.5 * (b + root)
+    };
+    let (t0, t1) = (q / a, if q != 0.0 { c / q } else { q / a });
+    Some(if t0 < t1 { (t0, t1) } else { (t1, t0) })
+}
+
+// Real roots inside [lo, hi] of the polynomial with the given coefficients,
+// constant term first. The roots of the derivative split the range into
+// pieces where the polynomial only goes one way, so each piece has at most one
+// root and bisection finds it.
+fn real_roots(coeffs: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    if coeffs.len() < 2 {
+        return vec![];
+    }
+    let derivative: Vec<f64> = coeffs[1..]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| c * (i + 1) as f64)
+        .collect();
+    let eval = |x: f64| coeffs.iter().rev().fold(0.0, |sum, c| sum * x + c);
+
+    let mut stops = vec![lo];
+    stops.extend(real_roots(&derivative, lo, hi));
+    stops.push(hi);
+
+    let mut roots: Vec<f64> = vec![];
+    for pair in stops.windows(2) {
+        let (mut a, mut b) = (pair[0], pair[1]);
+        let fa = eval(a);
+        if fa * eval(b) > 0.0 {
+            continue;
+        }
+        for _ in 0..64 {
+            let m = (a + b) / 2.0;
+            if eval(m) * fa > 0.0 {
+                a = m;
+            } else {
+                b = m;
+            }
+        }
+        let root = (a + b) / 2.0;
+        if roots.last().is_none_or(|last| root - last > 1e-9) {
+            roots.push(root);
+        }
+    }
+    roots
+}
+
+// Box around the part of the ring between the two radii that the sweep
+// covers, flat at y = 0. The extremes are at the ends of the sweep or where
+// it crosses an axis.
+fn arc_bounds(inner: f64, outer: f64, phi_max: f64) -> (Vec3, Vec3) {
+    let mut angles = vec![0.0, phi_max];
+    angles.extend(
+        [0.5 * PI, PI, 1.5 * PI]
+            .iter()
+            .filter(|a| **a < phi_max)
+            .cloned(),
+    );
+
+    let mut min = Vec3::new(f64::INFINITY, 0.0, f64::INFINITY);
+    let mut max = Vec3::new(-f64::INFINITY, 0.0, -f64::INFINITY);
+    for angle in angles.iter() {
+        for r in [inner, outer].iter() {
+            let (x, z) = (r * angle.cos(), r * angle.sin());
+            min = Vec3::new(min.x().min(x), 0.0, min.z().min(z));
+            max = Vec3::new(max.x().max(x), 0.0, max.z().max(z));
+        }
+    }
+    (min, max)
+}
+
+fn closer(a: Option<LocalHit>, b: Option<LocalHit>) -> Option<LocalHit> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.t < b.t { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// Flat ring at height y, facing along `normal`
+struct Ring {
+    y: f64,
+    inner: f64,
+    outer: f64,
+    phi_max: f64,
+    normal: Vec3,
+}
+
+impl Ring {
+    fn hit(&self, o: Vec3, d: Vec3, t_min: f64, t_max: f64) -> Option<LocalHit> {
+        if d.y() == 0.0 {
+            return None;
+        }
+        let t = (self.y - o.y()) / d.y();
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let p = o + d * t;
+        let r = (p.x() * p.x() + p.z() * p.z()).sqrt();
+        let angle = phi(p);
+        if r > self.outer || r < self.inner || angle > self.phi_max {
+            return None;
+        }
+
+        let width = self.outer - self.inner;
+        Some(LocalHit {
+            t,
+            p,
+            normal: self.normal,
+            uv: (angle / self.phi_max, (self.outer - r) / width),
+            dpdu: sweep_tangent(p, self.phi_max),
+            dpdv: -outwards(p) * width,
+        })
+    }
+}
+
+// First of the candidate times inside the range that `surface` accepts
+fn first_hit(
+    roots: &[f64],
+    t_min: f64,
+    t_max: f64,
+    mut surface: impl FnMut(f64) -> Option<LocalHit>,
+) -> Option<LocalHit> {
+    roots
+        .iter()
+        .filter(|t| **t > t_min && **t < t_max)
+        .find_map(|t| surface(*t))
+}
+
+fn record(r: &Ray, rec: &mut HitRecord, center: Vec3, id: usize, hit: Option<LocalHit>) -> bool {
+    match hit {
+        Some(hit) => {
+            rec.set_t(hit.t);
+            rec.set_p(hit.p + center);
+            rec.set_face_normal(r, &hit.normal);
+            rec.set_uv(hit.uv);
+            rec.set_dpdu(hit.dpdu);
+            rec.set_dpdv(hit.dpdv);
+            rec.set_id(Some(id));
+            true
+        }
+        None => false,
+    }
+}
+
+fn world_box(center: Vec3, min: Vec3, max: Vec3) -> Option<AABB> {
+    Some(AABB::new(min + center, max + center))
+}
+
+// Flat disk facing up, or an annulus when it has an inner radius
+pub struct Disk {
+    center: Vec3,
+    inner_radius: f64,
+    radius: f64,
+    phi_max: f64,
+    id: usize,
+}
+
+impl Disk {
+    pub fn new(center: Vec3, radius: f64, id: usize) -> Self {
+        Disk::annulus(center, 0.0, radius, id)
+    }
+
+    pub fn annulus(center: Vec3, inner_radius: f64, radius: f64, id: usize) -> Self {
+        Self {
+            center,
+            inner_radius,
+            radius,
+            phi_max: 2.0 * PI,
+            id,
+        }
+    }
+
+    pub fn with_phi_max(mut self, degrees: f64) -> Self {
+        self.phi_max = sweep(degrees);
+        self
+    }
+}
+
+impl HitAble for Disk {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let ring = Ring {
+            y: 0.0,
+            inner: self.inner_radius,
+            outer: self.radius,
+            phi_max: self.phi_max,
+            normal: Vec3::new(0.0, 1.0, 0.0),
+        };
+        let hit = ring.hit(r.origin() - self.center, r.direction(), t_min, t_max);
+        record(r, rec, self.center, self.id, hit)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        let (min, max) = arc_bounds(self.inner_radius, self.radius, self.phi_max);
+        world_box(
+            self.center,
+            min - Vec3::new(0.0, FLAT, 0.0),
+            max + Vec3::new(0.0, FLAT, 0.0),
+        )
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+// Side of a cylinder from the base up to `height`, with flat caps at both
+// ends when `capped`
+pub struct Cylinder {
+    center: Vec3,
+    radius: f64,
+    height: f64,
+    capped: bool,
+    phi_max: f64,
+    id: usize,
+}
+
+impl Cylinder {
+    pub fn new(center: Vec3, radius: f64, height: f64, capped: bool, id: usize) -> Self {
+        Self {
+            center,
+            radius,
+            height,
+            capped,
+            phi_max: 2.0 * PI,
+            id,
+        }
+    }
+
+    pub fn with_phi_max(mut self, degrees: f64) -> Self {
+        self.phi_max = sweep(degrees);
+        self
+    }
+}
+
+impl HitAble for Cylinder {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (o, d) = (r.origin() - self.center, r.direction());
+
+        let side = quadratic(
+            d.x() * d.x() + d.z() * d.z(),
+            2.0 * (o.x() * d.x() + o.z() * d.z()),
+            o.x() * o.x() + o.z() * o.z() - self.radius * self.radius,
+        )
+        .and_then(|(t0, t1)| {
+            first_hit(&[t0, t1], t_min, t_max, |t| {
+                let p = o + d * t;
+                let angle = phi(p);
+                if p.y() < 0.0 || p.y() > self.height || angle > self.phi_max {
+                    return None;
+                }
+                Some(LocalHit {
+                    t,
+                    p,
+                    normal: Vec3::new(p.x(), 0.0, p.z()) / self.radius,
+                    uv: (angle / self.phi_max, p.y() / self.height),
+                    dpdu: sweep_tangent(p, self.phi_max),
+                    dpdv: Vec3::new(0.0, self.height, 0.0),
+                })
+            })
+        });
+
+        let mut hit = side;
+        if self.capped {
+            for (y, ny) in [(0.0, -1.0), (self.height, 1.0)].iter() {
+                let ring = Ring {
+                    y: *y,
+                    inner: 0.0,
+                    outer: self.radius,
+                    phi_max: self.phi_max,
+                    normal: Vec3::new(0.0, *ny, 0.0),
+                };
+                let cap = ring.hit(o, d, t_min, t_max);
+                hit = closer(hit, cap);
+            }
+        }
+        record(r, rec, self.center, self.id, hit)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        // Caps of a partial sweep are pie slices that reach the axis
+        let inner = if self.capped { 0.0 } else { self.radius };
+        let (min, max) = arc_bounds(inner, self.radius, self.phi_max);
+        world_box(self.center, min, max + Vec3::new(0.0, self.height, 0.0))
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+// Cone with its base on `center` and its tip `height` above, closed at the
+// base when `capped`
+pub struct Cone {
+    center: Vec3,
+    radius: f64,
+    height: f64,
+    capped: bool,
+    phi_max: f64,
+    id: usize,
+}
+
+impl Cone {
+    pub fn new(center: Vec3, radius: f64, height: f64, capped: bool, id: usize) -> Self {
+        Self {
+            center,
+            radius,
+            height,
+            capped,
+            phi_max: 2.0 * PI,
+            id,
+        }
+    }
+
+    pub fn with_phi_max(mut self, degrees: f64) -> Self {
+        self.phi_max = sweep(degrees);
+        self
+    }
+}
+
+impl HitAble for Cone {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (o, d) = (r.origin() - self.center, r.direction());
+
+        // x^2 + z^2 = (k (height - y))^2, written from the tip down
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let (down_o, down_d) = (self.height - o.y(), -d.y());
+
+        let side = quadratic(
+            d.x() * d.x() + d.z() * d.z() - k2 * down_d * down_d,
+            2.0 * (o.x() * d.x() + o.z() * d.z() - k2 * down_o * down_d),
+            o.x() * o.x() + o.z() * o.z() - k2 * down_o * down_o,
+        )
+        .and_then(|(t0, t1)| {
+            first_hit(&[t0, t1], t_min, t_max, |t| {
+                let p = o + d * t;
+                let angle = phi(p);
+                if p.y() < 0.0 || p.y() > self.height || angle > self.phi_max {
+                    return None;
+                }
+                let normal = Vec3::new(p.x(), k2 * (self.height - p.y()), p.z());
+                // Up the side the radius shrinks by k per unit of height
+                let up = Vec3::new(0.0, 1.0, 0.0) - outwards(p) * k;
+                Some(LocalHit {
+                    t,
+                    p,
+                    normal: Vec3::unit_vector(normal),
+                    uv: (angle / self.phi_max, p.y() / self.height),
+                    dpdu: sweep_tangent(p, self.phi_max),
+                    dpdv: up * self.height,
+                })
+            })
+        });
+
+        let mut hit = side;
+        if self.capped {
+            let ring = Ring {
+                y: 0.0,
+                inner: 0.0,
+                outer: self.radius,
+                phi_max: self.phi_max,
+                normal: Vec3::new(0.0, -1.0, 0.0),
+            };
+            let base = ring.hit(o, d, t_min, t_max);
+            hit = closer(hit, base);
+        }
+        record(r, rec, self.center, self.id, hit)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        let (min, max) = arc_bounds(0.0, self.radius, self.phi_max);
+        world_box(self.center, min, max + Vec3::new(0.0, self.height, 0.0))
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+// Bowl with its lowest point on `center`, `radius` wide at `height`, closed
+// at the top when `capped`
+pub struct Paraboloid {
+    center: Vec3,
+    radius: f64,
+    height: f64,
+    capped: bool,
+    phi_max: f64,
+    id: usize,
+}
+
+impl Paraboloid {
+    pub fn new(center: Vec3, radius: f64, height: f64, capped: bool, id: usize) -> Self {
+        Self {
+            center,
+            radius,
+            height,
+            capped,
+            phi_max: 2.0 * PI,
+            id,
+        }
+    }
+
+    pub fn with_phi_max(mut self, degrees: f64) -> Self {
+        self.phi_max = sweep(degrees);
+        self
+    }
+}
+
+impl HitAble for Paraboloid {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (o, d) = (r.origin() - self.center, r.direction());
+
+        // y = k (x^2 + z^2)
+        let k = self.height / (self.radius * self.radius);
+        let side = quadratic(
+            k * (d.x() * d.x() + d.z() * d.z()),
+            2.0 * k * (o.x() * d.x() + o.z() * d.z()) - d.y(),
+            k * (o.x() * o.x() + o.z() * o.z()) - o.y(),
+        )
+        .and_then(|(t0, t1)| {
+            first_hit(&[t0, t1], t_min, t_max, |t| {
+                let p = o + d * t;
+                let angle = phi(p);
+                if p.y() < 0.0 || p.y() > self.height || angle > self.phi_max {
+                    return None;
+                }
+                let normal = Vec3::new(2.0 * k * p.x(), -1.0, 2.0 * k * p.z());
+                // The radius grows as sqrt(y / k), fastest at the bottom
+                let spread = 1.0 / (4.0 * k * p.y()).max(1e-12).sqrt();
+                let up = Vec3::new(0.0, 1.0, 0.0) + outwards(p) * spread;
+                Some(LocalHit {
+                    t,
+                    p,
+                    normal: Vec3::unit_vector(normal),
+                    uv: (angle / self.phi_max, p.y() / self.height),
+                    dpdu: sweep_tangent(p, self.phi_max),
+                    dpdv: up * self.height,
+                })
+            })
+        });
+
+        let mut hit = side;
+        if self.capped {
+            let ring = Ring {
+                y: self.height,
+                inner: 0.0,
+                outer: self.radius,
+                phi_max: self.phi_max,
+                normal: Vec3::new(0.0, 1.0, 0.0),
+            };
+            let top = ring.hit(o, d, t_min, t_max);
+            hit = closer(hit, top);
+        }
+        record(r, rec, self.center, self.id, hit)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        let (min, max) = arc_bounds(0.0, self.radius, self.phi_max);
+        world_box(self.center, min, max + Vec3::new(0.0, self.height, 0.0))
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+// Ring with a round cross section of `minor_radius`, whose middle runs on a
+// horizontal circle of `major_radius` around `center`. v goes once around the
+// cross section, starting on the outside.
+pub struct Torus {
+    center: Vec3,
+    major_radius: f64,
+    minor_radius: f64,
+    phi_max: f64,
+    id: usize,
+}
+
+impl Torus {
+    pub fn new(center: Vec3, major_radius: f64, minor_radius: f64, id: usize) -> Self {
+        Self {
+            center,
+            major_radius,
+            minor_radius,
+            phi_max: 2.0 * PI,
+            id,
+        }
+    }
+
+    pub fn with_phi_max(mut self, degrees: f64) -> Self {
+        self.phi_max = sweep(degrees);
+        self
+    }
+
+    fn local_bounds(&self) -> (Vec3, Vec3) {
+        let (min, max) = arc_bounds(
+            self.major_radius - self.minor_radius,
+            self.major_radius + self.minor_radius,
+            self.phi_max,
+        );
+        let up = Vec3::new(0.0, self.minor_radius, 0.0);
+        (min - up, max + up)
+    }
+}
+
+impl HitAble for Torus {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (o, d) = (r.origin() - self.center, r.direction());
+        let (min, max) = self.local_bounds();
+        let bounds = AABB::new(min + self.center, max + self.center);
+        let (start, end) = match bounds.clip(r, t_min, t_max) {
+            Some(range) => range,
+            None => return false,
+        };
+
+        // The quartic is far better behaved for a unit direction starting
+        // close by, so solve for the distance s from where the ray enters the
+        // box
+        let length = d.length();
+        let (o, dn) = (o + d * start, d / length);
+        let big = self.major_radius * self.major_radius;
+        let e = o.length_squared() + big - self.minor_radius * self.minor_radius;
+        let f = Vec3::dot(&o, &dn);
+        let flat_dd = dn.x() * dn.x() + dn.z() * dn.z();
+        let flat_od = o.x() * dn.x() + o.z() * dn.z();
+        let flat_oo = o.x() * o.x() + o.z() * o.z();
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2)
+        let coeffs = [
+            e * e - 4.0 * big * flat_oo,
+            4.0 * f * e - 8.0 * big * flat_od,
+            4.0 * f * f + 2.0 * e - 4.0 * big * flat_dd,
+            4.0 * f,
+            1.0,
+        ];
+        let roots: Vec<f64> = real_roots(&coeffs, 0.0, (end - start) * length)
+            .iter()
+            .map(|s| start + s / length)
+            .collect();
+
+        let hit = first_hit(&roots, t_min, t_max, |t| {
+            let p = r.origin() - self.center + d * t;
+            let angle = phi(p);
+            if angle > self.phi_max {
+                return None;
+            }
+
+            let from_ring = (p.x() * p.x() + p.z() * p.z()).sqrt() - self.major_radius;
+            let around = p.y().atan2(from_ring);
+            let around = if around < 0.0 {
+                around + 2.0 * PI
+            } else {
+                around
+            };
+            let s = p.length_squared() + big - self.minor_radius * self.minor_radius;
+            let normal = Vec3::new(p.x() * (s - 2.0 * big), p.y() * s, p.z() * (s - 2.0 * big));
+            Some(LocalHit {
+                t,
+                p,
+                normal: Vec3::unit_vector(normal),
+                uv: (angle / self.phi_max, around / (2.0 * PI)),
+                dpdu: sweep_tangent(p, self.phi_max),
+                dpdv: (Vec3::new(0.0, from_ring, 0.0) - outwards(p) * p.y()) * (2.0 * PI),
+            })
+        });
+        record(r, rec, self.center, self.id, hit)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        let (min, max) = self.local_bounds();
+        world_box(self.center, min, max)
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit};
+    use crate::transforms::{RotateY, Translate};
+
+    fn down(object: &dyn HitAble, x: f64, z: f64) -> Option<HitRecord> {
+        hit(object, Vec3::new(x, 10.0, z), Vec3::new(0.0, -1.0, 0.0))
+    }
+
+    fn contains(bbox: &AABB, p: Vec3) -> bool {
+        (0..3).all(|a| p[a] >= bbox.min()[a] - 1e-9 && p[a] <= bbox.max()[a] + 1e-9)
+    }
+
+    // Shoots a grid of parallel rays at the object from many directions and
+    // checks every hit is in its box and that some hit touches each face of it
+    fn check_tight_box(object: &dyn HitAble) {
+        let bbox = object.bounding_box(0.0, 1.0).unwrap();
+        let centre = (*bbox.min() + *bbox.max()) / 2.0;
+        let reach = (*bbox.max() - *bbox.min()).length() / 2.0;
+        let mut touched = [false; 6];
+
+        for i in 0..10 {
+            for j in 0..20 {
+                let theta = PI * (i as f64 + 0.5) / 10.0;
+                let phi = 2.0 * PI * j as f64 / 20.0;
+                let d = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let side = Vec3::unit_vector(Vec3::cross(&d, &Vec3::new(0.3, 0.5, 0.8)));
+                let up = Vec3::cross(&d, &side);
+
+                for a in -12..=12 {
+                    for b in -12..=12 {
+                        let offset = (side * a as f64 + up * b as f64) * (reach / 12.0);
+                        let origin = centre + d * 20.0 + offset;
+                        if let Some(rec) = hit(object, origin, -d) {
+                            let p = rec.p();
+                            assert!(contains(&bbox, p));
+                            for a in 0..3 {
+                                touched[a] |= p[a] - bbox.min()[a] < 0.05;
+                                touched[a + 3] |= bbox.max()[a] - p[a] < 0.05;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert!(touched.iter().all(|t| *t), "loose box {:?}", touched);
+    }
+
+    #[test]
+    fn test_real_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        let roots = real_roots(&[24.0, -50.0, 35.0, -10.0, 1.0], 0.0, 10.0);
+        assert_eq!(roots.len(), 4);
+        for (root, expected) in roots.iter().zip([1.0, 2.0, 3.0, 4.0].iter()) {
+            assert!((root - expected).abs() < 1e-9);
+        }
+        assert_eq!(
+            real_roots(&[24.0, -50.0, 35.0, -10.0, 1.0], 1.5, 2.5).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_disk_and_annulus() {
+        let disk = Disk::new(Vec3::new(0.0, 1.0, 0.0), 2.0, 0);
+        let rec = down(&disk, 0.0, 0.0).unwrap();
+        assert!(close(rec.p(), Vec3::new(0.0, 1.0, 0.0), 1e-6));
+        assert!(close(rec.normal(), Vec3::new(0.0, 1.0, 0.0), 1e-6));
+        assert!((rec.v() - 1.0).abs() < 1e-9);
+
+        let annulus = Disk::annulus(Vec3::empty(), 1.0, 2.0, 0);
+        assert!(down(&annulus, 0.5, 0.0).is_none());
+        assert!(down(&annulus, 1.5, 0.0).is_some());
+        assert!(down(&annulus, 2.5, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_partial_sweep() {
+        let half = Disk::new(Vec3::empty(), 2.0, 0).with_phi_max(180.0);
+        // +z is a quarter of the way round, -z three quarters
+        let rec = down(&half, 0.0, 1.0).unwrap();
+        assert!((rec.u() - 0.5).abs() < 1e-9);
+        assert!(down(&half, 0.0, -1.0).is_none());
+
+        let bbox = half.bounding_box(0.0, 1.0).unwrap();
+        assert!(close(*bbox.min(), Vec3::new(-2.0, -FLAT, 0.0), 1e-6));
+        assert!(close(*bbox.max(), Vec3::new(2.0, FLAT, 2.0), 1e-6));
+    }
+
+    #[test]
+    fn test_cylinder_caps() {
+        let open = Cylinder::new(Vec3::empty(), 1.0, 2.0, false, 0);
+        let closed = Cylinder::new(Vec3::empty(), 1.0, 2.0, true, 0);
+
+        // Straight down the axis only the caps are in the way. The open one
+        // is seen from inside through its side.
+        assert!(down(&open, 0.0, 0.0).is_none());
+        let rec = down(&closed, 0.0, 0.0).unwrap();
+        assert!(close(rec.p(), Vec3::new(0.0, 2.0, 0.0), 1e-6));
+
+        let side = hit(&open, Vec3::new(5.0, 1.0, 0.0), Vec3::new(-1.0, 0.0, 0.0)).unwrap();
+        assert!(close(side.p(), Vec3::new(1.0, 1.0, 0.0), 1e-6));
+        assert!(close(side.normal(), Vec3::new(1.0, 0.0, 0.0), 1e-6));
+        assert!((side.v() - 0.5).abs() < 1e-9);
+        let inside = hit(&open, Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!(!inside.front_face());
+    }
+
+    #[test]
+    fn test_cone_slope() {
+        let cone = Cone::new(Vec3::empty(), 1.0, 1.0, true, 0);
+        let rec = down(&cone, 0.5, 0.0).unwrap();
+        assert!(close(rec.p(), Vec3::new(0.5, 0.5, 0.0), 1e-6));
+        let expected = Vec3::unit_vector(Vec3::new(1.0, 1.0, 0.0));
+        assert!(close(rec.normal(), expected, 1e-6));
+
+        let below = hit(&cone, Vec3::new(0.5, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0)).unwrap();
+        assert!(close(below.normal(), Vec3::new(0.0, -1.0, 0.0), 1e-6));
+    }
+
+    #[test]
+    fn test_paraboloid_height() {
+        let bowl = Paraboloid::new(Vec3::empty(), 2.0, 4.0, false, 0);
+        // From above the inside of the bowl is seen, at y = x^2
+        let rec = down(&bowl, 1.0, 0.0).unwrap();
+        assert!(close(rec.p(), Vec3::new(1.0, 1.0, 0.0), 1e-6));
+        assert!(!rec.front_face());
+        assert!(down(&bowl, 2.5, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_torus() {
+        let torus = Torus::new(Vec3::empty(), 2.0, 0.5, 0);
+        assert!(down(&torus, 0.0, 0.0).is_none());
+
+        let rec = down(&torus, 2.0, 0.0).unwrap();
+        assert!(close(rec.p(), Vec3::new(2.0, 0.5, 0.0), 1e-6));
+        assert!(close(rec.normal(), Vec3::new(0.0, 1.0, 0.0), 1e-6));
+        assert!((rec.v() - 0.25).abs() < 1e-9);
+
+        // Through the tube on one side of the hole, out and into the other
+        let across = hit(&torus, Vec3::new(-10.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!(close(across.p(), Vec3::new(-2.5, 0.0, 0.0), 1e-6));
+        let mut rec = HitRecord::empty();
+        let r = Ray::new(&Vec3::new(-10.0, 0.0, 0.0), &Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(torus.hit(&r, 9.0, f64::INFINITY, &mut rec));
+        assert!(close(rec.p(), Vec3::new(1.5, 0.0, 0.0), 1e-6));
+    }
+
+    // Aiming a little along dp/du or dp/dv from the hit moves the UVs by as
+    // much along u or v
+    fn check_tangents(object: &dyn HitAble, origin: Vec3, direction: Vec3) {
+        let rec = hit(object, origin, direction).unwrap();
+        let step = 1e-5;
+        for &(tangent, du, dv) in [(rec.dpdu(), step, 0.0), (rec.dpdv(), 0.0, step)].iter() {
+            let nudged = hit(object, origin, rec.p() + tangent * step - origin).unwrap();
+            assert!((nudged.u() - rec.u() - du).abs() < step * 0.01);
+            assert!((nudged.v() - rec.v() - dv).abs() < step * 0.01);
+        }
+    }
+
+    #[test]
+    fn test_tangents_follow_uv() {
+        let diagonal = Vec3::new(-1.0, 0.0, -1.0);
+        let from_above = Vec3::new(0.0, -1.0, 0.0);
+        check_tangents(
+            &Disk::annulus(Vec3::empty(), 0.5, 2.0, 0),
+            Vec3::new(1.0, 5.0, 0.5),
+            from_above,
+        );
+        check_tangents(
+            &Cylinder::new(Vec3::empty(), 1.0, 2.0, false, 0).with_phi_max(300.0),
+            Vec3::new(3.0, 1.0, 3.0),
+            diagonal,
+        );
+        check_tangents(
+            &Cone::new(Vec3::empty(), 1.0, 1.0, false, 0),
+            Vec3::new(3.0, 0.3, 3.0),
+            diagonal,
+        );
+        check_tangents(
+            &Paraboloid::new(Vec3::empty(), 2.0, 4.0, false, 0),
+            Vec3::new(0.7, 10.0, 0.7),
+            from_above,
+        );
+        check_tangents(
+            &Torus::new(Vec3::empty(), 2.0, 0.5, 0),
+            Vec3::new(1.5, 5.0, 0.9),
+            from_above,
+        );
+    }
+
+    #[test]
+    fn test_bounding_boxes_are_tight() {
+        check_tight_box(&Disk::annulus(Vec3::empty(), 0.5, 1.0, 0).with_phi_max(270.0));
+        check_tight_box(&Cylinder::new(Vec3::empty(), 1.0, 2.0, true, 0).with_phi_max(120.0));
+        check_tight_box(&Cylinder::new(Vec3::empty(), 1.0, 2.0, false, 0));
+        check_tight_box(&Cone::new(Vec3::new(1.0, 2.0, 3.0), 1.0, 1.5, true, 0));
+        check_tight_box(&Paraboloid::new(Vec3::empty(), 1.0, 1.0, true, 0).with_phi_max(200.0));
+        check_tight_box(&Torus::new(Vec3::empty(), 1.0, 0.3, 0).with_phi_max(250.0));
+    }
+
+    #[test]
+    fn test_under_transforms() {
+        let cylinder = Box::new(Cylinder::new(Vec3::empty(), 1.0, 2.0, true, 0));
+        let moved = Translate::new(
+            Box::new(RotateY::new(cylinder, 45.0)),
+            Vec3::new(10.0, 0.0, 0.0),
+        );
+
+        let rec = down(&moved, 10.0, 0.0).unwrap();
+        assert!(close(rec.p(), Vec3::new(10.0, 2.0, 0.0), 1e-6));
+        let bbox = moved.bounding_box(0.0, 1.0).unwrap();
+        assert!(contains(&bbox, Vec3::new(11.0, 1.0, 0.0)));
+        assert!(down(&moved, 0.0, 0.0).is_none());
+    }
+}
@@ -0,0 +1,601 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::aov::AovSample;
+use crate::camera::CameraModel;
+use crate::film::{Film, FilmPixel};
+use crate::filter::Filter;
+use crate::ray::Ray;
+use crate::sampler::{create_sampler, Sampler, SamplerType};
+use crate::vec3::Vec3;
+use crate::world::World;
+
+pub struct RenderSettings {
+    pub image_width: usize,
+    pub image_height: usize,
+    pub samples_per_pixel: i32,
+    pub max_depth: i32,
+    pub background: Vec3,
+    pub seed: u64,
+    pub sampler: SamplerType,
+    // Samples per pixel that the sampler lays out its strata and shuffles for,
+    // or None for this render's own count. A resumed render keeps the count
+    // it started with, so raising the samples adds new points rather than
+    // repeating ones the film already has.
+    pub sampler_samples: Option<u32>,
+    pub adaptive: Option<AdaptiveSettings>,
+    pub filter: Box<dyn Filter>,
+    pub tile_size: usize,
+    pub threads: usize,
+    pub aovs: bool,
+    pub id_mattes: bool,
+}
+
+// With adaptive sampling a pixel stops once the relative standard error of its
+// luminance drops below `threshold`. It always takes at least `min_samples` and
+// never more than `max_samples`, which replaces `samples_per_pixel`.
+#[derive(Copy, Clone)]
+pub struct AdaptiveSettings {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f64,
+}
+
+// How many samples are taken between two convergence checks
+const ADAPTIVE_BATCH: u32 = 8;
+
+impl RenderSettings {
+    fn max_samples(&self) -> u32 {
+        match self.adaptive {
+            Some(adaptive) => adaptive.max_samples,
+            None => self.samples_per_pixel as u32,
+        }
+    }
+
+    pub fn sampler_samples(&self) -> u32 {
+        self.sampler_samples.unwrap_or_else(|| self.max_samples())
+    }
+
+    fn converged(&self, pixel: &FilmPixel) -> bool {
+        let adaptive = match self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return false,
+        };
+
+        pixel.samples() >= adaptive.min_samples
+            && (pixel.samples() - adaptive.min_samples).is_multiple_of(ADAPTIVE_BATCH)
+            && pixel.relative_error() < adaptive.threshold
+    }
+}
+
+// A square block of the image that one thread renders at a time
+struct Tile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+fn make_tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let mut tiles = vec![];
+    let size = tile_size.max(1);
+
+    for y0 in (0..height).step_by(size) {
+        for x0 in (0..width).step_by(size) {
+            tiles.push(Tile {
+                x0,
+                y0,
+                x1: (x0 + size).min(width),
+                y1: (y0 + size).min(height),
+            });
+        }
+    }
+
+    tiles
+}
+
+// `aov` is only passed for the camera ray and receives its first hit
+pub fn ray_color(
+    r: &Ray,
+    background: Vec3,
+    world: &World,
+    depth: i32,
+    sampler: &mut dyn Sampler,
+    aov: Option<&mut AovSample>,
+) -> Vec3 {
+    if depth <= 0 {
+        return Vec3::empty();
+    }
+
+    let hit_res = match world.hit(r, 0.001, f64::INFINITY) {
+        Some(v) => v,
+        None => {
+            if let Some(aov) = aov {
+                *aov = AovSample::miss(background);
+            }
+            return background;
+        }
+    };
+
+    let mut scattered = Ray::empty();
+    let mut attenuation: Vec3 = Vec3::empty();
+
+    let hit_index = hit_res.id().unwrap();
+
+    let hit_pair = world.get(hit_index);
+
+    if let Some(aov) = aov {
+        *aov = AovSample {
+            hit: true,
+            normal: hit_res.normal(),
+            depth: hit_res.t() * r.direction().length(),
+            albedo: hit_pair.1.albedo(&hit_res),
+            uv: (hit_res.u(), hit_res.v()),
+            position: hit_res.p(),
+            object_id: Some(hit_index),
+            material_id: Some(world.material_id(hit_index)),
+        };
+    }
+
+    let emitted = hit_pair.1.emitted(hit_res.u(), hit_res.v(), &hit_res.p());
+
+    let scatter = hit_pair
+        .1
+        .scatter(r, &hit_res, &mut attenuation, &mut scattered, sampler);
+
+    if !scatter {
+        return emitted;
+    }
+
+    emitted + attenuation * ray_color(&scattered, background, world, depth - 1, sampler, None)
+}
+
+// What every pixel of a pass shares
+struct Pass<'a> {
+    world: &'a World,
+    cam: &'a dyn CameraModel,
+    settings: &'a RenderSettings,
+    // The samples of earlier passes, which the pass adds up to
+    // `samples_per_pass` more to
+    accumulated: &'a Film,
+    samples_per_pass: u32,
+}
+
+fn pixel_sampler(settings: &RenderSettings) -> Box<dyn Sampler> {
+    create_sampler(settings.sampler, settings.sampler_samples(), settings.seed)
+}
+
+// Film row y is counted from the top, the camera counts t from the bottom.
+// Samples are splatted through the reconstruction filter at their film position.
+// The pixel picks up at the sample index where `accumulated` left off, which is
+// what lets a later pass or a resumed render continue the same sequence.
+fn render_pixel(pass: &Pass, sampler: &mut dyn Sampler, film: &mut Film, x: usize, y: usize) {
+    let settings = pass.settings;
+    let previous = pass.accumulated.pixel(x, y);
+    let start = previous.samples();
+    let end = (start + pass.samples_per_pass).min(settings.max_samples());
+
+    if start > 0 && settings.converged(previous) {
+        return;
+    }
+
+    for s in start..end {
+        sampler.start_pixel_sample(x, y, s);
+        let (du, dv) = sampler.get_2d();
+        let film_x = x as f64 + du;
+        let film_y = y as f64 + dv;
+        let u = film_x / settings.image_width as f64;
+        let v = 1.0 - film_y / settings.image_height as f64;
+
+        let mut aov = AovSample::miss(settings.background);
+        let color = if let Some((r, weight)) = pass.cam.get_weighted_ray(u, v, sampler) {
+            let color = ray_color(
+                &r,
+                settings.background,
+                pass.world,
+                settings.max_depth,
+                sampler,
+                Some(&mut aov),
+            );
+            color * weight
+        } else {
+            aov = AovSample::miss(Vec3::empty());
+            Vec3::empty()
+        };
+        film.add_sample(x, y, film_x, film_y, color, settings.filter.as_ref());
+        if settings.aovs || settings.id_mattes {
+            film.add_aov(x, y, &aov);
+        }
+
+        let mut combined = *previous;
+        combined.merge(film.pixel(x, y));
+        if settings.converged(&combined) {
+            break;
+        }
+    }
+}
+
+// Renders one pass of up to `samples_per_pass` samples per pixel on top of `film`.
+// The image is split into tiles that worker threads pick up one at a time. Every
+// pixel sample restarts the sampler from the seed, and the tile films are merged
+// in tile order once all threads are done, so filter splats that overlap between
+// tiles are summed in the same order and the result is the same for any number
+// of threads. Returns false once there was nothing left to sample.
+fn render_pass(
+    world: &World,
+    cam: &dyn CameraModel,
+    settings: &RenderSettings,
+    film: &mut Film,
+    samples_per_pass: u32,
+) -> bool {
+    let tiles = make_tiles(
+        settings.image_width,
+        settings.image_height,
+        settings.tile_size,
+    );
+    let next_tile = AtomicUsize::new(0);
+    let tile_films: Mutex<Vec<Option<Film>>> = Mutex::new((0..tiles.len()).map(|_| None).collect());
+    let pass = Pass {
+        world,
+        cam,
+        settings,
+        accumulated: film,
+        samples_per_pass,
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..settings.threads.max(1) {
+            scope.spawn(|| {
+                let mut sampler = pixel_sampler(settings);
+
+                loop {
+                    let index = next_tile.fetch_add(1, Ordering::SeqCst);
+                    if index >= tiles.len() {
+                        break;
+                    }
+
+                    let tile = &tiles[index];
+                    let mut tile_film = Film::for_tile(
+                        tile.x0,
+                        tile.y0,
+                        tile.x1,
+                        tile.y1,
+                        settings.filter.radius(),
+                        settings.image_width,
+                        settings.image_height,
+                    );
+                    if settings.aovs {
+                        tile_film.enable_aovs();
+                    }
+                    if settings.id_mattes {
+                        tile_film.enable_id_mattes();
+                    }
+                    for y in tile.y0..tile.y1 {
+                        for x in tile.x0..tile.x1 {
+                            render_pixel(&pass, sampler.as_mut(), &mut tile_film, x, y);
+                        }
+                    }
+
+                    tile_films.lock().unwrap()[index] = Some(tile_film);
+
+                    eprintln!("Tiles remaining: {}", tiles.len() - index - 1);
+                }
+            });
+        }
+    });
+
+    let mut sampled = false;
+    for tile_film in tile_films.into_inner().unwrap().iter().flatten() {
+        sampled |= tile_film.total_samples() > 0;
+        film.merge(tile_film);
+    }
+    sampled
+}
+
+pub fn render(world: &World, cam: &dyn CameraModel, settings: &RenderSettings) -> Film {
+    let mut film = Film::new(settings.image_width, settings.image_height);
+    if settings.aovs {
+        film.enable_aovs();
+    }
+    if settings.id_mattes {
+        film.enable_id_mattes();
+    }
+    render_pass(world, cam, settings, &mut film, settings.max_samples());
+    film
+}
+
+// Keeps adding passes to `film`, which may already hold samples from a
+// checkpoint, until every pixel has all of its samples or has converged.
+// `on_pass` sees the accumulated film after every pass, e.g. to write a
+// preview or a checkpoint.
+pub fn render_progressive<F>(
+    world: &World,
+    cam: &dyn CameraModel,
+    settings: &RenderSettings,
+    mut film: Film,
+    samples_per_pass: u32,
+    mut on_pass: F,
+) -> Film
+where
+    F: FnMut(&Film),
+{
+    while render_pass(world, cam, settings, &mut film, samples_per_pass.max(1)) {
+        on_pass(&film);
+    }
+    film
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::camera::Camera;
+    use crate::checkpoint::Checkpoint;
+    use crate::cryptomatte::IdKind;
+    use crate::filter::{BoxFilter, GaussianFilter, MitchellFilter};
+    use crate::hit::HitAble;
+    use crate::material::{DiffuseLight, Lambertian, Material};
+    use crate::models::Sphere;
+    use crate::rng::Rng;
+    use std::sync::Arc;
+
+    fn test_scene() -> World {
+        let mut rng = Rng::new(1);
+        let objects: Vec<Box<dyn HitAble>> = vec![
+            Box::new(Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, 0)),
+            Box::new(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, 1)),
+        ];
+        let materials: Vec<Arc<dyn Material>> = vec![
+            Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+            Arc::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        ];
+
+        World::new(objects, materials, &mut rng)
+    }
+
+    fn test_settings(seed: u64, threads: usize) -> RenderSettings {
+        RenderSettings {
+            image_width: 12,
+            image_height: 8,
+            samples_per_pixel: 4,
+            max_depth: 5,
+            background: Vec3::new(0.2, 0.3, 0.5),
+            seed,
+            sampler: SamplerType::Sobol,
+            sampler_samples: None,
+            adaptive: None,
+            filter: Box::new(GaussianFilter::new(1.5, 0.5)),
+            tile_size: 5,
+            threads,
+            aovs: false,
+            id_mattes: false,
+        }
+    }
+
+    fn test_camera() -> Camera {
+        Camera::new(
+            Vec3::empty(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            1.5,
+            0.1,
+            1.0,
+            0.0,
+            1.0,
+        )
+    }
+
+    fn bits(film: &Film) -> Vec<u64> {
+        film.resolve()
+            .pixels()
+            .iter()
+            .flat_map(|c| vec![c.x().to_bits(), c.y().to_bits(), c.z().to_bits()])
+            .collect()
+    }
+
+    #[test]
+    fn test_tiles_cover_image() {
+        let tiles = make_tiles(12, 8, 5);
+        let area: usize = tiles.iter().map(|t| (t.x1 - t.x0) * (t.y1 - t.y0)).sum();
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(area, 96);
+    }
+
+    #[test]
+    fn test_same_seed_is_bit_identical_across_thread_counts() {
+        let world = test_scene();
+        let cam = test_camera();
+
+        let one = render(&world, &cam, &test_settings(9, 1));
+        let four = render(&world, &cam, &test_settings(9, 4));
+
+        assert_eq!(bits(&one), bits(&four));
+    }
+
+    #[test]
+    fn test_different_seed_changes_image() {
+        let world = test_scene();
+        let cam = test_camera();
+
+        let a = render(&world, &cam, &test_settings(1, 2));
+        let b = render(&world, &cam, &test_settings(2, 2));
+
+        assert_ne!(bits(&a), bits(&b));
+    }
+
+    #[test]
+    fn test_adaptive_sampling_stops_converged_pixels() {
+        let world = test_scene();
+        let cam = test_camera();
+        let mut settings = test_settings(5, 2);
+        settings.adaptive = Some(AdaptiveSettings {
+            min_samples: 8,
+            max_samples: 64,
+            threshold: 0.05,
+        });
+
+        let film = render(&world, &cam, &settings);
+
+        // The flat background in the top corner converges right away, the
+        // diffuse ground at the bottom keeps sampling
+        assert_eq!(film.pixel(0, 0).samples(), 8);
+        assert!(film.pixel(6, 7).samples() > 8);
+        assert!(film.pixel(6, 7).samples() <= 64);
+    }
+
+    #[test]
+    fn test_negative_lobe_filter_is_deterministic_across_threads() {
+        let world = test_scene();
+        let cam = test_camera();
+        let mut one = test_settings(4, 1);
+        let mut three = test_settings(4, 3);
+        one.filter = Box::new(MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0));
+        three.filter = Box::new(MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0));
+
+        let a = render(&world, &cam, &one);
+        let b = render(&world, &cam, &three);
+
+        assert_eq!(bits(&a), bits(&b));
+    }
+
+    #[test]
+    fn test_box_filter_keeps_samples_in_their_pixel() {
+        let world = test_scene();
+        let cam = test_camera();
+        let mut settings = test_settings(4, 2);
+        settings.filter = Box::new(BoxFilter::new(0.5));
+        settings.background = Vec3::new(1.0, 1.0, 1.0);
+
+        let film = render(&world, &cam, &settings);
+
+        // The top row only sees the background, any leak from below would darken it
+        assert_eq!(film.pixel(0, 0).color().x(), 1.0);
+    }
+
+    #[test]
+    fn test_progressive_passes_match_single_pass() {
+        let world = test_scene();
+        let cam = test_camera();
+        let settings = test_settings(6, 2);
+
+        let single = render(&world, &cam, &settings);
+        let mut passes = 0;
+        let empty = Film::new(settings.image_width, settings.image_height);
+        let progressive = render_progressive(&world, &cam, &settings, empty, 1, |_| passes += 1);
+
+        assert_eq!(passes, 4);
+        for y in 0..settings.image_height {
+            for x in 0..settings.image_width {
+                let a = single.pixel(x, y);
+                let b = progressive.pixel(x, y);
+                assert_eq!(a.samples(), b.samples());
+                assert!((a.color() - b.color()).length() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_progressive_adds_samples_to_existing_film() {
+        let world = test_scene();
+        let cam = test_camera();
+        let mut settings = test_settings(6, 2);
+
+        let film = render(&world, &cam, &settings);
+        settings.samples_per_pixel = 10;
+        let film = render_progressive(&world, &cam, &settings, film, 4, |_| {});
+
+        assert_eq!(film.pixel(3, 3).samples(), 10);
+    }
+
+    #[test]
+    fn test_resumed_samples_do_not_repeat() {
+        let path = std::env::temp_dir()
+            .join(format!("ray-trace-{}-resume.ckpt", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+
+        for kind in [SamplerType::Sobol, SamplerType::Stratified].iter() {
+            let mut settings = test_settings(3, 1);
+            settings.sampler = *kind;
+            settings.samples_per_pixel = 8;
+            let film = Film::new(settings.image_width, settings.image_height);
+            Checkpoint::save(
+                &path,
+                &film,
+                settings.seed,
+                settings.sampler,
+                settings.sampler_samples(),
+            )
+            .unwrap();
+            let first = pixel_sampler(&settings);
+
+            // Resumed from the checkpoint with twice the samples
+            let checkpoint = Checkpoint::load(&path).unwrap();
+            settings.samples_per_pixel = 16;
+            settings.sampler_samples = Some(checkpoint.sampler_samples);
+            let resumed = pixel_sampler(&settings);
+
+            let mut seen = std::collections::HashSet::new();
+            let mut strata = [0; 8];
+            for (mut sampler, samples) in vec![(first, 0..8), (resumed, 8..16)] {
+                for s in samples {
+                    sampler.start_pixel_sample(5, 2, s);
+                    let (a, b) = sampler.get_2d();
+                    let c = sampler.get_1d();
+                    assert!(seen.insert((a.to_bits(), b.to_bits(), c.to_bits())));
+                    strata[(c * 8.0) as usize] += 1;
+                }
+            }
+            // The second eight fill in the same strata once more
+            assert!(strata.iter().all(|count| *count == 2));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_aovs_record_first_hit() {
+        let world = test_scene();
+        let cam = test_camera();
+        let mut settings = test_settings(3, 2);
+        settings.aovs = true;
+
+        let film = render(&world, &cam, &settings);
+        let object_id = film.aov_image(Aov::ObjectId).unwrap();
+        let depth = film.aov_image(Aov::Depth).unwrap();
+        let albedo = film.aov_image(Aov::Albedo).unwrap();
+
+        // The top corner sees the sky, the centre the emissive sphere and the
+        // bottom row the ground
+        assert_eq!(object_id.get(0, 0).x(), -1.0);
+        assert_eq!(albedo.get(0, 0).z(), 0.5);
+        assert_eq!(object_id.get(6, 4).x(), 1.0);
+        assert!(depth.get(6, 4).x() > 0.4 && depth.get(6, 4).x() < 0.6);
+        assert_eq!(object_id.get(6, 7).x(), 0.0);
+        assert_eq!(albedo.get(6, 7).x(), 0.5);
+    }
+
+    #[test]
+    fn test_id_mattes_cover_object_edges() {
+        let world = test_scene();
+        let cam = test_camera();
+        let mut settings = test_settings(3, 2);
+        settings.samples_per_pixel = 16;
+        settings.id_mattes = true;
+
+        let film = render(&world, &cam, &settings);
+        let centre = film.id_matte(6, 4).unwrap();
+
+        // Every sample of the centre pixel sees the sphere, and the coverage of
+        // all ids in a pixel never adds up to more than one
+        assert_eq!(centre.ranked(IdKind::Object), vec![(1, 1.0)]);
+        for y in 0..settings.image_height {
+            for x in 0..settings.image_width {
+                let ranked = film.id_matte(x, y).unwrap().ranked(IdKind::Object);
+                let total: f64 = ranked.iter().map(|(_, c)| c).sum();
+                assert!(total <= 1.0 + 1e-12);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// PCG32 (XSH RR) random number generator. Everything that needs randomness gets
+// handed one of these, so a render is fully reproducible from its seed.
+const PCG_MULTIPLIER: u64 = 6364136223846793005;
+
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+    inc: u64,
+}
+
+// SplitMix64 finaliser, used to spread user seeds and stream indices over the
+// whole 64 bit range before they go into PCG
+pub fn mix_bits(mut v: u64) -> u64 {
+    v = (v ^ (v >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    v = (v ^ (v >> 27)).wrapping_mul(0x94d049bb133111eb);
+    v ^ (v >> 31)
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng::with_stream(seed, 0)
+    }
+
+    pub fn with_stream(seed: u64, stream: u64) -> Self {
+        let mut rng = Rng {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(mix_bits(seed ^ mix_bits(stream)));
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG_MULTIPLIER).wrapping_add(self.inc);
+
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    // Uniform value in [0, 1)
+    pub fn random_double(&mut self) -> f64 {
+        let bits = ((self.next_u32() as u64) << 21) ^ (self.next_u32() as u64 >> 11);
+        bits as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn random_double_from_values(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.random_double()
+    }
+
+    pub fn random_int_from_values(&mut self, min: i32, max: i32) -> i32 {
+        self.random_double_from_values(min as f64, max as f64) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+    }
+
+    #[test]
+    fn test_different_seeds() {
+        let mut a = Rng::new(1);
+        let mut b = Rng::new(2);
+        let same = (0..100).filter(|_| a.next_u32() == b.next_u32()).count();
+        assert!(same < 5);
+    }
+
+    #[test]
+    fn test_random_double_range() {
+        let mut rng = Rng::new(3);
+        let mut sum = 0.0;
+        for _ in 0..10000 {
+            let v = rng.random_double();
+            assert!((0.0..1.0).contains(&v));
+            sum += v;
+        }
+        assert!((sum / 10000.0 - 0.5).abs() < 0.02);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::rng::{mix_bits, Rng};
+
+// Source of sample values for one pixel sample at a time. Every call to get_1d or
+// get_2d consumes the next dimension, so the camera, the lens and each bounce of
+// the materials all draw from their own dimensions of the sequence.
+pub trait Sampler: Send {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: u32);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerType {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+pub fn create_sampler(kind: SamplerType, samples_per_pixel: u32, seed: u64) -> Box<dyn Sampler> {
+    match kind {
+        SamplerType::Independent => Box::new(IndependentSampler::new(seed)),
+        SamplerType::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+        SamplerType::Halton => Box::new(HaltonSampler::new(seed)),
+        SamplerType::Sobol => Box::new(SobolSampler::new(samples_per_pixel, seed)),
+    }
+}
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |acc, v| {
+        mix_bits(acc ^ v.wrapping_add(acc << 6))
+    })
+}
+
+// Kensler's hashed permutation: element i of a random permutation of 0..n
+pub fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+
+        if i < n {
+            break;
+        }
+    }
+
+    (i.wrapping_add(seed)) % n
+}
+
+// Nested uniform (Owen) scrambling of the bits of a base 2 sample
+fn owen_scramble(mut v: u32, seed: u32) -> u32 {
+    v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+fn to_unit(v: u32) -> f64 {
+    (v as f64 / 4294967296.0).min(ONE_MINUS_EPSILON)
+}
+
+// Radical inverse of `index` in `base`, with each digit passed through a random
+// permutation that depends on the digits before it (Owen scrambling)
+pub fn owen_scrambled_radical_inverse(base: u64, mut index: u64, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut value = 0.0;
+    let mut prefix = seed;
+
+    while 1.0 - (base - 1) as f64 * inv_base_m < 1.0 {
+        let next = index / base;
+        let digit_value = index - next * base;
+        let digit = permutation_element(digit_value as u32, base as u32, prefix as u32) as u64;
+
+        inv_base_m *= inv_base;
+        value += digit as f64 * inv_base_m;
+        prefix = hash(&[prefix, digit]);
+        index = next;
+    }
+
+    value.min(ONE_MINUS_EPSILON)
+}
+
+pub fn sobol_first_dimension(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+// The second Sobol dimension, whose direction numbers follow v_k = v_{k-1} ^ (v_{k-1} >> 1)
+pub fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut v: u32 = 1 << 31;
+    let mut result = 0;
+
+    while index != 0 {
+        if index & 1 == 1 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+
+    result
+}
+
+// Plain uniform random numbers, the same as the renderer always used
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Rng,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: u32) {
+        let stream = hash(&[x as u64, y as u64]);
+        self.rng = Rng::with_stream(self.seed ^ mix_bits(sample_index as u64), stream);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.rng.random_double()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.rng.random_double(), self.rng.random_double())
+    }
+}
+
+// Jittered strata per dimension. The strata are visited in a different random
+// order for each dimension, so dimensions do not correlate with each other.
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    x_strata: u32,
+    y_strata: u32,
+    seed: u64,
+    pixel: (u64, u64),
+    sample_index: u32,
+    dimension: u64,
+    rng: Rng,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let mut x_strata = (samples_per_pixel as f64).sqrt() as u32;
+        while !samples_per_pixel.is_multiple_of(x_strata) {
+            x_strata -= 1;
+        }
+
+        Self {
+            samples_per_pixel,
+            x_strata,
+            y_strata: samples_per_pixel / x_strata,
+            seed,
+            pixel: (0, 0),
+            sample_index: 0,
+            dimension: 0,
+            rng: Rng::new(seed),
+        }
+    }
+
+    // Past the count the strata were made for, every further round of
+    // samples visits each stratum once more
+    fn stratum(&mut self) -> u32 {
+        let permutation_seed = hash(&[self.pixel.0, self.pixel.1, self.dimension, self.seed]);
+        self.dimension += 1;
+
+        permutation_element(
+            self.sample_index % self.samples_per_pixel,
+            self.samples_per_pixel,
+            permutation_seed as u32,
+        )
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: u32) {
+        self.pixel = (x as u64, y as u64);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+        self.rng = Rng::with_stream(
+            self.seed ^ mix_bits(sample_index as u64),
+            hash(&[x as u64, y as u64]),
+        );
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let stratum = self.stratum();
+        ((stratum as f64 + self.rng.random_double()) / self.samples_per_pixel as f64)
+            .min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let stratum = self.stratum();
+        let sx = stratum % self.x_strata;
+        let sy = stratum / self.x_strata;
+
+        (
+            ((sx as f64 + self.rng.random_double()) / self.x_strata as f64).min(ONE_MINUS_EPSILON),
+            ((sy as f64 + self.rng.random_double()) / self.y_strata as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+// Halton sequence with one prime base per dimension. Each pixel gets its own
+// Owen scrambling so neighbouring pixels do not share the same points.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: (u64, u64),
+    sample_index: u64,
+    dimension: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pixel: (0, 0),
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_dimension(&mut self) -> f64 {
+        // Past the prime table the sequence wraps with a fresh scramble
+        let base = PRIMES[self.dimension % PRIMES.len()];
+        let seed = hash(&[self.pixel.0, self.pixel.1, self.dimension as u64, self.seed]);
+        self.dimension += 1;
+
+        owen_scrambled_radical_inverse(base, self.sample_index, seed)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: u32) {
+        self.pixel = (x as u64, y as u64);
+        self.sample_index = sample_index as u64;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.next_dimension()
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.next_dimension(), self.next_dimension())
+    }
+}
+
+// Owen scrambled Sobol points, padded: every dimension pair uses the first two
+// Sobol dimensions with its own scramble and its own shuffle of the sample order
+pub struct SobolSampler {
+    samples_per_pixel: u32,
+    seed: u64,
+    pixel: (u64, u64),
+    sample_index: u32,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> Self {
+        Self {
+            samples_per_pixel: samples_per_pixel.max(1),
+            seed,
+            pixel: (0, 0),
+            sample_index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_hash(&mut self) -> u64 {
+        let h = hash(&[self.pixel.0, self.pixel.1, self.dimension, self.seed]);
+        self.dimension += 1;
+        h
+    }
+
+    // Past the count the shuffle was made for the indices go on unshuffled,
+    // onto Sobol points that no earlier sample used
+    fn shuffled_index(&self, h: u64) -> u32 {
+        if self.sample_index >= self.samples_per_pixel {
+            return self.sample_index;
+        }
+        permutation_element(self.sample_index, self.samples_per_pixel, h as u32)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, sample_index: u32) {
+        self.pixel = (x as u64, y as u64);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let h = self.next_hash();
+        let index = self.shuffled_index(h);
+        to_unit(owen_scramble(
+            sobol_first_dimension(index),
+            (h >> 32) as u32,
+        ))
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.next_hash();
+        let index = self.shuffled_index(h);
+        let seed = mix_bits(h);
+
+        (
+            to_unit(owen_scramble(sobol_first_dimension(index), seed as u32)),
+            to_unit(owen_scramble(
+                sobol_second_dimension(index),
+                (seed >> 32) as u32,
+            )),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [SamplerType; 4] = [
+        SamplerType::Independent,
+        SamplerType::Stratified,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+    ];
+
+    #[test]
+    fn test_values_in_range() {
+        for kind in ALL.iter() {
+            let mut sampler = create_sampler(*kind, 16, 3);
+            for s in 0..16 {
+                sampler.start_pixel_sample(5, 7, s);
+                for _ in 0..10 {
+                    let v = sampler.get_1d();
+                    let (a, b) = sampler.get_2d();
+                    assert!((0.0..1.0).contains(&v));
+                    assert!((0.0..1.0).contains(&a));
+                    assert!((0.0..1.0).contains(&b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_restarting_sample_is_deterministic() {
+        for kind in ALL.iter() {
+            let mut sampler = create_sampler(*kind, 16, 3);
+            sampler.start_pixel_sample(2, 1, 4);
+            let first = (sampler.get_1d(), sampler.get_2d());
+            sampler.start_pixel_sample(9, 9, 0);
+            sampler.get_2d();
+            sampler.start_pixel_sample(2, 1, 4);
+            assert_eq!(first, (sampler.get_1d(), sampler.get_2d()));
+        }
+    }
+
+    #[test]
+    fn test_permutation_element_is_a_permutation() {
+        for n in [1, 5, 16, 17].iter() {
+            let mut seen = vec![false; *n as usize];
+            for i in 0..*n {
+                seen[permutation_element(i, *n, 1234) as usize] = true;
+            }
+            assert!(seen.iter().all(|s| *s));
+        }
+    }
+
+    #[test]
+    fn test_sobol_dimensions() {
+        assert_eq!(sobol_first_dimension(1), 1 << 31);
+        assert_eq!(sobol_second_dimension(1), 1 << 31);
+        assert_eq!(sobol_second_dimension(2), 3 << 30);
+        assert_eq!(sobol_second_dimension(3), 1 << 30);
+    }
+
+    // Every stratum of the 1D and 2D domains should receive exactly one sample
+    fn check_stratified(kind: SamplerType) {
+        let mut sampler = create_sampler(kind, 16, 11);
+        let mut strata_1d = [0; 16];
+        let mut strata_2d = [0; 16];
+
+        for s in 0..16 {
+            sampler.start_pixel_sample(3, 4, s);
+            let v = sampler.get_1d();
+            let (a, b) = sampler.get_2d();
+            strata_1d[(v * 16.0) as usize] += 1;
+            strata_2d[(a * 4.0) as usize * 4 + (b * 4.0) as usize] += 1;
+        }
+
+        assert!(strata_1d.iter().all(|c| *c == 1));
+        assert!(strata_2d.iter().all(|c| *c == 1));
+    }
+
+    #[test]
+    fn test_stratified_sampler_covers_strata() {
+        check_stratified(SamplerType::Stratified);
+    }
+
+    #[test]
+    fn test_sobol_sampler_covers_strata() {
+        check_stratified(SamplerType::Sobol);
+    }
+
+    #[test]
+    fn test_halton_base_two_is_stratified() {
+        let mut strata = [0; 8];
+        for i in 0..8 {
+            strata[(owen_scrambled_radical_inverse(2, i, 77) * 8.0) as usize] += 1;
+        }
+        assert!(strata.iter().all(|c| *c == 1));
+    }
+}
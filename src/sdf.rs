@@ -0,0 +1,374 @@
+use std::sync::Arc;
+
+use crate::aabb::AABB;
+use crate::hit::{HitAble, HitRecord};
+use crate::models::Sphere;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+
+// Signed distance functions, negative inside. Nodes build on each other, e.g.
+//
+//     Sdf::sphere(1.0).smooth_union(Sdf::cuboid(Vec3::new(1.0, 0.5, 0.5)).translate(x), 0.3)
+//
+// Repeat and twist bend space, so their distances are no longer exact and the
+// object tracing them may need a smaller step scale.
+#[derive(Clone)]
+pub enum Sdf {
+    Sphere {
+        radius: f64,
+    },
+    Cuboid {
+        half_size: Vec3,
+    },
+    // Around the y axis
+    Torus {
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    // Round ended segment from a to b
+    Capsule {
+        a: Vec3,
+        b: Vec3,
+        radius: f64,
+    },
+    Translate {
+        sdf: Box<Sdf>,
+        offset: Vec3,
+    },
+    // Blends the two over a distance of about k
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: f64,
+    },
+    // a with b carved out of it
+    SmoothSubtract {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: f64,
+    },
+    // Copies every `period` units along each axis with a non zero period
+    Repeat {
+        sdf: Box<Sdf>,
+        period: Vec3,
+    },
+    // Turns `rate` radians around y per unit up y
+    Twist {
+        sdf: Box<Sdf>,
+        rate: f64,
+    },
+    Custom(Arc<dyn Fn(Vec3) -> f64 + Send + Sync>),
+}
+
+impl Sdf {
+    pub fn sphere(radius: f64) -> Sdf {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cuboid(half_size: Vec3) -> Sdf {
+        Sdf::Cuboid { half_size }
+    }
+
+    pub fn torus(major_radius: f64, minor_radius: f64) -> Sdf {
+        Sdf::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn capsule(a: Vec3, b: Vec3, radius: f64) -> Sdf {
+        Sdf::Capsule { a, b, radius }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Sdf {
+        Sdf::Translate {
+            sdf: Box::new(self),
+            offset,
+        }
+    }
+
+    pub fn smooth_union(self, other: Sdf, k: f64) -> Sdf {
+        Sdf::SmoothUnion {
+            a: Box::new(self),
+            b: Box::new(other),
+            k,
+        }
+    }
+
+    pub fn smooth_subtract(self, other: Sdf, k: f64) -> Sdf {
+        Sdf::SmoothSubtract {
+            a: Box::new(self),
+            b: Box::new(other),
+            k,
+        }
+    }
+
+    pub fn repeat(self, period: Vec3) -> Sdf {
+        Sdf::Repeat {
+            sdf: Box::new(self),
+            period,
+        }
+    }
+
+    pub fn twist(self, rate: f64) -> Sdf {
+        Sdf::Twist {
+            sdf: Box::new(self),
+            rate,
+        }
+    }
+
+    pub fn distance(&self, p: Vec3) -> f64 {
+        match self {
+            Sdf::Sphere { radius } => p.length() - radius,
+            Sdf::Cuboid { half_size } => {
+                let q = Vec3::new(
+                    p.x().abs() - half_size.x(),
+                    p.y().abs() - half_size.y(),
+                    p.z().abs() - half_size.z(),
+                );
+                let outside = Vec3::new(q.x().max(0.0), q.y().max(0.0), q.z().max(0.0));
+                outside.length() + q.x().max(q.y()).max(q.z()).min(0.0)
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let ring = (p.x() * p.x() + p.z() * p.z()).sqrt() - major_radius;
+                (ring * ring + p.y() * p.y()).sqrt() - minor_radius
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let pa = p - *a;
+                let ba = *b - *a;
+                let h = (Vec3::dot(&pa, &ba) / ba.length_squared()).clamp(0.0, 1.0);
+                (pa - ba * h).length() - radius
+            }
+            Sdf::Translate { sdf, offset } => sdf.distance(p - *offset),
+            Sdf::SmoothUnion { a, b, k } => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (db - da) / k).clamp(0.0, 1.0);
+                db + (da - db) * h - k * h * (1.0 - h)
+            }
+            Sdf::SmoothSubtract { a, b, k } => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = (0.5 - 0.5 * (da + db) / k).clamp(0.0, 1.0);
+                da + (-db - da) * h + k * h * (1.0 - h)
+            }
+            Sdf::Repeat { sdf, period } => {
+                let mut q = p;
+                for a in 0..3 {
+                    if period[a] > 0.0 {
+                        q[a] = p[a] - period[a] * (p[a] / period[a]).round();
+                    }
+                }
+                sdf.distance(q)
+            }
+            Sdf::Twist { sdf, rate } => {
+                let (s, c) = (rate * p.y()).sin_cos();
+                sdf.distance(Vec3::new(
+                    c * p.x() - s * p.z(),
+                    p.y(),
+                    s * p.x() + c * p.z(),
+                ))
+            }
+            Sdf::Custom(f) => f(p),
+        }
+    }
+
+    // Points out of the surface, from central differences h apart
+    pub fn normal(&self, p: Vec3, h: f64) -> Vec3 {
+        let dx = Vec3::new(h, 0.0, 0.0);
+        let dy = Vec3::new(0.0, h, 0.0);
+        let dz = Vec3::new(0.0, 0.0, h);
+        Vec3::unit_vector(Vec3::new(
+            self.distance(p + dx) - self.distance(p - dx),
+            self.distance(p + dy) - self.distance(p - dy),
+            self.distance(p + dz) - self.distance(p - dz),
+        ))
+    }
+}
+
+// Renders an SDF by sphere tracing: stepping along the ray by the distance to
+// the surface, which can not overshoot it, until closer than `epsilon`. The
+// bounding box is where the marching happens, so it has to hold the whole
+// surface. Rays that take more than `max_steps` steps count as misses.
+pub struct SdfObject {
+    sdf: Sdf,
+    bbox: AABB,
+    epsilon: f64,
+    max_steps: usize,
+    // Below 1 for SDFs whose distances overestimate
+    step_scale: f64,
+    id: usize,
+}
+
+impl SdfObject {
+    pub fn new(sdf: Sdf, bbox: AABB, id: usize) -> Self {
+        Self {
+            sdf,
+            bbox,
+            epsilon: 1e-4,
+            max_steps: 256,
+            step_scale: 1.0,
+            id,
+        }
+    }
+
+    pub fn with_epsilon(mut self, epsilon: f64) -> Self {
+        self.epsilon = epsilon;
+        self
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_step_scale(mut self, step_scale: f64) -> Self {
+        self.step_scale = step_scale;
+        self
+    }
+}
+
+impl HitAble for SdfObject {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (start, end) = match self.bbox.clip(r, t_min, t_max) {
+            Some(range) => range,
+            None => return false,
+        };
+
+        // March in distance along the unit direction, then convert back to t
+        let speed = r.direction().length();
+        let dir = r.direction() / speed;
+        let mut s = start * speed;
+        let end = end * speed;
+
+        // A ray that starts on the surface, like one scattered off it, has
+        // to get away from it before it can hit it again
+        let mut left_surface = self.sdf.distance(r.at(start)).abs() >= self.epsilon;
+
+        for _ in 0..self.max_steps {
+            if s > end {
+                return false;
+            }
+            let p = r.origin() + dir * s;
+            let distance = self.sdf.distance(p).abs();
+
+            if distance < self.epsilon {
+                if left_surface {
+                    let outward_normal = self.sdf.normal(p, self.epsilon);
+                    rec.set_t(s / speed);
+                    rec.set_p(p);
+                    rec.set_face_normal(r, &outward_normal);
+                    rec.set_uv(Sphere::get_sphere_uv(&outward_normal, 0.0, 0.0));
+                    // The UVs come from the normal, as on a unit sphere
+                    let (dpdu, dpdv) = Sphere::get_sphere_tangents(&outward_normal, 1.0);
+                    rec.set_dpdu(dpdu);
+                    rec.set_dpdv(dpdv);
+                    rec.set_id(Some(self.id));
+                    return true;
+                }
+            } else {
+                left_surface = true;
+            }
+            s += (distance * self.step_scale).max(self.epsilon);
+        }
+
+        false
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AABB> {
+        Some(self.bbox)
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::{close, hit};
+
+    fn cube(half: f64) -> AABB {
+        AABB::new(Vec3::new(-half, -half, -half), Vec3::new(half, half, half))
+    }
+
+    #[test]
+    fn test_distances() {
+        let p = Vec3::new(3.0, 0.0, 0.0);
+        assert_eq!(Sdf::sphere(1.0).distance(p), 2.0);
+        assert_eq!(Sdf::cuboid(Vec3::new(1.0, 1.0, 1.0)).distance(p), 2.0);
+        assert_eq!(Sdf::torus(2.0, 0.5).distance(p), 0.5);
+        let capsule = Sdf::capsule(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.5);
+        assert_eq!(capsule.distance(p), 2.5);
+        assert_eq!(capsule.distance(Vec3::new(0.0, 3.0, 0.0)), 1.5);
+        assert_eq!(Sdf::sphere(1.0).translate(p).distance(p), -1.0);
+
+        // Far from both a smooth union is the plain one, in between it bulges
+        let a = Sdf::sphere(1.0).translate(Vec3::new(-1.5, 0.0, 0.0));
+        let b = Sdf::sphere(1.0).translate(Vec3::new(1.5, 0.0, 0.0));
+        let blend = a.clone().smooth_union(b.clone(), 0.5);
+        assert!((blend.distance(Vec3::new(-4.0, 0.0, 0.0)) - 1.5).abs() < 1e-12);
+        assert!(blend.distance(Vec3::empty()) < a.distance(Vec3::empty()));
+
+        let carved = Sdf::sphere(1.0).smooth_subtract(Sdf::sphere(0.5), 0.1);
+        assert!(carved.distance(Vec3::empty()) > 0.0);
+        assert!(carved.distance(Vec3::new(0.75, 0.0, 0.0)) < 0.0);
+    }
+
+    #[test]
+    fn test_traces_like_a_sphere() {
+        let object = SdfObject::new(Sdf::sphere(1.0), cube(1.0), 3).with_epsilon(1e-6);
+        let rec = hit(&object, Vec3::new(0.3, 0.2, 5.0), Vec3::new(0.0, 0.0, -2.0)).unwrap();
+
+        let z = (1.0 - 0.3 * 0.3 - 0.2 * 0.2_f64).sqrt();
+        assert!(close(rec.p(), Vec3::new(0.3, 0.2, z), 1e-5));
+        assert!((rec.t() - (5.0 - z) / 2.0).abs() < 1e-5);
+        assert!(close(rec.normal(), Vec3::new(0.3, 0.2, z), 1e-4));
+        assert_eq!(rec.id(), Some(3));
+
+        assert!(hit(&object, Vec3::new(1.2, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)).is_none());
+    }
+
+    #[test]
+    fn test_scattered_rays_do_not_hit_their_start() {
+        let object = SdfObject::new(Sdf::sphere(1.0), cube(1.0), 0);
+        let on_surface = Vec3::new(0.0, 0.0, 1.0);
+
+        assert!(hit(&object, on_surface, Vec3::new(0.3, 0.0, 1.0)).is_none());
+        // Refracted into the sphere it comes out on the far side
+        let rec = hit(&object, on_surface, Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!(close(rec.p(), Vec3::new(0.0, 0.0, -1.0), 1e-3));
+        assert!(!rec.front_face());
+    }
+
+    #[test]
+    fn test_repeat_and_step_limit() {
+        let spheres = Sdf::sphere(0.3).repeat(Vec3::new(1.0, 0.0, 0.0));
+        let bbox = AABB::new(Vec3::new(-10.0, -1.0, -1.0), Vec3::new(10.0, 1.0, 1.0));
+        let object = SdfObject::new(spheres, bbox, 0);
+
+        let rec = hit(&object, Vec3::new(4.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)).unwrap();
+        assert!(close(rec.p(), Vec3::new(4.0, 0.3, 0.0), 1e-3));
+        assert!(hit(&object, Vec3::new(4.5, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0)).is_none());
+
+        // Grazing past the row takes many small steps
+        let grazing = Vec3::new(-9.0, 0.3005, 0.0);
+        let short = SdfObject::new(Sdf::sphere(0.3).repeat(Vec3::new(1.0, 0.0, 0.0)), bbox, 0)
+            .with_max_steps(4);
+        assert!(hit(&short, grazing, Vec3::new(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn test_twist_keeps_the_axis() {
+        let bar = Sdf::cuboid(Vec3::new(1.0, 2.0, 0.2)).twist(0.5);
+        let object = SdfObject::new(bar, cube(2.5), 0).with_step_scale(0.5);
+
+        // On the axis nothing moves, away from it the bar has turned
+        let rec = hit(&object, Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!((rec.p().z() - 0.2).abs() < 1e-3);
+        let top = hit(&object, Vec3::new(0.0, 1.9, 5.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!(top.p().z() > 0.3);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::sampler::Sampler;
+
+// How far open the shutter is while it is open, from 0 to 1. Real shutters
+// take a while to open and close, which gives motion blur soft ends.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShutterCurve {
+    // Fully open from the moment it opens until it closes
+    Box,
+    // Opens and closes linearly, over fractions of the open time
+    Trapezoid { opening: f64, closing: f64 },
+    // Evenly spaced from open to close, linear in between
+    Table(Vec<f64>),
+}
+
+// When the shutter lets light in. Camera ray times are spread from `open` to
+// `close` in proportion to the curve. The curve shapes the blur, it does not
+// change the brightness of the image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shutter {
+    open: f64,
+    close: f64,
+    curve: ShutterCurve,
+    // The curve as points (x, efficiency) with x from 0 to 1, and the running
+    // area under it up to every point, normalised to end at 1
+    points: Vec<(f64, f64)>,
+    cdf: Vec<f64>,
+}
+
+impl Shutter {
+    pub fn new(open: f64, close: f64) -> Self {
+        Shutter::with_curve(open, close, ShutterCurve::Box)
+    }
+
+    pub fn with_curve(open: f64, close: f64, curve: ShutterCurve) -> Self {
+        let mut points = match &curve {
+            ShutterCurve::Box => vec![(0.0, 1.0), (1.0, 1.0)],
+            ShutterCurve::Trapezoid { opening, closing } => {
+                let opening = opening.clamp(0.0, 1.0);
+                let closing = closing.clamp(0.0, 1.0 - opening);
+                vec![(0.0, 0.0), (opening, 1.0), (1.0 - closing, 1.0), (1.0, 0.0)]
+            }
+            ShutterCurve::Table(values) if values.len() > 1 => {
+                let last = (values.len() - 1) as f64;
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| (i as f64 / last, v.max(0.0)))
+                    .collect()
+            }
+            ShutterCurve::Table(_) => vec![(0.0, 1.0), (1.0, 1.0)],
+        };
+
+        let mut cdf = vec![0.0];
+        for pair in points.windows(2) {
+            let area = (pair[1].0 - pair[0].0) * (pair[0].1 + pair[1].1) / 2.0;
+            cdf.push(cdf[cdf.len() - 1] + area);
+        }
+        let total = cdf[cdf.len() - 1];
+        if total > 0.0 {
+            cdf.iter_mut().for_each(|c| *c /= total);
+        } else {
+            // Never open, treat it as a box rather than return nothing
+            points = vec![(0.0, 1.0), (1.0, 1.0)];
+            cdf = vec![0.0, 1.0];
+        }
+
+        Self {
+            open,
+            close,
+            curve,
+            points,
+            cdf,
+        }
+    }
+
+    pub fn open(&self) -> f64 {
+        self.open
+    }
+
+    pub fn close(&self) -> f64 {
+        self.close
+    }
+
+    pub fn curve(&self) -> &ShutterCurve {
+        &self.curve
+    }
+
+    // Picks the piece of the curve by its area, then inverts the linear ramp
+    // inside that piece
+    pub fn time(&self, u: f64) -> f64 {
+        let i = self.cdf[1..]
+            .iter()
+            .position(|c| u < *c)
+            .unwrap_or(self.cdf.len() - 2);
+        let (x0, y0) = self.points[i];
+        let (x1, y1) = self.points[i + 1];
+        let piece = self.cdf[i + 1] - self.cdf[i];
+        let f = if piece > 0.0 {
+            ((u - self.cdf[i]) / piece).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        let along = if (y1 - y0).abs() < 1e-12 {
+            f
+        } else {
+            ((y0 * y0 * (1.0 - f) + y1 * y1 * f).sqrt() - y0) / (y1 - y0)
+        };
+        let x = x0 + (x1 - x0) * along;
+        self.open + (self.close - self.open) * x
+    }
+
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> f64 {
+        self.time(sampler.get_1d())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fraction of evenly spread samples that land in each tenth of the open time
+    fn histogram(shutter: &Shutter) -> Vec<f64> {
+        let n = 10000;
+        let mut bins = vec![0.0; 10];
+        for i in 0..n {
+            let time = shutter.time((i as f64 + 0.5) / n as f64);
+            let x = (time - shutter.open()) / (shutter.close() - shutter.open());
+            bins[((x * 10.0) as usize).min(9)] += 1.0 / n as f64;
+        }
+        bins
+    }
+
+    #[test]
+    fn test_box_shutter_is_uniform() {
+        let shutter = Shutter::new(0.25, 0.75);
+        assert_eq!(shutter.time(0.0), 0.25);
+        assert_eq!(shutter.time(0.5), 0.5);
+        assert!((shutter.time(1.0) - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_trapezoid_ramps_up_and_down() {
+        let shutter = Shutter::with_curve(
+            0.0,
+            1.0,
+            ShutterCurve::Trapezoid {
+                opening: 0.2,
+                closing: 0.2,
+            },
+        );
+        let bins = histogram(&shutter);
+
+        // The flat middle gets the same share in every bin, the ramps less
+        assert!((bins[4] - bins[5]).abs() < 1e-3);
+        assert!((bins[4] - 0.125).abs() < 1e-3);
+        assert!(bins[0] < bins[1] && bins[1] < bins[4]);
+        assert!(bins[9] < bins[8]);
+        assert!((bins[0] - bins[9]).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_table_follows_efficiency() {
+        // Open only in the second half, twice as much at the very end
+        let shutter = Shutter::with_curve(0.0, 1.0, ShutterCurve::Table(vec![0.0, 0.0, 1.0]));
+        let bins = histogram(&shutter);
+
+        assert!(bins[..5].iter().all(|b| *b == 0.0));
+        assert!(bins[9] > bins[5]);
+        assert!((bins.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_closed_shutter_falls_back_to_box() {
+        let shutter = Shutter::with_curve(0.0, 1.0, ShutterCurve::Table(vec![0.0, 0.0]));
+        assert_eq!(shutter.time(0.5), 0.5);
+    }
+}
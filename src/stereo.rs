@@ -0,0 +1,280 @@
+use std::f64::consts::PI;
+
+use crate::camera::{basis, Camera, CameraModel};
+use crate::panorama::EquirectangularCamera;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::shutter::Shutter;
+use crate::vec3::Vec3;
+
+// Where the two eyes go in the single output image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StereoLayout {
+    // Left eye in the left half
+    SideBySide,
+    // Left eye in the top half
+    TopBottom,
+}
+
+// Renders two cameras into one image. Each eye gets half of the image, so the
+// eye cameras should be set up for the aspect ratio of their half.
+pub struct StereoPair<C: CameraModel> {
+    left: C,
+    right: C,
+    layout: StereoLayout,
+}
+
+impl<C: CameraModel> StereoPair<C> {
+    pub fn new(left: C, right: C, layout: StereoLayout) -> Self {
+        Self {
+            left,
+            right,
+            layout,
+        }
+    }
+
+    // Picks the eye for an image position and maps the position into it
+    fn eye(&self, s: f64, t: f64) -> (&C, f64, f64) {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => (&self.left, s * 2.0, t),
+            StereoLayout::SideBySide => (&self.right, s * 2.0 - 1.0, t),
+            StereoLayout::TopBottom if t >= 0.5 => (&self.left, s, t * 2.0 - 1.0),
+            StereoLayout::TopBottom => (&self.right, s, t * 2.0),
+        }
+    }
+}
+
+impl StereoPair<Camera> {
+    // Off-axis stereo around `cam`. The eyes sit `interocular` apart along the
+    // camera's u axis and converge at the `convergence` distance.
+    pub fn perspective(
+        cam: &Camera,
+        interocular: f64,
+        convergence: f64,
+        layout: StereoLayout,
+    ) -> Self {
+        let half = interocular / 2.0;
+        Self::new(
+            cam.eye(-half, convergence),
+            cam.eye(half, convergence),
+            layout,
+        )
+    }
+}
+
+impl StereoPair<OdsCamera> {
+    // Both eyes of an ODS panorama set up like `cam`, whose own offset is
+    // replaced by half the `interocular` distance either way
+    pub fn ods(cam: &OdsCamera, interocular: f64, layout: StereoLayout) -> Self {
+        let half = interocular / 2.0;
+        let eye = |offset| OdsCamera {
+            offset,
+            ..cam.clone()
+        };
+        Self::new(eye(-half), eye(half), layout)
+    }
+}
+
+impl<C: CameraModel> CameraModel for StereoPair<C> {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (eye, s, t) = self.eye(s, t);
+        eye.get_ray(s, t, sampler)
+    }
+
+    fn exposure(&self) -> f64 {
+        self.left.exposure()
+    }
+
+    fn get_weighted_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<(Ray, f64)> {
+        let (eye, s, t) = self.eye(s, t);
+        eye.get_weighted_ray(s, t, sampler)
+    }
+}
+
+// One eye of an omni-directional stereo panorama. The image is laid out like
+// the equirectangular camera, but every column's rays start on a circle of
+// radius |offset| around lookfrom, to the side of the direction they look in,
+// so each column sees the scene as an eye turned towards it would. A negative
+// offset is the left eye. With a finite convergence the rays are turned in
+// towards the point that distance away along the central ray.
+#[derive(Clone)]
+pub struct OdsCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    offset: f64,
+    convergence: f64,
+    shutter: Shutter,
+}
+
+impl OdsCamera {
+    pub fn new(
+        lookfrom: Vec3,
+        lookat: Vec3,
+        vup: Vec3,
+        offset: f64,
+        convergence: f64,
+        time0: f64,
+        time1: f64,
+    ) -> Self {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        Self {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            offset,
+            convergence,
+            shutter: Shutter::new(time0, time1),
+        }
+    }
+
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    fn to_world(&self, d: Vec3) -> Vec3 {
+        self.u * d.x() + self.v * d.y() + self.w * d.z()
+    }
+}
+
+impl CameraModel for OdsCamera {
+    fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let direction = self.to_world(EquirectangularCamera::direction(s, t));
+
+        // Horizontal right hand vector for this column's viewing direction
+        let phi = (s - 0.5) * 2.0 * PI;
+        let right = self.to_world(Vec3::new(phi.cos(), 0.0, phi.sin()));
+        let origin = self.origin + right * self.offset;
+
+        let direction = if self.convergence.is_finite() {
+            self.origin + direction * self.convergence - origin
+        } else {
+            direction
+        };
+
+        Some(Ray::new(&origin, &direction, self.shutter.sample(sampler)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{create_sampler, SamplerType};
+
+    fn ray(cam: &dyn CameraModel, s: f64, t: f64) -> Ray {
+        let mut sampler = create_sampler(SamplerType::Independent, 1, 0);
+        cam.get_ray(s, t, sampler.as_mut()).unwrap()
+    }
+
+    fn base_camera() -> Camera {
+        Camera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+            0.0,
+            2.0,
+            0.0,
+            1.0,
+        )
+    }
+
+    // Where a ray crosses the plane z = z
+    fn cross_plane(r: &Ray, z: f64) -> Vec3 {
+        let t = (z - r.origin().z()) / r.direction().z();
+        r.at(t)
+    }
+
+    #[test]
+    fn test_eyes_are_offset_along_u() {
+        let pair = StereoPair::perspective(&base_camera(), 0.064, 5.0, StereoLayout::SideBySide);
+
+        let left = ray(&pair, 0.25, 0.5);
+        let right = ray(&pair, 0.75, 0.5);
+
+        assert!((left.origin() - Vec3::new(-0.032, 0.0, 5.0)).length() < 1e-12);
+        assert!((right.origin() - Vec3::new(0.032, 0.0, 5.0)).length() < 1e-12);
+    }
+
+    #[test]
+    fn test_zero_parallax_at_convergence() {
+        let pair = StereoPair::perspective(&base_camera(), 0.5, 5.0, StereoLayout::TopBottom);
+
+        // The same position in both halves meets at the convergence plane and
+        // nowhere else
+        for (s, t) in [(0.5, 0.25), (0.2, 0.1), (0.9, 0.4)].iter() {
+            let right = ray(&pair, *s, *t);
+            let left = ray(&pair, *s, t + 0.5);
+
+            assert!((cross_plane(&left, 0.0) - cross_plane(&right, 0.0)).length() < 1e-9);
+            assert!((cross_plane(&left, -5.0) - cross_plane(&right, -5.0)).length() > 0.1);
+        }
+    }
+
+    #[test]
+    fn test_ods_origins_sit_on_circle() {
+        let cam = OdsCamera::new(
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::empty(),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            f64::INFINITY,
+            0.0,
+            1.0,
+        );
+        let pair = StereoPair::ods(&cam, 0.064, StereoLayout::TopBottom);
+
+        for s in [0.0, 0.3, 0.5, 0.8].iter() {
+            let left = ray(&pair, *s, 0.75);
+            let right = ray(&pair, *s, 0.25);
+            let centre = Vec3::new(0.0, 0.0, 5.0);
+
+            // Both eyes look the same way from opposite sides of the circle,
+            // at right angles to the viewing direction
+            assert!(((left.origin() - centre).length() - 0.032).abs() < 1e-12);
+            assert!((left.origin() - centre + right.origin() - centre).length() < 1e-12);
+            let d = Vec3::unit_vector(left.direction());
+            assert!((d - Vec3::unit_vector(right.direction())).length() < 1e-12);
+            assert!(Vec3::dot(&d, &(right.origin() - left.origin())).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_ods_front_right_eye_is_to_the_right() {
+        let right = OdsCamera::new(
+            Vec3::empty(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.5,
+            f64::INFINITY,
+            0.0,
+            1.0,
+        );
+
+        assert!(ray(&right, 0.5, 0.5).origin().x() > 0.0);
+    }
+
+    #[test]
+    fn test_ods_convergence_turns_rays_in() {
+        let cam = OdsCamera::new(
+            Vec3::empty(),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            0.0,
+            4.0,
+            0.0,
+            1.0,
+        );
+        let pair = StereoPair::ods(&cam, 0.5, StereoLayout::SideBySide);
+
+        let left = ray(&pair, 0.25, 0.5);
+        let right = ray(&pair, 0.75, 0.5);
+
+        assert!((left.at(1.0) - Vec3::new(0.0, 0.0, -4.0)).length() < 1e-12);
+        assert!((right.at(1.0) - Vec3::new(0.0, 0.0, -4.0)).length() < 1e-12);
+    }
+}
@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use crate::mesh::TriangleMesh;
+use crate::vec3::Vec3;
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+fn lerp(t: f64, a: Vec3, b: Vec3) -> Vec3 {
+    a * (1.0 - t) + b * t
+}
+
+fn average(points: impl Iterator<Item = Vec3>) -> Vec3 {
+    let mut sum = Vec3::empty();
+    let mut count = 0;
+    for p in points {
+        sum += p;
+        count += 1;
+    }
+    sum / count.max(1) as f64
+}
+
+// Polygon control mesh for Catmull-Clark subdivision. Faces list their
+// vertices counter clockwise seen from outside and can have any number of
+// them, although modellers mostly export quads and triangles.
+//
+// Edges can be creased with a sharpness: a crease of sharpness s stays sharp
+// for s levels and is smoothed from there, so fractional and small values give
+// rounded edges, and f64::INFINITY a hard edge at every level. Boundary edges
+// are always sharp, and boundary corners that belong to a single face stay
+// put.
+#[derive(Clone)]
+pub struct ControlMesh {
+    positions: Vec<Vec3>,
+    faces: Vec<Vec<usize>>,
+    creases: HashMap<(usize, usize), f64>,
+}
+
+// Who touches whom, for one level of the mesh
+struct Topology {
+    edges: Vec<(usize, usize)>,
+    edge_index: HashMap<(usize, usize), usize>,
+    edge_faces: Vec<Vec<usize>>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(mesh: &ControlMesh) -> Self {
+        let mut topology = Topology {
+            edges: vec![],
+            edge_index: HashMap::new(),
+            edge_faces: vec![],
+            vertex_edges: vec![vec![]; mesh.positions.len()],
+            vertex_faces: vec![vec![]; mesh.positions.len()],
+        };
+
+        for (f, face) in mesh.faces.iter().enumerate() {
+            for (i, &a) in face.iter().enumerate() {
+                let b = face[(i + 1) % face.len()];
+                let key = edge_key(a, b);
+                let e = match topology.edge_index.get(&key) {
+                    Some(&e) => e,
+                    None => {
+                        let e = topology.edges.len();
+                        topology.edges.push(key);
+                        topology.edge_faces.push(vec![]);
+                        topology.edge_index.insert(key, e);
+                        topology.vertex_edges[a].push(e);
+                        topology.vertex_edges[b].push(e);
+                        e
+                    }
+                };
+                topology.edge_faces[e].push(f);
+                topology.vertex_faces[a].push(f);
+            }
+        }
+        topology
+    }
+
+    fn edge(&self, a: usize, b: usize) -> usize {
+        self.edge_index[&edge_key(a, b)]
+    }
+
+    fn is_boundary(&self, e: usize) -> bool {
+        self.edge_faces[e].len() != 2
+    }
+
+    fn other_end(&self, e: usize, v: usize) -> usize {
+        let (a, b) = self.edges[e];
+        if a == v {
+            b
+        } else {
+            a
+        }
+    }
+}
+
+impl ControlMesh {
+    pub fn new(positions: Vec<Vec3>, faces: Vec<Vec<usize>>) -> Self {
+        Self {
+            positions,
+            faces,
+            creases: HashMap::new(),
+        }
+    }
+
+    pub fn with_crease(mut self, a: usize, b: usize, sharpness: f64) -> Self {
+        self.creases.insert(edge_key(a, b), sharpness);
+        self
+    }
+
+    // Vertices and faces of a Wavefront OBJ file. Texture coordinates, normals
+    // and everything else are skipped, since subdivision replaces them.
+    pub fn load_obj(path: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad OBJ line: {}", line),
+            )
+        };
+
+        let mut positions = vec![];
+        let mut faces = vec![];
+        for line in fs::read_to_string(path)?.lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("v") => {
+                    let values: Vec<f64> = fields
+                        .take(3)
+                        .map(|f| f.parse().map_err(|_| invalid(line)))
+                        .collect::<io::Result<_>>()?;
+                    if values.len() != 3 {
+                        return Err(invalid(line));
+                    }
+                    positions.push(Vec3::new(values[0], values[1], values[2]));
+                }
+                Some("f") => {
+                    let mut face = vec![];
+                    for field in fields {
+                        // v, v/vt, v//vn or v/vt/vn, counted from 1 or back
+                        // from the last vertex when negative
+                        let index: i64 = field
+                            .split('/')
+                            .next()
+                            .and_then(|v| v.parse().ok())
+                            .ok_or_else(|| invalid(line))?;
+                        let index = if index < 0 {
+                            positions.len() as i64 + index
+                        } else {
+                            index - 1
+                        };
+                        if index < 0 || index >= positions.len() as i64 {
+                            return Err(invalid(line));
+                        }
+                        face.push(index as usize);
+                    }
+                    if face.len() < 3 {
+                        return Err(invalid(line));
+                    }
+                    faces.push(face);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(ControlMesh::new(positions, faces))
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn faces(&self) -> &[Vec<usize>] {
+        &self.faces
+    }
+
+    // Same faces and creases with the vertices moved
+    pub fn with_positions(self, positions: Vec<Vec3>) -> Self {
+        assert_eq!(positions.len(), self.positions.len());
+        Self { positions, ..self }
+    }
+
+    // Newell's method, which also works for faces that are not flat. The
+    // length is twice the area.
+    fn face_normals(&self) -> Vec<Vec3> {
+        self.faces
+            .iter()
+            .map(|face| {
+                let mut n = Vec3::empty();
+                for (i, &a) in face.iter().enumerate() {
+                    let (p, q) = (
+                        self.positions[a],
+                        self.positions[face[(i + 1) % face.len()]],
+                    );
+                    n += Vec3::cross(&p, &q);
+                }
+                n
+            })
+            .collect()
+    }
+
+    // Area weighted average of the normals of the faces around each vertex,
+    // creases or not, so moving vertices along them keeps the surface closed
+    pub fn vertex_normals(&self) -> Vec<Vec3> {
+        let mut normals = vec![Vec3::empty(); self.positions.len()];
+        for (face, n) in self.faces.iter().zip(self.face_normals()) {
+            for &v in face.iter() {
+                normals[v] += n;
+            }
+        }
+        normals
+            .into_iter()
+            .map(|n| {
+                if n.length_squared() > 0.0 {
+                    Vec3::unit_vector(n)
+                } else {
+                    n
+                }
+            })
+            .collect()
+    }
+
+    fn sharpness(&self, topology: &Topology, e: usize) -> f64 {
+        if topology.is_boundary(e) {
+            return f64::INFINITY;
+        }
+        let (a, b) = topology.edges[e];
+        self.creases.get(&(a, b)).cloned().unwrap_or(0.0)
+    }
+
+    // One level of Catmull-Clark. Every face turns into one quad per corner,
+    // so after the first level the mesh is all quads. New vertices are the old
+    // vertices, then one per edge, then one per face.
+    pub fn subdivide(&self) -> ControlMesh {
+        let topology = Topology::new(self);
+        let (nv, ne) = (self.positions.len(), topology.edges.len());
+
+        let face_points: Vec<Vec3> = self
+            .faces
+            .iter()
+            .map(|face| average(face.iter().map(|&v| self.positions[v])))
+            .collect();
+
+        let edge_points: Vec<Vec3> = (0..ne)
+            .map(|e| {
+                let (a, b) = topology.edges[e];
+                let mid = (self.positions[a] + self.positions[b]) / 2.0;
+                if topology.is_boundary(e) {
+                    return mid;
+                }
+                let faces = &topology.edge_faces[e];
+                let smooth = (self.positions[a]
+                    + self.positions[b]
+                    + face_points[faces[0]]
+                    + face_points[faces[1]])
+                    / 4.0;
+                lerp(self.sharpness(&topology, e).min(1.0), smooth, mid)
+            })
+            .collect();
+
+        let vertex_points: Vec<Vec3> = (0..nv)
+            .map(|v| {
+                let s = self.positions[v];
+                let edges = &topology.vertex_edges[v];
+                let faces = &topology.vertex_faces[v];
+                if faces.is_empty() {
+                    return s;
+                }
+
+                let sharp: Vec<usize> = edges
+                    .iter()
+                    .cloned()
+                    .filter(|&e| self.sharpness(&topology, e) > 0.0)
+                    .collect();
+                let on_boundary = edges.iter().any(|&e| topology.is_boundary(e));
+
+                let smooth = if on_boundary {
+                    s
+                } else {
+                    let n = edges.len() as f64;
+                    let q = average(faces.iter().map(|&f| face_points[f]));
+                    let r = average(
+                        edges
+                            .iter()
+                            .map(|&e| (s + self.positions[topology.other_end(e, v)]) / 2.0),
+                    );
+                    (q + r * 2.0 + s * (n - 3.0)) / n
+                };
+                if sharp.len() < 2 {
+                    return smooth;
+                }
+
+                // Along a crease the vertex only feels its neighbours on it,
+                // where creases meet or at a lone boundary corner it stays
+                let sharp_point = if sharp.len() == 2 && !(on_boundary && faces.len() == 1) {
+                    let a = self.positions[topology.other_end(sharp[0], v)];
+                    let b = self.positions[topology.other_end(sharp[1], v)];
+                    (a + s * 6.0 + b) / 8.0
+                } else {
+                    s
+                };
+                let vertex_sharpness = sharp
+                    .iter()
+                    .map(|&e| self.sharpness(&topology, e))
+                    .sum::<f64>()
+                    / sharp.len() as f64;
+                lerp(vertex_sharpness.min(1.0), smooth, sharp_point)
+            })
+            .collect();
+
+        let mut faces = vec![];
+        for (f, face) in self.faces.iter().enumerate() {
+            let k = face.len();
+            for i in 0..k {
+                let (prev, here, next) = (face[(i + k - 1) % k], face[i], face[(i + 1) % k]);
+                faces.push(vec![
+                    here,
+                    nv + topology.edge(here, next),
+                    nv + ne + f,
+                    nv + topology.edge(prev, here),
+                ]);
+            }
+        }
+
+        let mut creases = HashMap::new();
+        for (&(a, b), &sharpness) in self.creases.iter() {
+            let e = match topology.edge_index.get(&(a, b)) {
+                Some(&e) => e,
+                None => continue,
+            };
+            if sharpness > 1.0 {
+                creases.insert(edge_key(a, nv + e), sharpness - 1.0);
+                creases.insert(edge_key(nv + e, b), sharpness - 1.0);
+            }
+        }
+
+        let mut positions = vertex_points;
+        positions.extend(edge_points);
+        positions.extend(face_points);
+        ControlMesh {
+            positions,
+            faces,
+            creases,
+        }
+    }
+
+    pub fn subdivided(&self, levels: usize) -> ControlMesh {
+        (0..levels).fold(self.clone(), |mesh, _| mesh.subdivide())
+    }
+
+    // Fewest levels, up to max_level, after which no edge is longer than
+    // max_edge_pixels on screen. Each level about halves the edges. For a
+    // camera with a vertical field of view of vfov degrees rendering an image
+    // `height` pixels tall, pixels_per_radian is height / vfov.to_radians().
+    pub fn level_for_screen(
+        &self,
+        eye: Vec3,
+        pixels_per_radian: f64,
+        max_edge_pixels: f64,
+        max_level: usize,
+    ) -> usize {
+        let topology = Topology::new(self);
+        let longest = topology
+            .edges
+            .iter()
+            .map(|&(a, b)| {
+                let (pa, pb) = (self.positions[a], self.positions[b]);
+                let distance = ((pa + pb) / 2.0 - eye).length().max(1e-9);
+                (pa - pb).length() / distance * pixels_per_radian
+            })
+            .fold(0.0, f64::max);
+
+        if longest <= max_edge_pixels {
+            return 0;
+        }
+        ((longest / max_edge_pixels).log2().ceil() as usize).min(max_level)
+    }
+
+    // Triangles with a normal per vertex averaged over the faces around it,
+    // weighted by area. Faces on either side of a crease that is still sharp
+    // keep separate normals, so the crease shows.
+    pub fn to_mesh(&self, id: usize) -> TriangleMesh {
+        let topology = Topology::new(self);
+
+        let face_normals = self.face_normals();
+
+        let mut positions = vec![];
+        let mut normals = vec![];
+        let mut corner_vertex: HashMap<(usize, usize), usize> = HashMap::new();
+
+        for v in 0..self.positions.len() {
+            let faces = &topology.vertex_faces[v];
+
+            // Faces around the vertex joined across smooth edges
+            let mut group: Vec<usize> = (0..faces.len()).collect();
+            fn root(group: &mut [usize], i: usize) -> usize {
+                let mut i = i;
+                while group[i] != i {
+                    group[i] = group[group[i]];
+                    i = group[i];
+                }
+                i
+            }
+            for &e in topology.vertex_edges[v].iter() {
+                if topology.is_boundary(e) || self.sharpness(&topology, e) > 0.0 {
+                    continue;
+                }
+                let (f0, f1) = (topology.edge_faces[e][0], topology.edge_faces[e][1]);
+                let i0 = faces.iter().position(|&f| f == f0).unwrap();
+                let i1 = faces.iter().position(|&f| f == f1).unwrap();
+                let (r0, r1) = (root(&mut group, i0), root(&mut group, i1));
+                group[r0] = r1;
+            }
+
+            let mut group_vertex: HashMap<usize, usize> = HashMap::new();
+            for i in 0..faces.len() {
+                let r = root(&mut group, i);
+                let index = *group_vertex.entry(r).or_insert_with(|| {
+                    positions.push(self.positions[v]);
+                    normals.push(Vec3::empty());
+                    positions.len() - 1
+                });
+                normals[index] += face_normals[faces[i]];
+                corner_vertex.insert((faces[i], v), index);
+            }
+        }
+        let normals = normals
+            .into_iter()
+            .map(|n| {
+                if n.length_squared() > 0.0 {
+                    Vec3::unit_vector(n)
+                } else {
+                    n
+                }
+            })
+            .collect();
+
+        let mut triangles = vec![];
+        for (f, face) in self.faces.iter().enumerate() {
+            let corner = |i: usize| corner_vertex[&(f, face[i])];
+            for i in 1..face.len() - 1 {
+                triangles.push([corner(0), corner(i), corner(i + 1)]);
+            }
+        }
+
+        TriangleMesh::new(positions, normals, triangles, id)
+    }
+
+    pub fn tessellate(&self, levels: usize, id: usize) -> TriangleMesh {
+        self.subdivided(levels).to_mesh(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::test_util::close;
+    use crate::hit::{HitAble, HitRecord};
+    use crate::ray::Ray;
+
+    // The cube [-1, 1]^3 with faces facing out
+    fn cube() -> ControlMesh {
+        let positions = (0..8)
+            .map(|i| {
+                let side = |bit: usize| if i & bit != 0 { 1.0 } else { -1.0 };
+                Vec3::new(side(1), side(2), side(4))
+            })
+            .collect();
+        let faces = vec![
+            vec![0, 4, 6, 2],
+            vec![1, 3, 7, 5],
+            vec![0, 1, 5, 4],
+            vec![2, 6, 7, 3],
+            vec![0, 2, 3, 1],
+            vec![4, 5, 7, 6],
+        ];
+        ControlMesh::new(positions, faces)
+    }
+
+    fn crease_all(mesh: ControlMesh, sharpness: f64) -> ControlMesh {
+        let topology = Topology::new(&mesh);
+        topology
+            .edges
+            .iter()
+            .fold(mesh, |m, &(a, b)| m.with_crease(a, b, sharpness))
+    }
+
+    #[test]
+    fn test_smooth_cube_rounds_off() {
+        let mesh = cube().subdivide();
+        assert_eq!(mesh.faces().len(), 24);
+        assert_eq!(mesh.positions().len(), 8 + 12 + 6);
+
+        // The corner moves to (Q + 2R) / 3 with Q = 1/3 and R = 2/3
+        assert!(close(
+            mesh.positions()[7],
+            Vec3::new(5.0, 5.0, 5.0) / 9.0,
+            1e-12
+        ));
+        // Face points stay at the face centres, edge points move in
+        assert!(close(
+            mesh.positions()[8 + 12],
+            Vec3::new(-1.0, 0.0, 0.0),
+            1e-12
+        ));
+
+        // Still closed, every edge has a face on either side
+        let fine = cube().subdivided(3);
+        assert_eq!(fine.faces().len(), 6 * 64);
+        let topology = Topology::new(&fine);
+        assert!((0..topology.edges.len()).all(|e| !topology.is_boundary(e)));
+    }
+
+    #[test]
+    fn test_hard_creases_keep_the_cube() {
+        let mesh = crease_all(cube(), f64::INFINITY).subdivided(2);
+        for p in mesh.positions() {
+            let largest = p.x().abs().max(p.y().abs()).max(p.z().abs());
+            assert!((largest - 1.0).abs() < 1e-12);
+        }
+
+        // With faces keeping their own normals
+        let triangles = mesh.to_mesh(0);
+        let mut rec = HitRecord::empty();
+        let r = Ray::new(&Vec3::new(0.9, 0.3, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(triangles.hit(&r, 0.001, f64::INFINITY, &mut rec));
+        assert!(close(rec.normal(), Vec3::new(0.0, 0.0, 1.0), 1e-9));
+        assert!(close(rec.p(), Vec3::new(0.9, 0.3, 1.0), 1e-9));
+    }
+
+    #[test]
+    fn test_semi_sharp_creases_are_in_between() {
+        let corner = |mesh: ControlMesh| mesh.subdivided(3).positions()[7].x();
+        let smooth = corner(cube());
+        let soft = corner(crease_all(cube(), 1.0));
+        let firm = corner(crease_all(cube(), 2.5));
+        let hard = corner(crease_all(cube(), f64::INFINITY));
+        assert!(smooth < soft && soft < firm && firm < hard);
+        assert_eq!(hard, 1.0);
+    }
+
+    #[test]
+    fn test_open_grid_stays_flat() {
+        // 3 by 3 quads in the plane z = 0
+        let positions = (0..16)
+            .map(|i| Vec3::new((i % 4) as f64, (i / 4) as f64, 0.0))
+            .collect();
+        let mut faces = vec![];
+        for y in 0..3 {
+            for x in 0..3 {
+                let i = y * 4 + x;
+                faces.push(vec![i, i + 1, i + 5, i + 4]);
+            }
+        }
+        let mesh = ControlMesh::new(positions, faces).subdivided(2);
+
+        assert!(mesh.positions().iter().all(|p| p.z() == 0.0));
+        // Lone corners stay, boundary vertices slide along the boundary
+        assert!(close(mesh.positions()[0], Vec3::empty(), 1e-12));
+        assert!(close(mesh.positions()[15], Vec3::new(3.0, 3.0, 0.0), 1e-12));
+        assert_eq!(mesh.positions()[1].y(), 0.0);
+
+        let triangles = mesh.to_mesh(0);
+        assert_eq!(triangles.triangle_count(), 9 * 16 * 2);
+    }
+
+    #[test]
+    fn test_smooth_normals_and_screen_level() {
+        let mesh = cube().tessellate(3, 2);
+        let mut rec = HitRecord::empty();
+        // Hit near the rounded corner, the normal leans out of the face
+        let r = Ray::new(&Vec3::new(0.45, 0.45, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(mesh.hit(&r, 0.001, f64::INFINITY, &mut rec));
+        assert!(rec.normal().x() > 0.05 && rec.normal().z() > 0.5);
+        assert_eq!(rec.id(), Some(2));
+
+        // Two edge lengths of 2 at distance 10 are 0.2 radians, 200 pixels at
+        // 1000 per radian, and need 3 halvings to get under 32
+        let eye = Vec3::new(0.0, 0.0, 10.0);
+        let near = cube().level_for_screen(eye, 1000.0, 32.0, 6);
+        let far = cube().level_for_screen(eye * 10.0, 1000.0, 32.0, 6);
+        assert!((3..=4).contains(&near));
+        assert!(far < near);
+        assert_eq!(cube().level_for_screen(eye, 1000.0, 32.0, 1), 1);
+    }
+
+    #[test]
+    fn test_load_obj() {
+        let path = std::env::temp_dir().join("subdivision_test_cage.obj");
+        let text = "# a square and a triangle\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 2 0 0\n\
+                    vt 0 0\nf 1/1 2/1 3/1 4/1\nf 2//1 5//1 -3//1\n";
+        fs::write(&path, text).unwrap();
+        let mesh = ControlMesh::load_obj(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mesh.positions().len(), 5);
+        assert_eq!(mesh.faces(), &[vec![0, 1, 2, 3], vec![1, 4, 2]][..]);
+        // A square and a triangle make 4 + 3 quads
+        assert_eq!(mesh.subdivide().faces().len(), 7);
+
+        let bad = std::env::temp_dir().join("subdivision_test_bad.obj");
+        fs::write(&bad, "v 0 0 0\nf 1 2 3\n").unwrap();
+        assert!(ControlMesh::load_obj(bad.to_str().unwrap()).is_err());
+        fs::remove_file(&bad).unwrap();
+    }
+}
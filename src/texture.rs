@@ -2,14 +2,11 @@ use std::fs::File;
 
 use png::Decoder;
 
-use crate::{
-    utility::{random_double, random_int_from_values},
-    vec3::Vec3,
-};
+use crate::{rng::Rng, vec3::Vec3};
 
 const POINT_COUNT: i32 = 256;
 
-pub trait Texture {
+pub trait Texture: Send + Sync {
     fn value(&self, u: f64, v: f64, p: &Vec3) -> Vec3;
 }
 
@@ -68,23 +65,23 @@ pub struct PerlinTexture {
 }
 
 impl PerlinTexture {
-    pub fn new(scale: f64) -> Self {
+    pub fn new(scale: f64, rng: &mut Rng) -> Self {
         let mut ran_temp: Vec<Vec3> = vec![];
 
         for _ in 0..POINT_COUNT {
-            ran_temp.push(Vec3::random_from_values(-1.0, 1.0));
+            ran_temp.push(Vec3::random_from_values(-1.0, 1.0, rng));
         }
 
         Self {
             ranvec: ran_temp,
-            perm_x: perlin_generate_perm(),
-            perm_y: perlin_generate_perm(),
-            perm_z: perlin_generate_perm(),
+            perm_x: perlin_generate_perm(rng),
+            perm_y: perlin_generate_perm(rng),
+            perm_z: perlin_generate_perm(rng),
             scale,
         }
     }
 
-    fn turb(&self, p: &Vec3) -> f64 {
+    pub fn turb(&self, p: &Vec3) -> f64 {
         let mut accum: f64 = 0.0;
         let mut temp_p = p.clone();
         let mut weight = 1.0;
@@ -132,21 +129,21 @@ impl Texture for PerlinTexture {
     }
 }
 
-fn perlin_generate_perm() -> Vec<i32> {
+fn perlin_generate_perm(rng: &mut Rng) -> Vec<i32> {
     let mut p: Vec<i32> = vec![];
 
     for i in 0..POINT_COUNT {
         p.push(i);
     }
 
-    permute(&mut p, POINT_COUNT);
+    permute(&mut p, POINT_COUNT, rng);
 
     return p;
 }
 
-fn permute(p: &mut [i32], n: i32) {
+fn permute(p: &mut [i32], n: i32, rng: &mut Rng) {
     for i in (0..n).rev() {
-        let target = random_int_from_values(0, i);
+        let target = rng.random_int_from_values(0, i);
         let tmp = p[i as usize];
         p[i as usize] = p[target as usize];
         p[target as usize] = tmp
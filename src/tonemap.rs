@@ -0,0 +1,127 @@
+use crate::vec3::Vec3;
+
+// Operators that map linear scene radiance onto the displayable [0, 1] range.
+// They work per channel, before the gamma step done when the image is written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMap {
+    Clamp,
+    Reinhard,
+    // Reinhard with a white point: radiance at `white_point` maps to exactly 1.0
+    ReinhardExtended { white_point: f64 },
+    // Narkowicz's fit of the ACES filmic reference curve
+    Aces,
+    // John Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+// Hable's curve parameters and the linear white point it is normalised against
+const HABLE_A: f64 = 0.15;
+const HABLE_B: f64 = 0.50;
+const HABLE_C: f64 = 0.10;
+const HABLE_D: f64 = 0.20;
+const HABLE_E: f64 = 0.02;
+const HABLE_F: f64 = 0.30;
+const HABLE_WHITE: f64 = 11.2;
+
+// Exposure is given in EV stops, so every stop doubles the incoming radiance
+pub fn exposure_scale(ev: f64) -> f64 {
+    2.0_f64.powf(ev)
+}
+
+fn hable_partial(x: f64) -> f64 {
+    ((x * (HABLE_A * x + HABLE_C * HABLE_B) + HABLE_D * HABLE_E)
+        / (x * (HABLE_A * x + HABLE_B) + HABLE_D * HABLE_F))
+        - HABLE_E / HABLE_F
+}
+
+impl ToneMap {
+    pub fn map_channel(&self, x: f64) -> f64 {
+        let x = x.max(0.0);
+
+        let mapped = match *self {
+            ToneMap::Clamp => x,
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::ReinhardExtended { white_point } => {
+                x * (1.0 + x / (white_point * white_point)) / (1.0 + x)
+            }
+            ToneMap::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+            ToneMap::Hable => {
+                // The 2.0 exposure bias is part of the original curve
+                hable_partial(x * 2.0) / hable_partial(HABLE_WHITE)
+            }
+        };
+
+        mapped.clamp(0.0, 1.0)
+    }
+
+    pub fn apply(&self, color: Vec3, exposure: f64) -> Vec3 {
+        let scale = exposure_scale(exposure);
+
+        Vec3::new(
+            self.map_channel(color.x() * scale),
+            self.map_channel(color.y() * scale),
+            self.map_channel(color.z() * scale),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_exposure_scale() {
+        close(exposure_scale(0.0), 1.0);
+        close(exposure_scale(1.0), 2.0);
+        close(exposure_scale(-2.0), 0.25);
+    }
+
+    #[test]
+    fn test_clamp() {
+        let result = ToneMap::Clamp.apply(Vec3::new(-1.0, 0.5, 4.0), 0.0);
+        close(result.x(), 0.0);
+        close(result.y(), 0.5);
+        close(result.z(), 1.0);
+    }
+
+    #[test]
+    fn test_reinhard() {
+        close(ToneMap::Reinhard.map_channel(1.0), 0.5);
+        close(ToneMap::Reinhard.map_channel(3.0), 0.75);
+    }
+
+    #[test]
+    fn test_reinhard_extended_white_point() {
+        let op = ToneMap::ReinhardExtended { white_point: 4.0 };
+        close(op.map_channel(4.0), 1.0);
+        assert!(op.map_channel(1.0) > ToneMap::Reinhard.map_channel(1.0));
+    }
+
+    #[test]
+    fn test_filmic_curves_are_monotonic() {
+        for op in [ToneMap::Aces, ToneMap::Hable].iter() {
+            let mut last = op.map_channel(0.0);
+            for i in 1..200 {
+                let next = op.map_channel(i as f64 * 0.1);
+                assert!(next >= last);
+                last = next;
+            }
+            assert!(last <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_hable_white_point() {
+        close(ToneMap::Hable.map_channel(HABLE_WHITE / 2.0), 1.0);
+    }
+
+    #[test]
+    fn test_apply_exposure() {
+        let result = ToneMap::Reinhard.apply(Vec3::new(0.5, 0.5, 0.5), 1.0);
+        close(result.x(), 0.5);
+    }
+}
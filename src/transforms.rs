@@ -127,8 +127,18 @@ impl HitAble for RotateY {
         normal[0] = self.cos_theta * rec.normal()[0] + self.sin_theta * rec.normal()[2];
         normal[2] = -self.sin_theta * rec.normal()[0] + self.cos_theta * rec.normal()[2];
 
+        let mut dpdu = rec.dpdu();
+        dpdu[0] = self.cos_theta * rec.dpdu()[0] + self.sin_theta * rec.dpdu()[2];
+        dpdu[2] = -self.sin_theta * rec.dpdu()[0] + self.cos_theta * rec.dpdu()[2];
+
+        let mut dpdv = rec.dpdv();
+        dpdv[0] = self.cos_theta * rec.dpdv()[0] + self.sin_theta * rec.dpdv()[2];
+        dpdv[2] = -self.sin_theta * rec.dpdv()[0] + self.cos_theta * rec.dpdv()[2];
+
         rec.set_p(p);
         rec.set_face_normal(&rotated_r, &normal);
+        rec.set_dpdu(dpdu);
+        rec.set_dpdv(dpdv);
 
         true
     }
@@ -2,7 +2,9 @@ use std::f64::consts::PI;
 use std::fmt;
 use std::ops;
 
-use crate::utility::{ffmin, random_double, random_double_from_values};
+use crate::rng::Rng;
+use crate::sampler::Sampler;
+use crate::utility::ffmin;
 
 #[derive(Copy, Clone)]
 pub struct Vec3 {
@@ -30,44 +32,47 @@ impl Vec3 {
         Vec3::new(0.0, 0.0, 0.0)
     }
 
-    pub fn random() -> Vec3 {
-        Vec3::new(random_double(), random_double(), random_double())
+    pub fn random(rng: &mut Rng) -> Vec3 {
+        Vec3::new(rng.random_double(), rng.random_double(), rng.random_double())
     }
 
-    pub fn random_unit_vector() -> Vec3 {
-        let a = random_double_from_values(0.0, 2.0 * PI);
-        let z = random_double_from_values(-1.0, 1.0);
+    pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let a = 2.0 * PI * u1;
+        let z = 1.0 - 2.0 * u2;
         let r = (1.0 - z * z).sqrt();
 
         Vec3::new(r * a.cos(), r * a.sin(), z)
     }
 
-    pub fn random_in_unit_sphere() -> Vec3 {
-        loop {
-            let p = Vec3::random_from_values(-1.0, 1.0);
-            if p.length_squared() >= 1.0 {
-                continue;
-            }
-            return p;
-        }
+    // Warped instead of rejection sampled, so every call uses a fixed number of
+    // sampler dimensions
+    pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = Vec3::random_unit_vector(sampler);
+        direction * sampler.get_1d().cbrt()
     }
 
-    pub fn random_in_unit_disk() -> Vec3 {
-        loop {
-            let p = Vec3::new(
-                random_double_from_values(-1.0, 1.0),
-                random_double_from_values(-1.0, 1.0),
-                0.0,
-            );
-            if p.length_squared() >= 1.0 {
-                continue;
-            }
-            return p;
+    // Shirley-Chiu concentric mapping of the unit square onto the disk
+    pub fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+        let (u1, u2) = sampler.get_2d();
+        let a = 2.0 * u1 - 1.0;
+        let b = 2.0 * u2 - 1.0;
+
+        if a == 0.0 && b == 0.0 {
+            return Vec3::empty();
         }
+
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, (PI / 4.0) * (b / a))
+        } else {
+            (b, (PI / 2.0) - (PI / 4.0) * (a / b))
+        };
+
+        Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
     }
 
-    pub fn random_in_hemisphere(normal: &Vec3) -> Vec3 {
-        let in_unit_sphere = Vec3::random_in_unit_sphere();
+    pub fn random_in_hemisphere(normal: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        let in_unit_sphere = Vec3::random_in_unit_sphere(sampler);
         if Vec3::dot(&in_unit_sphere, normal) > 0.0 {
             return in_unit_sphere;
         }
@@ -75,11 +80,11 @@ impl Vec3 {
         -in_unit_sphere
     }
 
-    pub fn random_from_values(min: f64, max: f64) -> Vec3 {
+    pub fn random_from_values(min: f64, max: f64, rng: &mut Rng) -> Vec3 {
         Vec3::new(
-            random_double_from_values(min, max),
-            random_double_from_values(min, max),
-            random_double_from_values(min, max),
+            rng.random_double_from_values(min, max),
+            rng.random_double_from_values(min, max),
+            rng.random_double_from_values(min, max),
         )
     }
 
@@ -91,18 +96,9 @@ impl Vec3 {
         self.e[0] * self.e[0] + self.e[1] * self.e[1] + self.e[2] * self.e[2]
     }
 
-    pub fn write_color(&self, samples_per_pixel: i32) {
-        let scale = 1.0 / samples_per_pixel as f64;
-
-        let r: f64 = (scale * self.e[0]).sqrt();
-        let g: f64 = (scale * self.e[1]).sqrt();
-        let b: f64 = (scale * self.e[2]).sqrt();
-        print!(
-            "{} {} {}\n",
-            (256.0 * r.clamp(0.0, 0.999)) as i32,
-            (256.0 * g.clamp(0.0, 0.999)) as i32,
-            (256.0 * b.clamp(0.0, 0.999)) as i32,
-        );
+    // Rec. 709 luminance of a linear RGB colour
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.e[0] + 0.7152 * self.e[1] + 0.0722 * self.e[2]
     }
 
     pub fn dot(u: &Vec3, v: &Vec3) -> f64 {
@@ -274,6 +270,18 @@ impl ops::Div<f64> for Vec3 {
     }
 }
 
+impl ops::Div for Vec3 {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Vec3 {
+        Vec3::new(
+            self.e[0] / other[0],
+            self.e[1] / other[1],
+            self.e[2] / other[2],
+        )
+    }
+}
+
 impl fmt::Display for Vec3 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} {} {}", self.e[0], self.e[1], self.e[2])
@@ -1,50 +1,132 @@
-use crate::aabb::AABB;
 use crate::hit::HitRecord;
 use crate::material::Material;
 use crate::ray::Ray;
 use crate::{bvh::BvhNode, hit::HitAble};
-use std::rc::Rc;
+use crate::rng::Rng;
+use std::sync::Arc;
 
 pub struct World {
     objects: Vec<Box<dyn HitAble>>,
-    materials: Vec<Rc<dyn Material>>,
-    node: BvhNode,
+    materials: Vec<Arc<dyn Material>>,
+    material_ids: Vec<usize>,
+    object_names: Vec<String>,
+    material_names: Vec<String>,
+    // Over the objects with a bounding box, the rest are tried one by one
+    node: Option<BvhNode>,
+    unbounded: Vec<usize>,
 }
 
-impl World {
-    pub fn new(mut objects: Vec<Box<dyn HitAble>>, materials: Vec<Rc<dyn Material>>) -> Self {
-        let tnode = BvhNode::new_from_list(&mut objects, 0.0, 10.0);
+// Objects that share one material Arc get the same id, numbered in order of
+// first use
+fn material_ids(materials: &[Arc<dyn Material>]) -> Vec<usize> {
+    let mut seen: Vec<*const u8> = vec![];
+    materials
+        .iter()
+        .map(|m| {
+            let ptr = Arc::as_ptr(m) as *const u8;
+            match seen.iter().position(|p| *p == ptr) {
+                Some(id) => id,
+                None => {
+                    seen.push(ptr);
+                    seen.len() - 1
+                }
+            }
+        })
+        .collect()
+}
 
-        let node = BvhNode::new_from_list(&mut objects, 0.0, 10.0);
+impl World {
+    pub fn new(
+        objects: Vec<Box<dyn HitAble>>,
+        materials: Vec<Arc<dyn Material>>,
+        rng: &mut Rng,
+    ) -> Self {
+        // Infinite planes have no bounding box and stay out of the BVH
+        let (mut bounded, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..objects.len()).partition(|&i| objects[i].bounding_box(0.0, 10.0).is_some());
+        let node = match bounded.len() {
+            0 => None,
+            _ => Some(BvhNode::new_from_list(
+                &objects,
+                &mut bounded,
+                0.0,
+                10.0,
+                rng,
+            )),
+        };
+        let material_ids = material_ids(&materials);
+        let object_names = (0..materials.len())
+            .map(|id| format!("object_{}", id))
+            .collect();
+        let material_count = material_ids.iter().max().map_or(0, |id| id + 1);
+        let material_names = (0..material_count)
+            .map(|id| format!("material_{}", id))
+            .collect();
         World {
             objects,
             materials,
+            material_ids,
+            object_names,
+            material_names,
             node,
+            unbounded,
         }
     }
 
-    pub fn get(&self, index: usize) -> (&Box<dyn HitAble>, &Rc<dyn Material>) {
-        (&self.objects[index], &self.materials[index])
+    pub fn get(&self, index: usize) -> (&dyn HitAble, &Arc<dyn Material>) {
+        (self.objects[index].as_ref(), &self.materials[index])
     }
 
-    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let mut temp_rec = HitRecord::empty();
+    // By position in the list passed in, not by id
+    pub(crate) fn object(&self, index: usize) -> &dyn HitAble {
+        self.objects[index].as_ref()
+    }
 
-        // let hit = self.node.hit(r, t_min, t_max, &mut temp_rec, &self);
+    pub fn material_id(&self, index: usize) -> usize {
+        self.material_ids[index]
+    }
 
-        // match hit {
-        //     true => Some(temp_rec),
-        //     false => Option::None,
-        // }
+    // Ids go up to the number of materials passed in, objects sharing an id
+    // are one object as far as ids and names are concerned
+    pub fn object_count(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn material_count(&self) -> usize {
+        self.material_names.len()
+    }
 
-        let mut hit_anything = false;
-        let mut closest_so_far = t_max;
+    // Names end up in the ID matte manifests. Unnamed ids are called
+    // `object_<id>` and `material_<id>`.
+    pub fn object_name(&self, id: usize) -> String {
+        self.object_names[id].clone()
+    }
 
-        for object in self.objects.iter() {
-            if object.hit(r, t_min, closest_so_far, &mut temp_rec) {
+    pub fn material_name(&self, id: usize) -> String {
+        self.material_names[id].clone()
+    }
+
+    pub fn set_object_name(&mut self, id: usize, name: &str) {
+        self.object_names[id] = name.to_string();
+    }
+
+    pub fn set_material_name(&mut self, id: usize, name: &str) {
+        self.material_names[id] = name.to_string();
+    }
+
+    pub fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut temp_rec = HitRecord::empty();
+
+        let mut hit_anything = match &self.node {
+            Some(node) => node.hit(r, t_min, t_max, &mut temp_rec, self),
+            None => false,
+        };
+        let mut closest_so_far = if hit_anything { temp_rec.t() } else { t_max };
+
+        for &index in self.unbounded.iter() {
+            if self.hit_object(self.object(index), r, t_min, closest_so_far, &mut temp_rec) {
                 hit_anything = true;
                 closest_so_far = temp_rec.t();
-                temp_rec.set_id(object.id())
             }
         }
         match hit_anything {
@@ -55,30 +137,215 @@ impl World {
         // (hit_anything, temp_rec)
     }
 
-    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut AABB) -> bool {
-        if self.objects.is_empty() {
-            return false;
+    // Nearest hit on one object that its material has not cut away. Past a
+    // cut away hit the object is asked again for the next one behind it, so
+    // objects with their own BVH, like meshes, skip the holes too.
+    pub(crate) fn hit_object(
+        &self,
+        object: &dyn HitAble,
+        r: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rec: &mut HitRecord,
+    ) -> bool {
+        let mut found = HitRecord::empty();
+        let mut from = t_min;
+        while object.hit(r, from, t_max, &mut found) {
+            // Objects without an id of their own, like CSG nodes, leave the
+            // one of the part that was hit
+            if object.id().is_some() {
+                found.set_id(object.id())
+            }
+            let cut_away = match found.id().and_then(|id| self.materials.get(id)) {
+                Some(material) => material.cut_away(r, &found),
+                None => false,
+            };
+            if !cut_away {
+                *rec = found;
+                return true;
+            }
+            // Strictly past the hole, or it is found again
+            from = found.t() + found.t().abs().max(1.0) * 1e-9;
         }
+        false
+    }
+}
 
-        // In the original file the temp box is outside of the for loop
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csg::Csg;
+    use crate::material::{AlphaMode, Bumped, Cutout, Lambertian};
+    use crate::models::Sphere;
+    use crate::quad::{Plane, Quad};
+    use crate::texture::{SolidColor, Texture};
+    use crate::vec3::Vec3;
 
-        let mut first_box = true;
+    // Opaque on the left half of a quad
+    struct LeftHalf;
 
-        for object in self.objects.as_slice() {
-            let temp_box = object.bounding_box(time0, time1);
-            if temp_box.is_none() {
-                return false;
-            }
+    impl Texture for LeftHalf {
+        fn value(&self, u: f64, _v: f64, _p: &Vec3) -> Vec3 {
+            let alpha = if u < 0.5 { 1.0 } else { 0.0 };
+            Vec3::new(alpha, alpha, alpha)
+        }
+    }
 
-            *output_box = if first_box {
-                temp_box.unwrap()
-            } else {
-                AABB::surrounding_box_mut(output_box, temp_box.unwrap())
-            };
+    // Opaque behind z = 0 only
+    struct BackHalf;
+
+    impl Texture for BackHalf {
+        fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Vec3 {
+            let alpha = if p.z() < 0.0 { 1.0 } else { 0.0 };
+            Vec3::new(alpha, alpha, alpha)
+        }
+    }
+
+    // A card at z = 1 in front of a sphere at the origin
+    fn card_and_ball(card: Arc<dyn Material>) -> World {
+        let mut rng = Rng::new(1);
+        let objects: Vec<Box<dyn HitAble>> = vec![
+            Box::new(Quad::xy(-2.0, 2.0, -2.0, 2.0, 1.0, 0)),
+            Box::new(Sphere::new(Vec3::empty(), 0.5, 1)),
+        ];
+        let grey = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        World::new(objects, vec![card, grey], &mut rng)
+    }
+
+    fn towards_ball(x: f64) -> Ray {
+        Ray::new(&Vec3::new(x, 0.0, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0)
+    }
+
+    #[test]
+    fn test_rays_pass_through_cut_away_parts() {
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let card = Arc::new(Cutout::new(grey, Arc::new(LeftHalf)));
+        let world = card_and_ball(card);
+
+        let solid = world
+            .hit(&towards_ball(-0.2), 0.001, f64::INFINITY)
+            .unwrap();
+        assert_eq!(solid.id(), Some(0));
+        let through = world.hit(&towards_ball(0.2), 0.001, f64::INFINITY).unwrap();
+        assert_eq!(through.id(), Some(1));
+        assert!((through.t() - (5.0 - (0.25_f64 - 0.04).sqrt())).abs() < 1e-9);
+        assert!(world
+            .hit(&towards_ball(1.0), 0.001, f64::INFINITY)
+            .is_none());
+    }
+
+    #[test]
+    fn test_wrapped_cutouts_still_cut_away() {
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let cutout: Arc<dyn Material> = Arc::new(Cutout::new(grey, Arc::new(LeftHalf)));
+        let flat = Arc::new(SolidColor::new(Vec3::new(0.5, 0.5, 1.0)));
+        let card = Arc::new(Bumped::normal_map(cutout, flat));
+        let world = card_and_ball(card);
+
+        let through = world.hit(&towards_ball(0.2), 0.001, f64::INFINITY).unwrap();
+        assert_eq!(through.id(), Some(1));
+        let solid = world
+            .hit(&towards_ball(-0.2), 0.001, f64::INFINITY)
+            .unwrap();
+        assert_eq!(solid.id(), Some(0));
+    }
 
-            first_box = false;
+    #[test]
+    fn test_bvh_agrees_with_every_object() {
+        // A plane first and a CSG node without an id of its own, so positions
+        // in the list and ids differ, and enough spheres for the BVH to sort
+        let mut rng = Rng::new(0);
+        let mut objects: Vec<Box<dyn HitAble>> = vec![
+            Box::new(Plane::new(
+                Vec3::new(0.0, -1.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                0,
+            )),
+            Box::new(Quad::xy(-2.0, 2.0, -2.0, 2.0, 1.0, 1)),
+        ];
+        for id in 2..8 {
+            let center = Vec3::new((id as f64 - 5.0) * 0.9, (id % 3) as f64 * 0.3, -1.0);
+            objects.push(Box::new(Sphere::new(center, 0.4, id)));
         }
+        objects.push(Box::new(Csg::difference(
+            Box::new(Sphere::new(Vec3::new(0.0, 1.5, -2.0), 0.8, 8)),
+            Box::new(Sphere::new(Vec3::new(0.4, 1.5, -1.5), 0.5, 9)),
+        )));
 
-        true
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let mut materials = vec![grey.clone(); 10];
+        materials[1] = Arc::new(Cutout::new(grey, Arc::new(LeftHalf)));
+        let world = World::new(objects, materials, &mut rng);
+
+        for _ in 0..2000 {
+            let target = Vec3::new(
+                rng.random_double_from_values(-4.0, 4.0),
+                rng.random_double_from_values(-2.0, 3.0),
+                -1.5,
+            );
+            let origin = Vec3::new(0.0, 0.5, 5.0);
+            let r = Ray::new(&origin, &(target - origin), 0.0);
+
+            let mut expected = HitRecord::empty();
+            let mut closest = f64::INFINITY;
+            let mut found = false;
+            for object in world.objects.iter() {
+                if world.hit_object(object.as_ref(), &r, 0.001, closest, &mut expected) {
+                    found = true;
+                    closest = expected.t();
+                }
+            }
+
+            match world.hit(&r, 0.001, f64::INFINITY) {
+                Some(rec) => {
+                    assert!(found);
+                    assert_eq!(rec.id(), expected.id());
+                    assert!((rec.t() - expected.t()).abs() < 1e-9);
+                }
+                None => assert!(!found),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cut_away_front_shows_the_back() {
+        // The same object is asked again past the hole
+        let mut rng = Rng::new(1);
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let bowl = Arc::new(Cutout::new(grey, Arc::new(BackHalf)));
+        let objects: Vec<Box<dyn HitAble>> = vec![Box::new(Sphere::new(Vec3::empty(), 1.0, 0))];
+        let world = World::new(objects, vec![bowl], &mut rng);
+
+        let rec = world.hit(&towards_ball(0.0), 0.001, f64::INFINITY).unwrap();
+        assert!((rec.p().z() + 1.0).abs() < 1e-9);
+        assert!(!rec.front_face());
+    }
+
+    #[test]
+    fn test_stochastic_opacity() {
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let haze = Arc::new(SolidColor::new(Vec3::new(0.3, 0.3, 0.3)));
+        let card = Arc::new(Cutout::new(grey, haze).with_mode(AlphaMode::Stochastic));
+        let world = card_and_ball(card);
+
+        let count = 4000;
+        let mut rng = Rng::new(4);
+        let mut blocked = 0;
+        for _ in 0..count {
+            let origin = Vec3::new(
+                rng.random_double_from_values(-0.3, 0.3),
+                rng.random_double_from_values(-0.3, 0.3),
+                5.0,
+            );
+            let r = Ray::new(&origin, &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let rec = world.hit(&r, 0.001, f64::INFINITY).unwrap();
+            if rec.id() == Some(0) {
+                blocked += 1;
+            }
+            // The same ray always makes the same choice
+            assert_eq!(world.hit(&r, 0.001, f64::INFINITY).unwrap().id(), rec.id());
+        }
+        let fraction = blocked as f64 / count as f64;
+        assert!((fraction - 0.3).abs() < 0.03, "{}", fraction);
     }
 }